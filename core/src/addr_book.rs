@@ -0,0 +1,356 @@
+use crate::config::GLOBAL_CONFIG;
+use crate::error::Result;
+use chrono::Utc;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use utils::coder;
+
+pub const PEERS_FILE: &str = "peers.dat";
+
+/// 一条 addr 消息最多携带的地址数量
+pub const MAX_ADDR_PER_MESSAGE: usize = 1000;
+
+/// 地址簿最多保存的地址数量，超过时淘汰最久没有见过的地址
+const MAX_BOOK_SIZE: usize = 4096;
+
+/// 连续连接失败达到该次数的地址从地址簿中移除
+const MAX_FAILURES: u32 = 5;
+
+/// 同一地址两次连接尝试之间的最短间隔，单位为秒
+const RETRY_INTERVAL: i64 = 60;
+
+/// 节点的监听地址和最后一次确认其在线的时间
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetAddr {
+    addr: String,
+    last_seen: i64, // Unix 时间戳，单位为秒
+}
+
+impl NetAddr {
+    pub fn new(addr: String, last_seen: i64) -> NetAddr {
+        NetAddr { addr, last_seen }
+    }
+
+    pub fn get_addr(&self) -> &str {
+        self.addr.as_str()
+    }
+
+    pub fn get_last_seen(&self) -> i64 {
+        self.last_seen
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    last_seen: i64, // 最后一次确认在线的时间
+    failures: u32,  // 连续连接失败的次数
+    // 最后一次尝试连接的时间，不保存到文件，重启后可以立即重试
+    #[serde(skip)]
+    last_attempt: i64,
+}
+
+/// 已知节点的地址簿，保存在数据目录下的 peers.dat 中，节点重启后仍然可以找到其他节点
+pub struct AddrBook {
+    entries: RwLock<HashMap<String, Entry>>,
+    dirty: AtomicBool, // 是否有尚未保存的修改
+}
+
+impl AddrBook {
+    pub fn new() -> AddrBook {
+        AddrBook {
+            entries: RwLock::new(HashMap::new()),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// 从数据目录加载地址簿，文件不存在或损坏时返回空的地址簿
+    pub fn load() -> AddrBook {
+        let book = AddrBook::new();
+        if let Err(e) = book.load_from_file() {
+            warn!("Failed to load {}: {}", PEERS_FILE, e);
+        }
+        book
+    }
+
+    fn load_from_file(&self) -> Result<()> {
+        let path = GLOBAL_CONFIG.get_data_dir().join(PEERS_FILE);
+        if !path.exists() {
+            return Ok(());
+        }
+        let buf = fs::read(path)?;
+        let mut entries: HashMap<String, Entry> = coder::deserialized(buf.as_slice())?;
+        entries.retain(|addr, _| addr.parse::<SocketAddr>().is_ok());
+        *self.entries.write().unwrap() = entries;
+        Ok(())
+    }
+
+    /// 有修改时保存到数据目录，先写入临时文件再替换，避免写到一半时文件损坏
+    pub fn save(&self) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let bytes = coder::serialized(&*self.entries.read().unwrap());
+        let data_dir = GLOBAL_CONFIG.get_data_dir();
+        if !data_dir.as_os_str().is_empty() {
+            fs::create_dir_all(&data_dir)?;
+        }
+        let path = data_dir.join(PEERS_FILE);
+        let tmp_path = path.with_extension("tmp");
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(bytes.as_slice())?;
+        writer.flush()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    /// 添加其他节点通告的地址，返回是否为新地址
+    /// 只接受 ip:port 形式的地址，晚于当前时间的时间戳按当前时间处理
+    pub fn add(&self, addr: &NetAddr) -> bool {
+        let socket_addr: SocketAddr = match addr.addr.parse() {
+            Ok(socket_addr) => socket_addr,
+            Err(_) => return false,
+        };
+        let last_seen = addr.last_seen.min(Utc::now().timestamp());
+        let mut entries = self.entries.write().unwrap();
+        self.dirty.store(true, Ordering::SeqCst);
+        if let Some(entry) = entries.get_mut(&socket_addr.to_string()) {
+            entry.last_seen = entry.last_seen.max(last_seen);
+            return false;
+        }
+        if entries.len() >= MAX_BOOK_SIZE {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_seen)
+                .map(|(addr, _)| addr.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            socket_addr.to_string(),
+            Entry {
+                last_seen,
+                failures: 0,
+                last_attempt: 0,
+            },
+        );
+        true
+    }
+
+    /// 记录一次连接尝试，重试间隔内不会再次选中该地址
+    pub fn mark_attempt(&self, addr: &str) {
+        if let Some(entry) = self.entries.write().unwrap().get_mut(addr) {
+            entry.last_attempt = Utc::now().timestamp();
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// 成功连接到该地址，更新在线时间并清除失败次数
+    pub fn mark_good(&self, addr: &str) {
+        if self.add(&NetAddr::new(addr.to_string(), Utc::now().timestamp())) {
+            return;
+        }
+        if let Some(entry) = self.entries.write().unwrap().get_mut(addr) {
+            entry.failures = 0;
+        }
+    }
+
+    /// 连接该地址失败，连续失败次数过多时移除
+    pub fn mark_failed(&self, addr: &str) {
+        let mut entries = self.entries.write().unwrap();
+        if let Some(entry) = entries.get_mut(addr) {
+            entry.failures += 1;
+            if entry.failures >= MAX_FAILURES {
+                entries.remove(addr);
+            }
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// 最近在线的 limit 个地址，用于回复 getaddr
+    pub fn get_addrs(&self, limit: usize) -> Vec<NetAddr> {
+        let mut addrs: Vec<NetAddr> = self
+            .entries
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(addr, entry)| NetAddr::new(addr.clone(), entry.last_seen))
+            .collect();
+        addrs.sort_by_key(|addr| Reverse(addr.last_seen));
+        addrs.truncate(limit);
+        addrs
+    }
+
+    /// 挑选最多 limit 个待连接的地址，失败次数少、最近在线的优先，跳过 skip 返回 true 的地址
+    pub fn get_candidates<F: Fn(&str) -> bool>(&self, limit: usize, skip: F) -> Vec<String> {
+        let now = Utc::now().timestamp();
+        let entries = self.entries.read().unwrap();
+        let mut candidates: Vec<(&String, &Entry)> = entries
+            .iter()
+            .filter(|(addr, entry)| {
+                now - entry.last_attempt >= RETRY_INTERVAL && !skip(addr.as_str())
+            })
+            .collect();
+        candidates.sort_by(|(_, a), (_, b)| {
+            a.failures
+                .cmp(&b.failures)
+                .then(b.last_seen.cmp(&a.last_seen))
+        });
+        candidates
+            .into_iter()
+            .take(limit)
+            .map(|(addr, _)| addr.clone())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for AddrBook {
+    fn default() -> Self {
+        AddrBook::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net_addr(addr: &str, last_seen: i64) -> NetAddr {
+        NetAddr::new(addr.to_string(), last_seen)
+    }
+
+    #[test]
+    fn add_and_dedup() {
+        let book = AddrBook::new();
+        assert!(book.add(&net_addr("127.0.0.1:2001", 100)));
+        assert!(!book.add(&net_addr("127.0.0.1:2001", 50)));
+        // 不是 ip:port 形式的地址不会加入地址簿
+        assert!(!book.add(&net_addr("localhost:2002", 100)));
+        assert_eq!(book.len(), 1);
+        // 重复添加时保留较新的在线时间
+        assert!(!book.add(&net_addr("127.0.0.1:2001", 200)));
+        assert_eq!(book.get_addrs(10), vec![net_addr("127.0.0.1:2001", 200)]);
+        // 晚于当前时间的时间戳按当前时间处理
+        let now = Utc::now().timestamp();
+        assert!(book.add(&net_addr("127.0.0.1:2003", now + 3600)));
+        let addrs = book.get_addrs(1);
+        assert_eq!(addrs[0].get_addr(), "127.0.0.1:2003");
+        assert!(addrs[0].get_last_seen() <= Utc::now().timestamp());
+    }
+
+    #[test]
+    fn evict_oldest_when_full() {
+        let book = AddrBook::new();
+        for i in 0..MAX_BOOK_SIZE {
+            let addr = format!("10.0.{}.{}:2001", i / 256, i % 256);
+            assert!(book.add(&net_addr(&addr, 1000 + i as i64)));
+        }
+        assert_eq!(book.len(), MAX_BOOK_SIZE);
+        assert!(book.add(&net_addr("10.1.0.0:2001", 1)));
+        assert_eq!(book.len(), MAX_BOOK_SIZE);
+        let addrs: Vec<String> = book
+            .get_addrs(MAX_BOOK_SIZE)
+            .iter()
+            .map(|addr| addr.get_addr().to_string())
+            .collect();
+        assert!(!addrs.contains(&"10.0.0.0:2001".to_string()));
+        assert!(addrs.contains(&"10.0.0.1:2001".to_string()));
+        assert!(addrs.contains(&"10.1.0.0:2001".to_string()));
+    }
+
+    #[test]
+    fn failed_addrs_are_retried_later() {
+        let book = AddrBook::new();
+        book.add(&net_addr("127.0.0.1:2001", 100));
+        book.add(&net_addr("127.0.0.1:2002", 200));
+        book.add(&net_addr("127.0.0.1:2003", 300));
+        assert_eq!(
+            book.get_candidates(10, |_| false),
+            vec!["127.0.0.1:2003", "127.0.0.1:2002", "127.0.0.1:2001"]
+        );
+        assert_eq!(
+            book.get_candidates(10, |addr| addr == "127.0.0.1:2002"),
+            vec!["127.0.0.1:2003", "127.0.0.1:2001"]
+        );
+
+        // 失败过的地址排在后面，重试间隔内不会再次选中
+        book.mark_attempt("127.0.0.1:2003");
+        book.mark_failed("127.0.0.1:2003");
+        assert_eq!(
+            book.get_candidates(10, |_| false),
+            vec!["127.0.0.1:2002", "127.0.0.1:2001"]
+        );
+        book.entries
+            .write()
+            .unwrap()
+            .get_mut("127.0.0.1:2003")
+            .unwrap()
+            .last_attempt -= RETRY_INTERVAL;
+        assert_eq!(
+            book.get_candidates(10, |_| false),
+            vec!["127.0.0.1:2002", "127.0.0.1:2001", "127.0.0.1:2003"]
+        );
+
+        // 连接成功后清除失败次数
+        book.mark_good("127.0.0.1:2003");
+        assert_eq!(book.get_candidates(1, |_| false), vec!["127.0.0.1:2003"]);
+
+        // 连续失败次数过多的地址被移除
+        for _ in 0..MAX_FAILURES {
+            book.mark_failed("127.0.0.1:2001");
+        }
+        assert_eq!(book.len(), 2);
+        assert!(!book
+            .get_candidates(10, |_| false)
+            .contains(&"127.0.0.1:2001".to_string()));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let data_dir = std::env::temp_dir().join(format!("addr_book_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        GLOBAL_CONFIG.set_data_dir(data_dir.to_str().unwrap().to_string());
+
+        // 文件不存在时加载为空的地址簿
+        assert!(AddrBook::load().is_empty());
+
+        let book = AddrBook::new();
+        book.add(&net_addr("127.0.0.1:2001", 100));
+        book.add(&net_addr("127.0.0.1:2002", 200));
+        book.mark_failed("127.0.0.1:2002");
+        book.save().unwrap();
+        assert!(data_dir.join(PEERS_FILE).exists());
+
+        let loaded = AddrBook::load();
+        assert_eq!(loaded.get_addrs(10), book.get_addrs(10));
+        // 失败次数随地址一起保存
+        assert_eq!(
+            loaded.get_candidates(10, |_| false),
+            vec!["127.0.0.1:2001", "127.0.0.1:2002"]
+        );
+
+        // 损坏的文件被忽略
+        fs::write(data_dir.join(PEERS_FILE), b"garbage").unwrap();
+        assert!(AddrBook::load().is_empty());
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
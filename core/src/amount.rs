@@ -0,0 +1,202 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// 1 个币等于多少最小单位
+pub const COIN: u64 = 100_000_000;
+
+/// 币的小数位数
+const DECIMALS: usize = 8;
+
+/// 任何单个金额或金额之和都不能超过的上限
+pub const MAX_MONEY: Amount = Amount(21_000_000 * COIN);
+
+/// 金额，以最小单位保存，显示和解析时以币为单位，保留 8 位小数
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    /// 由最小单位数量创建金额
+    pub const fn from_base_units(units: u64) -> Amount {
+        Amount(units)
+    }
+
+    /// 由整币数量创建金额，溢出时返回 None
+    pub fn from_coins(coins: u64) -> Option<Amount> {
+        coins.checked_mul(COIN).map(Amount)
+    }
+
+    pub fn get_base_units(&self) -> u64 {
+        self.0
+    }
+
+    /// 金额不超过 MAX_MONEY
+    pub fn is_valid(&self) -> bool {
+        *self <= MAX_MONEY
+    }
+
+    /// 加法，溢出时返回 None
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    /// 减法，结果为负时返回 None
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// 除以整数，用于奖励减半
+    pub fn checked_div(self, divisor: u64) -> Option<Amount> {
+        self.0.checked_div(divisor).map(Amount)
+    }
+
+    /// 乘以整数，溢出时返回 None
+    pub fn checked_mul(self, multiplier: u64) -> Option<Amount> {
+        self.0.checked_mul(multiplier).map(Amount)
+    }
+
+    /// 求和，溢出或者超过 MAX_MONEY 时返回 None
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        let mut total = Amount::ZERO;
+        for amount in amounts {
+            total = total.checked_add(amount)?;
+            if !total.is_valid() {
+                return None;
+            }
+        }
+        Some(total)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:0width$}",
+            self.0 / COIN,
+            self.0 % COIN,
+            width = DECIMALS
+        )
+    }
+}
+
+/// 金额解析失败的原因
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseAmountError {
+    /// 不是非负的十进制数
+    Invalid,
+    /// 小数位数超过 8 位
+    TooPrecise,
+    /// 超过 MAX_MONEY
+    TooLarge,
+}
+
+impl fmt::Display for ParseAmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseAmountError::Invalid => write!(f, "invalid amount"),
+            ParseAmountError::TooPrecise => {
+                write!(f, "amount has more than {} decimal places", DECIMALS)
+            }
+            ParseAmountError::TooLarge => write!(f, "amount exceeds {}", MAX_MONEY),
+        }
+    }
+}
+
+impl std::error::Error for ParseAmountError {}
+
+/// 以币为单位解析金额，例如 "1"、"0.5"、"12.00000001"
+impl FromStr for Amount {
+    type Err = ParseAmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (int_part, frac_part) = match s.split_once('.') {
+            Some((int_part, frac_part)) => (int_part, frac_part),
+            None => (s, ""),
+        };
+        let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if int_part.is_empty() || !is_digits(int_part) || !is_digits(frac_part) {
+            return Err(ParseAmountError::Invalid);
+        }
+        if frac_part.len() > DECIMALS {
+            return Err(ParseAmountError::TooPrecise);
+        }
+        let coins: u64 = int_part.parse().map_err(|_| ParseAmountError::TooLarge)?;
+        let frac_units: u64 = match frac_part {
+            "" => 0,
+            _ => format!("{:0<width$}", frac_part, width = DECIMALS)
+                .parse()
+                .map_err(|_| ParseAmountError::Invalid)?,
+        };
+        let amount = Amount::from_coins(coins)
+            .and_then(|amount| amount.checked_add(Amount(frac_units)))
+            .ok_or(ParseAmountError::TooLarge)?;
+        if !amount.is_valid() {
+            return Err(ParseAmountError::TooLarge);
+        }
+        Ok(amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<Amount, ParseAmountError> {
+        s.parse()
+    }
+
+    #[test]
+    fn parse_valid_amounts() {
+        assert_eq!(parse("0"), Ok(Amount::ZERO));
+        assert_eq!(parse("1"), Ok(Amount(COIN)));
+        assert_eq!(parse("0.5"), Ok(Amount(COIN / 2)));
+        assert_eq!(parse("12.00000001"), Ok(Amount(12 * COIN + 1)));
+        assert_eq!(parse("0.00000001"), Ok(Amount(1)));
+        assert_eq!(parse("21000000"), Ok(MAX_MONEY));
+        assert_eq!(parse("007.10"), Ok(Amount(7 * COIN + COIN / 10)));
+    }
+
+    #[test]
+    fn parse_invalid_amounts() {
+        for s in ["", ".5", "-1", "+1", "1e8", "1.2.3", " 1", "1,5", "abc"] {
+            assert_eq!(parse(s), Err(ParseAmountError::Invalid), "{:?}", s);
+        }
+        assert_eq!(parse("0.000000001"), Err(ParseAmountError::TooPrecise));
+        assert_eq!(parse("21000000.00000001"), Err(ParseAmountError::TooLarge));
+        assert_eq!(
+            parse("18446744073709551616"),
+            Err(ParseAmountError::TooLarge)
+        );
+        assert_eq!(
+            parse("184467440737.09551616"),
+            Err(ParseAmountError::TooLarge)
+        );
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for s in [
+            "0.00000000",
+            "0.00000001",
+            "12.50000000",
+            "21000000.00000000",
+        ] {
+            assert_eq!(parse(s).unwrap().to_string(), s);
+        }
+    }
+
+    #[test]
+    fn checked_sum_rejects_more_than_max_money() {
+        assert_eq!(
+            Amount::checked_sum([MAX_MONEY, Amount::ZERO]),
+            Some(MAX_MONEY)
+        );
+        assert_eq!(Amount::checked_sum([MAX_MONEY, Amount(1)]), None);
+        assert_eq!(Amount::checked_sum([Amount(u64::MAX), Amount(1)]), None);
+    }
+}
@@ -1,49 +1,126 @@
+use crate::error::{Error, Result};
+use crate::merkle::{MerkleProof, MerkleTree};
 use crate::pow::ProofOfWork;
 use crate::transaction::Transaction;
 use chrono::prelude::*;
+use data_encoding::HEXLOWER;
 use serde::{Deserialize, Serialize};
-use sled::IVec;
 use utils::coder;
 
+/// 区块版本
+const BLOCK_VERSION: u32 = 1;
+
+/// 创世块的上一区块哈希
+pub const GENESIS_PRE_HASH: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// 区块头，区块哈希只由区块头决定
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BlockHeader {
-    timestamp: i64,   // 区块时间戳
-    tx_hash: String,  //交易数据hash
-    pre_hash: String, // 上一区块的哈希值
+    version: u32,          // 区块版本
+    pre_hash: [u8; 32],    // 上一区块的哈希值
+    merkle_root: [u8; 32], // 交易的默克尔根
+    timestamp: i64,        // 区块时间戳
+    bits: u32,             // 难度目标（紧凑格式）
+    nonce: i64,            // 计数器
+    height: usize,         // 区块链中节点的高度
+}
+
+impl BlockHeader {
+    /// 区块头的规范编码，所有整数均为大端序，共 96 字节
+    /// version(4) | pre_hash(32) | merkle_root(32) | timestamp(8) | bits(4) | nonce(8) | height(8)
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data_bytes = Vec::with_capacity(96);
+        data_bytes.extend(self.version.to_be_bytes());
+        data_bytes.extend(self.pre_hash);
+        data_bytes.extend(self.merkle_root);
+        data_bytes.extend(self.timestamp.to_be_bytes());
+        data_bytes.extend(self.bits.to_be_bytes());
+        data_bytes.extend(self.nonce.to_be_bytes());
+        data_bytes.extend((self.height as u64).to_be_bytes());
+        data_bytes
+    }
+
+    /// 区块头哈希：规范编码的双重 sha256
+    pub fn hash(&self) -> String {
+        HEXLOWER.encode(self.hash_bytes().as_slice())
+    }
+
+    pub fn hash_bytes(&self) -> Vec<u8> {
+        coder::double_sha256_digest(self.serialize().as_slice())
+    }
+
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn get_pre_hash(&self) -> String {
+        HEXLOWER.encode(&self.pre_hash)
+    }
+
+    pub fn get_merkle_root(&self) -> String {
+        HEXLOWER.encode(&self.merkle_root)
+    }
+
+    pub fn get_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    pub fn get_bits(&self) -> u32 {
+        self.bits
+    }
+
+    pub fn get_nonce(&self) -> i64 {
+        self.nonce
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    pub(crate) fn set_nonce(&mut self, nonce: i64) {
+        self.nonce = nonce;
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
     header: BlockHeader,            //区块头部
-    hash: String,                   // 当前区块的哈希值
+    hash: String,                   // 当前区块的哈希值，即区块头哈希
     transactions: Vec<Transaction>, // 交易数据
-    nonce: i64,                     // 计数器
-    height: usize,                  // 区块链中节点的高度
 }
 
 impl Block {
-    // 新建一个区块
-    pub fn new_block(transactions: &[Transaction], pre_hash: String, height: usize) -> Block {
+    // 新建一个区块，bits 为按难度调整规则计算出的难度目标
+    // 上一区块哈希格式错误时返回 Error::InvalidBlockHash
+    pub fn new_block(
+        transactions: &[Transaction],
+        pre_hash: String,
+        height: usize,
+        bits: u32,
+    ) -> Result<Block> {
+        let pre_hash = decode_hash(pre_hash.as_str()).ok_or(Error::InvalidBlockHash(pre_hash))?;
         let timestamp = Utc::now().timestamp();
-        let tx_ser = coder::serialized(transactions);
-        let tx_hash = coder::get_hash(&tx_ser);
         let mut block = Block {
             header: BlockHeader {
-                timestamp,
+                version: BLOCK_VERSION,
                 pre_hash,
-                tx_hash,
+                merkle_root: [0; 32],
+                timestamp,
+                bits,
+                nonce: 0,
+                height,
             },
             hash: String::new(),
             transactions: transactions.to_vec(),
-            nonce: 0,
-            height,
         };
+        block.hash_transactions();
         // 挖矿计算哈希
-        let mut pow = ProofOfWork::new_proof_of_work(block.clone());
+        let mut pow = ProofOfWork::new_proof_of_work(block.header.clone());
         let (nonce, hash) = pow.run();
-        block.nonce = nonce;
+        block.header.nonce = nonce;
         block.hash = hash;
-        block
+        Ok(block)
     }
 
     //获取区块的hash
@@ -51,16 +128,36 @@ impl Block {
         self.hash.as_str()
     }
 
-    // 计算区块里所有交易的哈希
+    //获取区块头
+    pub fn get_header(&self) -> &BlockHeader {
+        &self.header
+    }
+
+    // 计算区块里所有交易的默克尔根并写入区块头
     pub fn hash_transactions(&mut self) -> String {
-        let tx_ser = coder::serialized(&self.transactions);
-        self.header.tx_hash = coder::get_hash(&tx_ser);
-        self.header.tx_hash.clone()
+        let merkle_root = self.build_merkle_tree().get_root();
+        self.header.merkle_root = merkle_root;
+        HEXLOWER.encode(&merkle_root)
+    }
+
+    // 由区块中的交易构建默克尔树
+    pub fn build_merkle_tree(&self) -> MerkleTree {
+        let txids: Vec<Vec<u8>> = self
+            .transactions
+            .iter()
+            .map(|tx| tx.get_id_bytes())
+            .collect();
+        MerkleTree::new(txids.as_slice())
+    }
+
+    // 生成交易包含在区块中的默克尔证明
+    pub fn get_merkle_proof(&self, txid: &[u8]) -> Option<MerkleProof> {
+        self.build_merkle_tree().get_proof(txid)
     }
 
     //获取上一个区块的hash
     pub fn get_pre_block_hash(&self) -> String {
-        self.header.pre_hash.clone()
+        self.header.get_pre_hash()
     }
 
     //获取区块时间戳
@@ -73,9 +170,19 @@ impl Block {
         self.transactions.as_slice()
     }
 
+    //获取计数器
+    pub fn get_nonce(&self) -> i64 {
+        self.header.nonce
+    }
+
+    //获取难度目标
+    pub fn get_bits(&self) -> u32 {
+        self.header.bits
+    }
+
     //获取区块高度
     pub fn get_height(&self) -> usize {
-        self.height
+        self.header.height
     }
 
     //将hash转为字节数组
@@ -84,9 +191,64 @@ impl Block {
     }
 }
 
-impl From<Block> for IVec {
-    fn from(b: Block) -> Self {
-        let bytes = coder::serialized(&b);
-        Self::from(bytes)
+/// 是否为格式正确的区块哈希，即 64 个小写十六进制字符
+/// 对方提供的哈希需要先检查格式，再用来查找区块
+pub fn is_block_hash(hash: &[u8]) -> bool {
+    std::str::from_utf8(hash)
+        .ok()
+        .and_then(decode_hash)
+        .is_some()
+}
+
+// 将十六进制哈希还原为 32 字节
+fn decode_hash(hash: &str) -> Option<[u8; 32]> {
+    let bytes = HEXLOWER.decode(hash.as_bytes()).ok()?;
+    bytes.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::pow;
+    use crate::wallet::Wallet;
+
+    fn coinbase(height: usize, extra: &[u8]) -> Transaction {
+        let address = Wallet::new().unwrap().get_address();
+        Transaction::new_coinbase_tx(&address, height, Amount::ZERO, extra).unwrap()
+    }
+
+    #[test]
+    fn new_block_rejects_malformed_pre_hash() {
+        let transactions = vec![coinbase(1, &[])];
+        let bits = pow::pow_limit_bits();
+        for pre_hash in ["", "00", &"0".repeat(63), &"G".repeat(64), &"AB".repeat(32)] {
+            let result = Block::new_block(&transactions, pre_hash.to_string(), 1, bits);
+            assert!(
+                matches!(result, Err(Error::InvalidBlockHash(_))),
+                "{}",
+                pre_hash
+            );
+        }
+        let block = Block::new_block(&transactions, "ab".repeat(32), 1, bits).unwrap();
+        assert_eq!(block.get_pre_block_hash(), "ab".repeat(32));
+        assert!(is_block_hash(block.get_hash().as_bytes()));
+    }
+
+    #[test]
+    fn merkle_proof_verifies_against_header() {
+        let transactions: Vec<Transaction> = (0..3u8).map(|i| coinbase(1, &[i])).collect();
+        let block = Block::new_block(
+            &transactions,
+            String::from(GENESIS_PRE_HASH),
+            1,
+            pow::pow_limit_bits(),
+        )
+        .unwrap();
+        for tx in &transactions {
+            let proof = block.get_merkle_proof(tx.get_id()).unwrap();
+            assert!(proof.verify(block.get_header()));
+        }
+        assert!(block.get_merkle_proof(&[0; 32]).is_none());
     }
 }
@@ -1,201 +1,1003 @@
-use crate::block::Block;
-use crate::transaction::{TXOutput, Transaction};
+use crate::amount::Amount;
+use crate::block::{Block, GENESIS_PRE_HASH};
+use crate::emission::GLOBAL_EMISSION;
+use crate::error::{Error, Result};
+use crate::pow::{self, RETARGET_INTERVAL};
+use crate::sled_store::SledStore;
+use crate::store::{AddressTx, ChainStore, TxLocation};
+use crate::transaction::{
+    TXInput, Transaction, SEQUENCE_LOCKTIME_DISABLE_FLAG, SEQUENCE_LOCKTIME_GRANULARITY,
+    SEQUENCE_LOCKTIME_MASK, SEQUENCE_LOCKTIME_TYPE_FLAG,
+};
+use crate::utxo::{block_address_history, UTXOEntry, UTXOSet};
+use crate::validation::{self, BlockError};
+use chrono::Utc;
 use data_encoding::HEXLOWER;
-use dotenv::dotenv;
-use sled::transaction::TransactionResult;
-use sled::{Db, Tree};
-use std::collections::HashMap;
-use std::env;
-use std::sync::{Arc, RwLock};
-use utils::coder;
+use log::{info, warn};
+use num_bigint::{BigInt, Sign};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex, RwLock};
 
-const TIP_BLOCK_HASH_KEY: &str = "tip_block_hash";
-const BLOCKS_TREE: &str = "blocks";
+/// 计算中位时间所用的区块数量
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// 区块时间戳最多可以超前本地时间的秒数
+const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60;
+
+/// 区块索引，每个保存下来的区块（包括侧链区块）都有一条
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockIndex {
+    chain_work: Vec<u8>, // 从创世块到该区块的累计工作量（大端序）
+    failed: bool,        // 接入主链时校验失败，后续不再尝试切换到该分支
+}
+
+impl BlockIndex {
+    pub fn get_chain_work(&self) -> BigInt {
+        BigInt::from_bytes_be(Sign::Plus, self.chain_work.as_slice())
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.failed
+    }
+}
+
+/// 区块加入后主链的变化
+#[derive(Debug, Default)]
+pub struct ChainChange {
+    connected: Vec<Block>,    // 接入主链的区块，按高度从低到高
+    disconnected: Vec<Block>, // 从主链断开的区块，按高度从高到低
+}
+
+impl ChainChange {
+    //获取接入主链的区块
+    pub fn get_connected(&self) -> &[Block] {
+        self.connected.as_slice()
+    }
+
+    //获取从主链断开的区块
+    pub fn get_disconnected(&self) -> &[Block] {
+        self.disconnected.as_slice()
+    }
+
+    //区块只是保存在侧链上，主链没有变化
+    pub fn is_side_chain(&self) -> bool {
+        self.connected.is_empty()
+    }
+}
+
+/// 主链上已确认的交易
+#[derive(Debug, Clone)]
+pub struct ConfirmedTransaction {
+    tx: Transaction,
+    block_hash: String,   // 交易所在的区块
+    height: usize,        // 区块高度
+    confirmations: usize, // 确认数，所在区块为链尾时为 1
+}
+
+impl ConfirmedTransaction {
+    pub fn get_transaction(&self) -> &Transaction {
+        &self.tx
+    }
+
+    pub fn get_block_hash(&self) -> &str {
+        self.block_hash.as_str()
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    pub fn get_confirmations(&self) -> usize {
+        self.confirmations
+    }
+}
+
+/// 从内存池中挑选出的可以一起打包的交易
+#[derive(Debug, Clone, Default)]
+pub struct TxSelection {
+    transactions: Vec<Transaction>, // 选中的交易，按打包顺序排列
+    fees: Amount,                   // 选中交易的手续费总额
+    rejected: Vec<Transaction>,     // 无法在当前主链上打包的交易
+}
+
+impl TxSelection {
+    pub fn get_transactions(&self) -> &[Transaction] {
+        self.transactions.as_slice()
+    }
+
+    pub fn get_fees(&self) -> Amount {
+        self.fees
+    }
+
+    pub fn get_rejected(&self) -> &[Transaction] {
+        self.rejected.as_slice()
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct BlockChain {
     tip_hash: Arc<RwLock<String>>, // hash of last block
-    db: Db,
+    store: Arc<dyn ChainStore>,
+    chain_lock: Arc<Mutex<()>>, // 保证同一时间只有一个区块在切换主链
 }
 
 impl BlockChain {
     // 生成创世块
-    fn new_genesis_block(transaction: &Transaction) -> Block {
+    fn new_genesis_block(transaction: &Transaction) -> Result<Block> {
         let transaction = vec![transaction.clone()];
-        let block = Block::new_block(&transaction, String::from("None"), 0);
-        block
+        Block::new_block(
+            &transaction,
+            String::from(GENESIS_PRE_HASH),
+            0,
+            pow::pow_limit_bits(),
+        )
     }
 
-    // 创建新的区块链
-    pub fn create_blockchain(genesis_address: &str) -> BlockChain {
-        dotenv().ok();
-        let key = "DBName";
-        let name = env::var(key).expect("获取环境变量失败");
-        let db = sled::open(name).expect("数据库不存在");
-        let blocks_tree = db.open_tree(BLOCKS_TREE).expect("block库不存在");
-        let last_hash = blocks_tree
-            .get(TIP_BLOCK_HASH_KEY)
-            .expect("获取最后一个块的哈希失败");
-
-        let tip_hash;
-        if last_hash.is_none() {
-            let coinbase_tx = Transaction::new_coinbase_tx(genesis_address); //新建coinbase交易
-            let block = self::BlockChain::new_genesis_block(&coinbase_tx); //创世块
-            self::BlockChain::update_blocks_tree(&blocks_tree, &block); //写入数据库
-            tip_hash = String::from(block.get_hash());
-        } else {
-            tip_hash = String::from_utf8(last_hash.unwrap().to_vec()).unwrap();
-        }
-
-        BlockChain {
+    // 创建新的区块链，数据保存在环境变量 DBName 指定的数据库中
+    pub fn create_blockchain(genesis_address: &str) -> Result<BlockChain> {
+        BlockChain::create_with_store(Arc::new(SledStore::from_env()?), genesis_address)
+    }
+
+    /// 打开环境变量 DBName 指定的数据库中已有的区块链
+    pub fn new_blockchain() -> Result<BlockChain> {
+        BlockChain::open_with_store(Arc::new(SledStore::from_env()?))
+    }
+
+    /// 在指定的存储后端中创建区块链，已有区块链时直接打开
+    pub fn create_with_store(
+        store: Arc<dyn ChainStore>,
+        genesis_address: &str,
+    ) -> Result<BlockChain> {
+        let tip_hash = match store.get_tip()? {
+            Some(tip_hash) => tip_hash,
+            None => {
+                let coinbase_tx =
+                    Transaction::new_coinbase_tx(genesis_address, 0, Amount::ZERO, &[])?; //新建coinbase交易
+                let block = self::BlockChain::new_genesis_block(&coinbase_tx)?; //创世块
+                let index = BlockIndex {
+                    chain_work: pow::block_work(block.get_bits()).to_bytes_be().1,
+                    failed: false,
+                };
+                store.put_block(&block)?;
+                store.put_block_index(block.get_hash(), &index)?;
+                store.reset_address_history(&[])?;
+                store.write_chainstate(&UTXOSet::update(store.as_ref(), &block)?)?;
+                String::from(block.get_hash())
+            }
+        };
+
+        Ok(BlockChain {
             tip_hash: Arc::new(RwLock::new(tip_hash)),
-            db,
-        }
+            store,
+            chain_lock: Arc::new(Mutex::new(())),
+        })
     }
 
-    /// 创建区块链实例
-    pub fn new_blockchain() -> BlockChain {
-        dotenv().ok();
-        let key = "DBName";
-        let name = env::var(key).expect("获取环境变量失败");
-        let db = sled::open(name).expect("数据库不存在");
-        let blocks_tree = db.open_tree(BLOCKS_TREE).unwrap();
-        let tip_bytes = blocks_tree
-            .get(TIP_BLOCK_HASH_KEY)
-            .unwrap()
-            .expect("No existing blockchain found. Create one first.");
-        let tip_hash = String::from_utf8(tip_bytes.to_vec()).unwrap();
-        BlockChain {
+    /// 打开指定存储后端中已有的区块链
+    pub fn open_with_store(store: Arc<dyn ChainStore>) -> Result<BlockChain> {
+        let tip_hash = store.get_tip()?.ok_or(Error::NoBlockchain)?;
+        let blockchain = BlockChain {
             tip_hash: Arc::new(RwLock::new(tip_hash)),
-            db,
-        }
-    }
-
-    // 添加一个区块到区块链
-    pub fn add_block(&self, block: &Block) {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        let _: TransactionResult<(), ()> = block_tree.transaction(|tx_db| {
-            // 获取上一个区块字节数据
-            let tip_block_bytes = tx_db
-                .get(self.get_tip_hash())
-                .unwrap()
-                .expect("The tip hash is not valid");
-
-            // 上一个区块字节数据转换为区块
-            let tip_block: Block = coder::deserialized(tip_block_bytes.as_ref());
-
-            // 对比区块高度
-            if block.get_height() > tip_block.get_height() {
-                //当前区块写入数据库
-                tx_db
-                    .insert(block.get_hash(), coder::serialized(&block))
-                    .expect("插入新区块失败");
-
-                //数据库插入当前区块hash
-                tx_db
-                    .insert(TIP_BLOCK_HASH_KEY, block.get_hash())
-                    .expect("数据库插入tip_Hash失败");
-                //替换链上的tip_hash
-                self.set_tip_hash(block.get_hash());
-            }
-            Ok(())
-        });
+            store,
+            chain_lock: Arc::new(Mutex::new(())),
+        };
+        // 旧版本的数据库没有高度索引，打开时重建
+        let tip_block = blockchain.get_tip_block()?;
+        let indexed_hash = blockchain
+            .store
+            .get_block_hash_by_height(tip_block.get_height())?;
+        if indexed_hash.as_deref() != Some(tip_block.get_hash()) {
+            blockchain.reindex_heights()?;
+        }
+        blockchain.recover()?;
+        // 旧版本的数据库没有地址索引，打开时重建
+        if !blockchain.store.has_address_index()? {
+            let entries = blockchain.store.get_utxos()?;
+            blockchain.store.reset_utxos(entries.as_slice())?;
+            blockchain.reindex_address_history()?;
+        }
+        Ok(blockchain)
     }
 
-    // 更新区块树
-    fn update_blocks_tree(blocks_tree: &Tree, block: &Block) {
-        let block_hash = block.get_hash(); //区块hash
-        let _: TransactionResult<(), ()> = blocks_tree.transaction(|tx_db| {
-            let _ = tx_db.insert(block_hash, block.clone()); //插入当前区块hash和区块数据
-            let _ = tx_db.insert(TIP_BLOCK_HASH_KEY, block_hash); //将当前区块哈希值作为尾巴写入数据库
-            Ok(())
-        });
+    // 从链尾回溯重建主链的高度索引
+    fn reindex_heights(&self) -> Result<()> {
+        info!("Rebuild height index");
+        let mut block_hashes = vec![];
+        let mut iterator = self.iterator();
+        while let Some(block) = iterator.next()? {
+            block_hashes.push(block.get_hash().to_string());
+        }
+        block_hashes.reverse();
+        self.store.reset_height_index(block_hashes.as_slice())
     }
 
-    /// 挖矿新区块
-    pub fn mine_block(&self, transactions: &[Transaction]) -> Block {
-        for transaction in transactions {
-            //验证签名
-            if transaction.verify(self) == false {
-                panic!("ERROR: Invalid transaction")
+    // 扫描主链，用每个区块的撤销数据重建地址索引的交易历史部分
+    pub(crate) fn reindex_address_history(&self) -> Result<()> {
+        info!("Rebuild address index");
+        let mut history = vec![];
+        for block in self.range(..)? {
+            let block = block?;
+            let spent = self.store.get_undo(block.get_hash())?.ok_or_else(|| {
+                Error::Corrupted(format!("undo data of block {} not found", block.get_hash()))
+            })?;
+            for (script_key, txid) in block_address_history(&block, spent.as_slice())? {
+                history.push((script_key, AddressTx::new(block.get_height(), &txid)));
             }
         }
-        let best_height = self.get_best_height();
-        let block = Block::new_block(transactions, self.get_tip_hash(), best_height);
-        let block_hash = block.get_hash();
+        self.store.reset_address_history(history.as_slice())
+    }
+
+    // 上次切换主链时进程中断，继续切换到记录的目标区块
+    // 每次接入或断开区块都是原子的，中断后主链停在某个一致的中间状态，只需处理剩下的区块
+    fn recover(&self) -> Result<()> {
+        let target_hash = match self.store.get_reorg_target()? {
+            Some(target_hash) => target_hash,
+            None => return Ok(()),
+        };
+        let _guard = self.chain_lock.lock().unwrap();
+        let target = self
+            .get_block(target_hash.as_bytes())?
+            .ok_or_else(|| Error::Corrupted(format!("reorg target {} not found", target_hash)))?;
+        info!("Resume reorganize to {}", target_hash);
+        match self.reorganize(&target) {
+            Err(Error::Block(e)) => warn!("Failed to resume reorganize: {}", e),
+            result => {
+                result?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 添加一个区块到区块链
+    /// 通过校验的区块都会保存，包括侧链上的区块；当某条分支的累计工作量超过主链时，切换到该分支
+    pub fn add_block(&self, block: &Block) -> Result<ChainChange> {
+        let _guard = self.chain_lock.lock().unwrap();
+
+        // 延长当前主链：完整校验后直接接入
+        if block.get_pre_block_hash() == self.get_tip_hash() {
+            self.validate_block(block)?;
+            self.store_block(block)?;
+            self.connect_block(block)?;
+            return Ok(ChainChange {
+                connected: vec![block.clone()],
+                disconnected: vec![],
+            });
+        }
+
+        // 侧链区块：先做不依赖 UTXO 集的检查，通过后保存
+        // 区块哈希由对方提供，校验通过后才能用来查找
+        validation::check_block(block)?;
+        if self.get_block(block.get_hash().as_bytes())?.is_some() {
+            return Err(BlockError::Duplicate.into());
+        }
+        let parent = self
+            .get_block(block.get_pre_block_hash().as_bytes())?
+            .ok_or(BlockError::UnknownParent)?;
+        self.contextual_check_block(block, &parent)?;
+        let chain_work = self.store_block(block)?;
+
+        // 累计工作量不超过主链，只保存不切换
+        let tip_work = self.get_chain_work(self.get_tip_hash().as_str())?;
+        if chain_work <= tip_work {
+            return Ok(ChainChange::default());
+        }
+        self.reorganize(block)
+    }
+
+    // 保存区块及其索引，返回该区块的累计工作量
+    fn store_block(&self, block: &Block) -> Result<BigInt> {
+        let parent_index = self
+            .store
+            .get_block_index(block.get_pre_block_hash().as_str())?
+            .ok_or(BlockError::UnknownParent)?;
+        if parent_index.failed {
+            return Err(BlockError::BadParent.into());
+        }
+        let chain_work = parent_index.get_chain_work() + pow::block_work(block.get_bits());
+        let index = BlockIndex {
+            chain_work: chain_work.to_bytes_be().1,
+            failed: false,
+        };
+        self.store.put_block(block)?;
+        self.store.put_block_index(block.get_hash(), &index)?;
+        Ok(chain_work)
+    }
+
+    // 将已保存的区块接入主链链尾，更新 UTXO 集并写入撤销数据
+    fn connect_block(&self, block: &Block) -> Result<()> {
+        let mut update = UTXOSet::update(self.store.as_ref(), block)?;
+        if self.store.has_tx_index()? {
+            update.set_txids(Self::block_txids(block));
+        }
+        self.store.write_chainstate(&update)?;
+        self.set_tip_hash(block.get_hash());
+        Ok(())
+    }
+
+    // 将链尾区块从主链断开：按撤销数据恢复 UTXO 集，链尾退回上一区块
+    fn disconnect_block(&self, block: &Block) -> Result<()> {
+        let mut update = UTXOSet::revert(self.store.as_ref(), block)?;
+        if self.store.has_tx_index()? {
+            update.set_txids(Self::block_txids(block));
+        }
+        self.store.write_chainstate(&update)?;
+        self.set_tip_hash(update.get_tip_hash());
+        Ok(())
+    }
 
-        let blocks_tree = self.db.open_tree(BLOCKS_TREE).expect("无法找到区块树");
-        Self::update_blocks_tree(&blocks_tree, &block);
-        self.set_tip_hash(block_hash);
+    // 区块中全部交易的ID，按在区块中的顺序
+    fn block_txids(block: &Block) -> Vec<Vec<u8>> {
         block
+            .get_transactions()
+            .iter()
+            .map(|tx| tx.get_id().to_vec())
+            .collect()
     }
 
-    /// 查找所有未花费的交易输出 ( K -> txid_hex, V -> Vec<TXOutput )
-    pub fn find_utxo(&self) -> HashMap<String, Vec<TXOutput>> {
-        let mut utxo: HashMap<String, Vec<TXOutput>> = HashMap::new();
-        let mut spent_txos: HashMap<String, Vec<usize>> = HashMap::new();
+    // 切换主链到以 new_tip 结尾的分支：断开旧主链上分叉点之后的区块，再依次接入新分支的区块
+    fn reorganize(&self, new_tip: &Block) -> Result<ChainChange> {
+        let old_tip = self.get_tip_block()?;
 
-        let mut iterator = self.iterator();
+        // 从两条链的链尾同时回溯，找到分叉点
+        let mut disconnected = vec![];
+        let mut connected = vec![];
+        let mut old = old_tip.clone();
+        let mut new = new_tip.clone();
+        while old.get_hash() != new.get_hash() {
+            let old_height = old.get_height();
+            let new_height = new.get_height();
+            if old_height >= new_height {
+                let parent = self.get_parent(&old)?;
+                disconnected.push(old);
+                old = parent;
+            }
+            if new_height >= old_height {
+                let parent = self.get_parent(&new)?;
+                connected.push(new);
+                new = parent;
+            }
+        }
+        connected.reverse();
+
+        // 新分支上有已判定无效的区块时不切换
+        for block in &connected {
+            if self.is_failed(block.get_hash())? {
+                self.mark_failed(new_tip.get_hash())?;
+                return Err(BlockError::BadParent.into());
+            }
+        }
+        info!(
+            "Reorganize: disconnect {} blocks, connect {} blocks from fork {}",
+            disconnected.len(),
+            connected.len(),
+            old.get_hash()
+        );
+
+        // 先记录切换目标，进程中断后重启时继续完成切换
+        self.store.set_reorg_target(Some(new_tip.get_hash()))?;
+        for block in &disconnected {
+            self.disconnect_block(block)?;
+        }
+        for (i, block) in connected.iter().enumerate() {
+            match self.check_transactions(block.get_transactions()) {
+                Ok(_) => {}
+                Err(Error::Block(e)) => {
+                    // 新分支无效，标记后按撤销数据回到原来的主链
+                    self.mark_failed(block.get_hash())?;
+                    self.store.set_reorg_target(Some(old_tip.get_hash()))?;
+                    for block in connected[..i].iter().rev() {
+                        self.disconnect_block(block)?;
+                    }
+                    for block in disconnected.iter().rev() {
+                        self.connect_block(block)?;
+                    }
+                    self.store.set_reorg_target(None)?;
+                    return Err(e.into());
+                }
+                // 数据库错误时保留切换目标，重启后继续切换
+                Err(e) => return Err(e),
+            }
+            self.connect_block(block)?;
+        }
+        self.store.set_reorg_target(None)?;
+        Ok(ChainChange {
+            connected,
+            disconnected,
+        })
+    }
+
+    // 获取链尾区块
+    fn get_tip_block(&self) -> Result<Block> {
+        let tip_hash = self.get_tip_hash();
+        self.get_block(tip_hash.as_bytes())?
+            .ok_or_else(|| Error::Corrupted(format!("tip block {} not found", tip_hash)))
+    }
+
+    // 获取上一区块，调用方保证区块已经保存且不是创世块
+    fn get_parent(&self, block: &Block) -> Result<Block> {
+        self.get_block(block.get_pre_block_hash().as_bytes())?
+            .ok_or_else(|| {
+                Error::Corrupted(format!("parent of block {} not found", block.get_hash()))
+            })
+    }
+
+    // 区块是否已被判定无效
+    fn is_failed(&self, block_hash: &str) -> Result<bool> {
+        Ok(self
+            .store
+            .get_block_index(block_hash)?
+            .is_some_and(|index| index.failed))
+    }
+
+    // 区块是否在主链上
+    fn is_in_main_chain(&self, block: &Block) -> Result<bool> {
+        let block_hash = self.store.get_block_hash_by_height(block.get_height())?;
+        Ok(block_hash.as_deref() == Some(block.get_hash()))
+    }
+
+    /// 将区块标记为无效，之后不会再切换到包含该区块的分支
+    /// 区块在主链上时，断开它及之后的区块，再切换到累计工作量最大的有效分支
+    /// 找不到区块或者区块是创世块时返回 None
+    pub fn invalidate_block(&self, block_hash: &str) -> Result<Option<ChainChange>> {
+        let _guard = self.chain_lock.lock().unwrap();
+        if self.store.get_block_index(block_hash)?.is_none() {
+            return Ok(None);
+        }
+        let block = match self.get_block(block_hash.as_bytes())? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        if block.get_pre_block_hash() == GENESIS_PRE_HASH {
+            return Ok(None);
+        }
+        self.mark_failed(block_hash)?;
+
+        let mut disconnected = vec![];
+        if self.is_in_main_chain(&block)? {
+            loop {
+                let tip = self.get_tip_block()?;
+                self.disconnect_block(&tip)?;
+                let done = tip.get_hash() == block_hash;
+                disconnected.push(tip);
+                if done {
+                    break;
+                }
+            }
+        }
+        let mut change = ChainChange {
+            connected: vec![],
+            disconnected,
+        };
+        if !change.disconnected.is_empty() {
+            let reorg = self.activate_best_chain()?;
+            change.disconnected.extend(reorg.disconnected);
+            change.connected = reorg.connected;
+        }
+        Ok(Some(change))
+    }
+
+    // 切换到累计工作量超过当前主链的最重有效分支，没有时保持不变
+    // 切换失败的分支会被标记为无效（包括无效区块的后代），然后尝试下一个
+    fn activate_best_chain(&self) -> Result<ChainChange> {
         loop {
-            let option = iterator.next();
-            if option.is_none() {
+            let tip_work = self.get_chain_work(self.get_tip_hash().as_str())?;
+            let best = self
+                .store
+                .get_block_indexes()?
+                .into_iter()
+                .filter(|(_, index)| !index.failed && index.get_chain_work() > tip_work)
+                .max_by_key(|(_, index)| index.get_chain_work());
+            let best_hash = match best {
+                Some((best_hash, _)) => best_hash,
+                None => return Ok(ChainChange::default()),
+            };
+            let best_block = self.get_block(best_hash.as_bytes())?.ok_or_else(|| {
+                Error::Corrupted(format!("indexed block {} not found", best_hash))
+            })?;
+            match self.reorganize(&best_block) {
+                Ok(change) => return Ok(change),
+                Err(Error::Block(e)) => {
+                    warn!("Failed to activate branch {}: {}", best_hash, e);
+                    // 无效区块在分支中间时，链尾本身还没有标记，避免再次选中
+                    self.mark_failed(best_hash.as_str())?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // 标记区块校验失败
+    fn mark_failed(&self, block_hash: &str) -> Result<()> {
+        if let Some(mut index) = self.store.get_block_index(block_hash)? {
+            index.failed = true;
+            self.store.put_block_index(block_hash, &index)?;
+        }
+        Ok(())
+    }
+
+    /// 从创世块到指定区块的累计工作量
+    pub fn get_chain_work(&self, block_hash: &str) -> Result<BigInt> {
+        Ok(match self.store.get_block_index(block_hash)? {
+            Some(index) => index.get_chain_work(),
+            None => BigInt::from(0),
+        })
+    }
+
+    /// 挖矿新区块，coinbase 交易必须放在第一位
+    pub fn mine_block(&self, transactions: &[Transaction]) -> Result<Block> {
+        // 挖矿前先校验交易，避免为无效的交易计算工作量证明
+        for tx in transactions {
+            validation::check_transaction(tx)?;
+        }
+        self.check_transactions(transactions)?;
+
+        let tip_block = self.get_tip_block()?;
+        let bits = self.get_next_work_required(&tip_block)?;
+        let block = Block::new_block(
+            transactions,
+            self.get_tip_hash(),
+            tip_block.get_height() + 1,
+            bits,
+        )?;
+        self.add_block(&block)?;
+        Ok(block)
+    }
+
+    /// 区块校验
+    /// 1. 上下文无关的检查：哈希、工作量证明、默克尔根、coinbase 位置和交易格式
+    /// 2. 依赖上一区块的检查：高度、难度和时间戳
+    /// 3. 依赖 UTXO 集的检查：输入未花费、签名、金额和 coinbase 奖励
+    pub fn validate_block(&self, block: &Block) -> Result<()> {
+        // 区块哈希由对方提供，校验通过后才能用来查找
+        validation::check_block(block)?;
+        if self.get_block(block.get_hash().as_bytes())?.is_some() {
+            return Err(BlockError::Duplicate.into());
+        }
+
+        let parent = self
+            .get_block(block.get_pre_block_hash().as_bytes())?
+            .ok_or(BlockError::UnknownParent)?;
+        self.contextual_check_block(block, &parent)?;
+
+        // UTXO 集只对应当前链尾，暂时只接受延长当前链的区块
+        if parent.get_hash() != self.get_tip_hash() {
+            return Err(BlockError::PrevNotTip.into());
+        }
+        self.check_transactions(block.get_transactions())?;
+        Ok(())
+    }
+
+    // 依赖上一区块的检查
+    fn contextual_check_block(&self, block: &Block, parent: &Block) -> Result<()> {
+        let expected_height = parent.get_height() + 1;
+        if block.get_height() != expected_height {
+            return Err(BlockError::BadHeight {
+                expected: expected_height,
+                actual: block.get_height(),
+            }
+            .into());
+        }
+        // 难度必须符合难度调整规则
+        let expected_bits = self.get_next_work_required(parent)?;
+        if block.get_bits() != expected_bits {
+            return Err(BlockError::BadDifficulty {
+                expected: expected_bits,
+                actual: block.get_bits(),
+            }
+            .into());
+        }
+        // 时间戳不能早于过去区块的中位时间，也不能超前本地时间太多
+        if block.get_timestamp() < self.get_median_time_past(parent)? {
+            return Err(BlockError::TimeTooOld.into());
+        }
+        if block.get_timestamp() > Utc::now().timestamp() + MAX_FUTURE_BLOCK_TIME {
+            return Err(BlockError::TimeTooNew.into());
+        }
+        // coinbase 必须写入区块高度
+        let coinbase_height = block
+            .get_transactions()
+            .first()
+            .and_then(|tx| tx.get_coinbase_height());
+        if coinbase_height != Some(block.get_height()) {
+            return Err(BlockError::BadCoinbaseHeight {
+                expected: block.get_height(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    // 依赖 UTXO 集的交易检查，交易只能花费已经上链的未花费输出
+    // 交易打包在链尾之后的区块中，时间锁按该区块的高度和链尾的中位时间判断
+    // 每笔交易输入减去输出的差额是手续费，返回手续费总额
+    fn check_transactions(&self, transactions: &[Transaction]) -> Result<Amount> {
+        let utxo_set = UTXOSet::new(self.clone());
+        let tip_block = self.get_tip_block()?;
+        let height = tip_block.get_height() + 1;
+        let median_time_past = self.get_median_time_past(&tip_block)?;
+        let mut spent = HashSet::new();
+        // 区块中前面的交易产生的输出，后面的交易可以花费
+        let mut created: HashMap<(Vec<u8>, usize), UTXOEntry> = HashMap::new();
+        let mut fees = Amount::ZERO;
+        for tx in transactions {
+            if !tx.is_final(height, median_time_past) {
+                return Err(BlockError::NonFinalTransaction(HEXLOWER.encode(tx.get_id())).into());
+            }
+        }
+        // 交易ID与仍有未花费输出的交易重复时，新输出会覆盖旧输出，必须拒绝
+        for tx in transactions {
+            for idx in 0..tx.get_vout().len() {
+                if utxo_set.get_entry(tx.get_id(), idx)?.is_some() {
+                    return Err(
+                        BlockError::DuplicateTransaction(HEXLOWER.encode(tx.get_id())).into(),
+                    );
+                }
+            }
+        }
+        for tx in transactions {
+            let txid_hex = HEXLOWER.encode(tx.get_id());
+            if tx.is_coinbase() {
+                Self::add_created_outputs(&mut created, tx, height, &txid_hex)?;
+                continue;
+            }
+            let mut values_in = vec![];
+            for (idx, vin) in tx.get_vin().iter().enumerate() {
+                // 同一区块内不能重复花费同一个输出
+                if !spent.insert((vin.get_txid(), vin.get_vout())) {
+                    return Err(BlockError::DoubleSpend(txid_hex).into());
+                }
+                // 先查找区块中前面的交易产生的输出，再查找 UTXO 集，与 UTXOSet::update 的规则一致
+                let outpoint = (vin.get_txid().to_vec(), vin.get_vout());
+                let entry = match created.get(&outpoint) {
+                    Some(entry) => entry.clone(),
+                    None => match utxo_set.get_entry(vin.get_txid(), vin.get_vout())? {
+                        Some(entry) => entry,
+                        None if self.find_transaction(vin.get_txid())?.is_some() => {
+                            return Err(BlockError::DoubleSpend(txid_hex).into());
+                        }
+                        None => return Err(BlockError::MissingInputs(txid_hex).into()),
+                    },
+                };
+                if !self.check_sequence_lock(vin, &entry, &tip_block, median_time_past)? {
+                    return Err(BlockError::SequenceLocked(txid_hex).into());
+                }
+                let out = entry.get_output();
+                // 输入的解锁脚本必须满足被花费输出的锁定脚本
+                if let Err(e) = tx.verify_input(idx, out.get_script_pub_key()) {
+                    return Err(BlockError::BadScript(txid_hex, e).into());
+                }
+                values_in.push(out.get_value());
+            }
+            // 输入和输出总额都不能溢出或超过 MAX_MONEY
+            let value_in = Amount::checked_sum(values_in)
+                .ok_or_else(|| BlockError::BadTransaction(txid_hex.clone()))?;
+            let value_out = Amount::checked_sum(tx.get_vout().iter().map(|out| out.get_value()))
+                .ok_or_else(|| BlockError::BadTransaction(txid_hex.clone()))?;
+            let fee = value_in
+                .checked_sub(value_out)
+                .ok_or_else(|| BlockError::InsufficientInputs(txid_hex.clone()))?;
+            fees = Amount::checked_sum([fees, fee])
+                .ok_or_else(|| BlockError::BadTransaction(txid_hex.clone()))?;
+            Self::add_created_outputs(&mut created, tx, height, &txid_hex)?;
+        }
+        // coinbase 奖励不能超过发行计划中该高度的挖矿奖励加手续费总额
+        for tx in transactions.iter().filter(|tx| tx.is_coinbase()) {
+            let height = tx.get_coinbase_height().ok_or(BlockError::BadCoinbase)?;
+            let max = GLOBAL_EMISSION
+                .subsidy_at(height)
+                .checked_add(fees)
+                .ok_or(BlockError::BadCoinbase)?;
+            let reward = Amount::checked_sum(tx.get_vout().iter().map(|out| out.get_value()))
+                .ok_or(BlockError::BadCoinbase)?;
+            if reward > max {
+                return Err(BlockError::BadCoinbaseAmount {
+                    max,
+                    actual: reward,
+                }
+                .into());
+            }
+        }
+        Ok(fees)
+    }
+
+    // 记录交易产生的输出，供同一区块中后面的交易花费，同一区块中交易ID重复时拒绝
+    fn add_created_outputs(
+        created: &mut HashMap<(Vec<u8>, usize), UTXOEntry>,
+        tx: &Transaction,
+        height: usize,
+        txid_hex: &str,
+    ) -> Result<()> {
+        for (idx, out) in tx.get_vout().iter().enumerate() {
+            let entry = UTXOEntry::new(tx.get_id(), idx, out.clone(), height);
+            if created.insert((tx.get_id_bytes(), idx), entry).is_some() {
+                return Err(BlockError::DuplicateTransaction(txid_hex.to_string()).into());
+            }
+        }
+        Ok(())
+    }
+
+    // 检查输入的相对时间锁是否到期，交易打包在 tip_block 之后的区块中
+    // 以区块数为单位时，从被花费的输出所在区块开始计算经过的区块数
+    // 以时间为单位时，从输出所在区块的上一区块的中位时间开始计算经过的时间
+    fn check_sequence_lock(
+        &self,
+        vin: &TXInput,
+        entry: &UTXOEntry,
+        tip_block: &Block,
+        median_time_past: i64,
+    ) -> Result<bool> {
+        let sequence = vin.get_sequence();
+        if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return Ok(true);
+        }
+        let value = sequence & SEQUENCE_LOCKTIME_MASK;
+        if sequence & SEQUENCE_LOCKTIME_TYPE_FLAG == 0 {
+            return Ok(tip_block.get_height() + 1 >= entry.get_height() + value as usize);
+        }
+        let prev_height = entry.get_height().saturating_sub(1);
+        let prev_block = self.get_ancestor(tip_block, prev_height)?.ok_or_else(|| {
+            Error::Corrupted(format!("block at height {} not found", prev_height))
+        })?;
+        let lock_time = self.get_median_time_past(&prev_block)?
+            + ((value as i64) << SEQUENCE_LOCKTIME_GRANULARITY);
+        Ok(median_time_past >= lock_time)
+    }
+
+    // 从 block 向前回溯，找到同一条链上指定高度的区块
+    fn get_ancestor(&self, block: &Block, height: usize) -> Result<Option<Block>> {
+        let mut current = block.clone();
+        while current.get_height() > height {
+            current = match self.get_block(current.get_pre_block_hash().as_bytes())? {
+                Some(block) => block,
+                None => return Ok(None),
+            };
+        }
+        Ok(match current.get_height() == height {
+            true => Some(current),
+            false => None,
+        })
+    }
+
+    /// 检查一笔未上链的交易能否在当前主链上被打包，返回交易的手续费
+    pub fn validate_transaction(&self, tx: &Transaction) -> Result<Amount> {
+        self.validate_transactions(std::slice::from_ref(tx))
+    }
+
+    /// 检查一组未上链的交易能否一起在当前主链上被打包，返回手续费总额
+    pub fn validate_transactions(&self, transactions: &[Transaction]) -> Result<Amount> {
+        for tx in transactions {
+            validation::check_transaction(tx)?;
+            if tx.is_coinbase() {
+                return Err(BlockError::BadCoinbase.into());
+            }
+        }
+        self.check_transactions(transactions)
+    }
+
+    /// 从候选交易中挑选一组互不冲突、可以一起打包的交易
+    /// 能单独在主链上打包的交易按手续费从高到低加入，与已选交易花费同一输出的交易跳过但不算无效
+    /// 依赖其他候选交易输出的交易在其父交易选中后再尝试，父交易未被选中的同样跳过
+    /// 最终仍无法打包的交易作为无效交易返回
+    pub fn select_transactions(&self, candidates: Vec<Transaction>) -> Result<TxSelection> {
+        let candidate_ids: HashSet<Vec<u8>> =
+            candidates.iter().map(|tx| tx.get_id_bytes()).collect();
+        let mut scored = vec![];
+        let mut pending = vec![];
+        for tx in candidates {
+            match self.validate_transaction(&tx) {
+                Ok(fee) => scored.push((fee, tx)),
+                Err(Error::Block(_)) => pending.push(tx),
+                Err(e) => return Err(e),
+            }
+        }
+        scored.sort_by(|(a_fee, a), (b_fee, b)| {
+            b_fee.cmp(a_fee).then_with(|| a.get_id().cmp(b.get_id()))
+        });
+
+        let mut selection = TxSelection::default();
+        let mut spent = HashSet::new();
+        let conflicts = |spent: &HashSet<(Vec<u8>, usize)>, tx: &Transaction| {
+            tx.get_vin()
+                .iter()
+                .any(|vin| spent.contains(&(vin.get_txid().to_vec(), vin.get_vout())))
+        };
+        for (_, tx) in scored {
+            if conflicts(&spent, &tx) {
+                continue;
+            }
+            for vin in tx.get_vin() {
+                spent.insert((vin.get_txid().to_vec(), vin.get_vout()));
+            }
+            selection.transactions.push(tx);
+        }
+
+        // 花费其他候选交易输出的交易，直到没有新的交易可以加入
+        loop {
+            let mut progressed = false;
+            let mut remaining = vec![];
+            for tx in pending {
+                // 已选交易花费了同一输出，之后也不可能再加入
+                if conflicts(&spent, &tx) {
+                    continue;
+                }
+                selection.transactions.push(tx);
+                match self.validate_transactions(selection.transactions.as_slice()) {
+                    Ok(_) => {
+                        let tx = selection.transactions.last().unwrap();
+                        for vin in tx.get_vin() {
+                            spent.insert((vin.get_txid().to_vec(), vin.get_vout()));
+                        }
+                        progressed = true;
+                    }
+                    Err(Error::Block(_)) => remaining.push(selection.transactions.pop().unwrap()),
+                    Err(e) => return Err(e),
+                }
+            }
+            pending = remaining;
+            if !progressed {
                 break;
             }
-            let block = option.unwrap();
-            'outer: for tx in block.get_transactions() {
-                let txid_hex = HEXLOWER.encode(tx.get_id());
+        }
+        // 父交易还在候选交易中但没有被选中时，交易本身不一定无效
+        let selected: HashSet<&[u8]> = selection
+            .transactions
+            .iter()
+            .map(|tx| tx.get_id())
+            .collect();
+        selection.rejected = pending
+            .into_iter()
+            .filter(|tx| {
+                !tx.get_vin().iter().any(|vin| {
+                    candidate_ids.contains(vin.get_txid()) && !selected.contains(vin.get_txid())
+                })
+            })
+            .collect();
+        selection.fees = self.validate_transactions(selection.transactions.as_slice())?;
+        Ok(selection)
+    }
+
+    /// 区块及其之前 MEDIAN_TIME_SPAN - 1 个区块时间戳的中位数
+    pub fn get_median_time_past(&self, block: &Block) -> Result<i64> {
+        let mut timestamps = vec![block.get_timestamp()];
+        let mut current = block.clone();
+        while timestamps.len() < MEDIAN_TIME_SPAN {
+            match self.get_block(current.get_pre_block_hash().as_bytes())? {
+                Some(block) => {
+                    timestamps.push(block.get_timestamp());
+                    current = block;
+                }
+                None => break,
+            }
+        }
+        timestamps.sort_unstable();
+        Ok(timestamps[timestamps.len() / 2])
+    }
+
+    /// 按难度调整规则计算 parent 之后下一个区块应使用的难度
+    /// 每隔 RETARGET_INTERVAL 个区块，根据上一个调整周期的实际出块时间重新计算目标值，否则沿用父区块的难度
+    pub fn get_next_work_required(&self, parent: &Block) -> Result<u32> {
+        let height = parent.get_height() + 1;
+        if !height.is_multiple_of(RETARGET_INTERVAL) {
+            return Ok(parent.get_bits());
+        }
+        // 回溯到本调整周期的第一个区块
+        let mut first = parent.clone();
+        for _ in 1..RETARGET_INTERVAL {
+            match self.get_block(first.get_pre_block_hash().as_bytes())? {
+                Some(block) => first = block,
+                None => break,
+            }
+        }
+        Ok(pow::calculate_next_bits(
+            parent.get_bits(),
+            first.get_timestamp(),
+            parent.get_timestamp(),
+        ))
+    }
+
+    /// 查找主链上所有未花费的交易输出
+    pub fn find_utxo(&self) -> Result<Vec<UTXOEntry>> {
+        let mut utxos = vec![];
+        let mut spent_txos: HashSet<(Vec<u8>, usize)> = HashSet::new();
+
+        let mut iterator = self.iterator();
+        while let Some(block) = iterator.next()? {
+            // 从链尾往前遍历，区块内后面的交易可能花费前面交易的输出，所以区块内也倒序处理
+            for tx in block.get_transactions().iter().rev() {
                 for (idx, out) in tx.get_vout().iter().enumerate() {
                     // 过滤已花费的输出
-                    if let Some(outs) = spent_txos.get(txid_hex.as_str()) {
-                        for spend_out_idx in outs {
-                            if idx.eq(spend_out_idx) {
-                                continue 'outer;
-                            }
-                        }
-                    }
-                    if utxo.contains_key(txid_hex.as_str()) {
-                        utxo.get_mut(txid_hex.as_str()).unwrap().push(out.clone());
-                    } else {
-                        utxo.insert(txid_hex.clone(), vec![out.clone()]);
+                    if spent_txos.contains(&(tx.get_id_bytes(), idx)) {
+                        continue;
                     }
+                    utxos.push(UTXOEntry::new(
+                        tx.get_id(),
+                        idx,
+                        out.clone(),
+                        block.get_height(),
+                    ));
                 }
                 if tx.is_coinbase() {
                     continue;
                 }
                 // 在输入中查找已花费输出
                 for tx_input in tx.get_vin() {
-                    let txid_hex = HEXLOWER.encode(tx_input.get_txid());
-                    if spent_txos.contains_key(txid_hex.as_str()) {
-                        spent_txos
-                            .get_mut(txid_hex.as_str())
-                            .unwrap()
-                            .push(tx_input.get_vout());
-                    } else {
-                        spent_txos.insert(txid_hex, vec![tx_input.get_vout()]);
-                    }
+                    spent_txos.insert((tx_input.get_txid().to_vec(), tx_input.get_vout()));
                 }
             }
         }
-        utxo
+        Ok(utxos)
     }
 
     /// 从区块链中查找交易
-    pub fn find_transaction(&self, txid: &[u8]) -> Option<Transaction> {
+    pub fn find_transaction(&self, txid: &[u8]) -> Result<Option<Transaction>> {
+        Ok(self.get_transaction(txid)?.map(|confirmed| confirmed.tx))
+    }
+
+    /// 查询主链上的交易及其所在区块和确认数
+    /// 维护交易索引时直接定位，否则从链尾开始逐个区块查找
+    pub fn get_transaction(&self, txid: &[u8]) -> Result<Option<ConfirmedTransaction>> {
+        let best_height = self.get_best_height()?;
+        let confirmed = |block: &Block, tx: &Transaction| ConfirmedTransaction {
+            tx: tx.clone(),
+            block_hash: block.get_hash().to_string(),
+            height: block.get_height(),
+            confirmations: best_height + 1 - block.get_height(),
+        };
+        if self.store.has_tx_index()? {
+            let location = match self.store.get_tx_location(txid)? {
+                Some(location) => location,
+                None => return Ok(None),
+            };
+            let block = self
+                .store
+                .get_block(location.get_block_hash())?
+                .ok_or_else(|| {
+                    Error::Corrupted(format!(
+                        "indexed block {} not found",
+                        location.get_block_hash()
+                    ))
+                })?;
+            let tx = block
+                .get_transactions()
+                .get(location.get_position())
+                .filter(|tx| tx.get_id() == txid)
+                .ok_or_else(|| Error::Corrupted(String::from("transaction index out of date")))?;
+            return Ok(Some(confirmed(&block, tx)));
+        }
         let mut iterator = self.iterator();
-        loop {
-            let option = iterator.next();
-            if option.is_none() {
-                break;
-            }
-            if let Some(block) = option {
-                for transaction in block.get_transactions() {
-                    if txid.eq(transaction.get_id()) {
-                        return Some(transaction.clone());
-                    }
+        while let Some(block) = iterator.next()? {
+            for transaction in block.get_transactions() {
+                if txid.eq(transaction.get_id()) {
+                    return Ok(Some(confirmed(&block, transaction)));
                 }
             }
         }
-        None
+        Ok(None)
+    }
+
+    /// 是否维护交易索引
+    pub fn has_tx_index(&self) -> Result<bool> {
+        self.store.has_tx_index()
+    }
+
+    /// 开启或关闭交易索引，开启时扫描主链重建索引，关闭时删除索引
+    pub fn set_tx_index(&self, enabled: bool) -> Result<()> {
+        let _guard = self.chain_lock.lock().unwrap();
+        if !enabled {
+            return self.store.reset_tx_index(None);
+        }
+        info!("Rebuild transaction index");
+        let mut locations = vec![];
+        for block in self.range(..)? {
+            let block = block?;
+            for (position, tx) in block.get_transactions().iter().enumerate() {
+                let location = TxLocation::new(block.get_hash(), position);
+                locations.push((tx.get_id().to_vec(), location));
+            }
+        }
+        self.store.reset_tx_index(Some(locations.as_slice()))
     }
 
     //获取当前tip_hash
@@ -209,51 +1011,70 @@ impl BlockChain {
         *tip_hash = String::from(new_tip_hash)
     }
 
-    pub fn get_db(&self) -> &Db {
-        &self.db
+    // 获取存储后端
+    pub(crate) fn get_store(&self) -> &dyn ChainStore {
+        self.store.as_ref()
     }
 
     /// 获取最新区块在链中的高度
-    pub fn get_best_height(&self) -> usize {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).expect("获取区块集失败");
-        match block_tree.get(self.get_tip_hash()) {
-            Ok(block_tree) => {
-                let tip_block_bytes = block_tree.expect("The tip hash is valid");
-                let tip_block: Block = coder::deserialized(tip_block_bytes.as_ref());
-                tip_block.get_height()
-            }
-            _ => 0,
-        }
+    pub fn get_best_height(&self) -> Result<usize> {
+        Ok(self.get_tip_block()?.get_height())
     }
 
     /// 通过区块哈希查询区块
-    pub fn get_block(&self, block_hash: &[u8]) -> Option<Block> {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        if let Some(block_bytes) = block_tree.get(block_hash).unwrap() {
-            let block = coder::deserialized(block_bytes.as_ref());
-            return Some(block);
+    pub fn get_block(&self, block_hash: &[u8]) -> Result<Option<Block>> {
+        match std::str::from_utf8(block_hash) {
+            Ok(block_hash) => self.store.get_block(block_hash),
+            Err(_) => Ok(None),
         }
-        return None;
     }
 
-    /// 返回链中所有区块的哈希列表
-    pub fn get_block_hashes(&self) -> Vec<Vec<u8>> {
-        let mut iterator = self.iterator();
-        let mut blocks = vec![];
-        loop {
-            let option = iterator.next();
-            if option.is_none() {
-                break;
-            }
-            let block = option.unwrap();
-            blocks.push(block.get_hash_bytes());
+    /// 查询主链上指定高度的区块，超过链尾高度时返回 None
+    pub fn get_block_by_height(&self, height: usize) -> Result<Option<Block>> {
+        match self.store.get_block_hash_by_height(height)? {
+            Some(block_hash) => self.store.get_block(block_hash.as_str()),
+            None => Ok(None),
+        }
+    }
+
+    /// 按高度遍历主链上指定范围内的区块，超出链尾的部分被忽略
+    /// 返回的迭代器从低到高遍历，调用 rev() 从高到低遍历
+    pub fn range<R: RangeBounds<usize>>(&self, range: R) -> Result<BlockRange> {
+        let start = match range.start_bound() {
+            Bound::Included(start) => *start,
+            Bound::Excluded(start) => start.saturating_add(1),
+            Bound::Unbounded => 0,
+        };
+        let best_end = self.get_best_height()? + 1;
+        let end = match range.end_bound() {
+            Bound::Included(end) => best_end.min(end.saturating_add(1)),
+            Bound::Excluded(end) => best_end.min(*end),
+            Bound::Unbounded => best_end,
+        };
+        Ok(BlockRange {
+            store: self.store.clone(),
+            start,
+            end: end.max(start),
+        })
+    }
+
+    /// 返回链中所有区块的哈希列表，按高度从低到高排列，便于对方按顺序下载
+    pub fn get_block_hashes(&self) -> Result<Vec<Vec<u8>>> {
+        let best_height = self.get_best_height()?;
+        let mut blocks = Vec::with_capacity(best_height + 1);
+        for height in 0..=best_height {
+            let block_hash = self
+                .store
+                .get_block_hash_by_height(height)?
+                .ok_or_else(|| Error::Corrupted(format!("no block at height {}", height)))?;
+            blocks.push(block_hash.into_bytes());
         }
-        return blocks;
+        Ok(blocks)
     }
 
     //区块链迭代器
     pub fn iterator(&self) -> BlockchainIterator {
-        BlockchainIterator::new(self.get_tip_hash(), self.db.clone())
+        BlockchainIterator::new(self.get_tip_hash(), self.store.clone())
     }
 
     //打印区块链信息
@@ -263,26 +1084,726 @@ impl BlockChain {
 }
 
 pub struct BlockchainIterator {
-    db: Db,
+    store: Arc<dyn ChainStore>,
     current_hash: String,
 }
 
 impl BlockchainIterator {
-    fn new(tip_hash: String, db: Db) -> BlockchainIterator {
+    fn new(tip_hash: String, store: Arc<dyn ChainStore>) -> BlockchainIterator {
         BlockchainIterator {
             current_hash: tip_hash,
-            db,
+            store,
+        }
+    }
+
+    /// 返回当前区块并移动到上一区块，越过创世块后返回 None
+    pub fn next(&mut self) -> Result<Option<Block>> {
+        let block = match self.store.get_block(self.current_hash.as_str())? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        self.current_hash = block.get_pre_block_hash().clone();
+        Ok(Some(block))
+    }
+}
+
+/// 按高度遍历主链区块的迭代器，由 BlockChain::range 创建
+pub struct BlockRange {
+    store: Arc<dyn ChainStore>,
+    start: usize, // 下一个正向返回的高度
+    end: usize,   // 下一个反向返回的高度加一
+}
+
+impl BlockRange {
+    fn load(&self, height: usize) -> Result<Block> {
+        let block_hash = self
+            .store
+            .get_block_hash_by_height(height)?
+            .ok_or_else(|| Error::Corrupted(format!("no block at height {}", height)))?;
+        self.store
+            .get_block(block_hash.as_str())?
+            .ok_or_else(|| Error::Corrupted(format!("block {} not found", block_hash)))
+    }
+}
+
+impl Iterator for BlockRange {
+    type Item = Result<Block>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.start >= self.end {
+            return None;
         }
+        self.start += 1;
+        Some(self.load(self.start - 1))
     }
 
-    pub fn next(&mut self) -> Option<Block> {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        let data = block_tree.get(self.current_hash.clone()).unwrap();
-        if data.is_none() {
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.end - self.start;
+        (len, Some(len))
+    }
+}
+
+impl DoubleEndedIterator for BlockRange {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.start >= self.end {
             return None;
         }
-        let block: Block = coder::deserialized(data.unwrap().to_vec().as_slice());
-        self.current_hash = block.get_pre_block_hash().clone();
-        return Some(block);
+        self.end -= 1;
+        Some(self.load(self.end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_store::MemoryStore;
+    use crate::psbt::PartiallySignedTransaction;
+    use crate::transaction::{TXOutput, SEQUENCE_FINAL};
+    use crate::wallet::{address_to_script, Wallet};
+
+    // 在内存中创建区块链，创世块的奖励属于返回的钱包
+    fn new_chain() -> (BlockChain, Wallet) {
+        let wallet = Wallet::new().unwrap();
+        let store = Arc::new(MemoryStore::new());
+        let blockchain = BlockChain::create_with_store(store, &wallet.get_address()).unwrap();
+        (blockchain, wallet)
+    }
+
+    // 在 parent 之后构造区块，extra 写入 coinbase，不同分支同一高度的 coinbase 交易ID不同
+    fn new_block(
+        blockchain: &BlockChain,
+        parent: &Block,
+        extra: &[u8],
+        txs: &[Transaction],
+    ) -> Block {
+        let height = parent.get_height() + 1;
+        let address = Wallet::new().unwrap().get_address();
+        let coinbase_tx = Transaction::new_coinbase_tx(&address, height, Amount::ZERO, extra);
+        let mut transactions = vec![coinbase_tx.unwrap()];
+        transactions.extend_from_slice(txs);
+        let bits = blockchain.get_next_work_required(parent).unwrap();
+        Block::new_block(&transactions, parent.get_hash().to_string(), height, bits).unwrap()
+    }
+
+    // 钱包签名一笔花费 prev_tx 第 vout 个输出的交易，向 to 支付 value，差额作为手续费
+    fn spend(
+        wallet: &Wallet,
+        prev_tx: &Transaction,
+        vout: usize,
+        value: Amount,
+        to: &Wallet,
+    ) -> Transaction {
+        spend_locked(wallet, prev_tx, vout, value, to, 0, SEQUENCE_FINAL)
+    }
+
+    // 与 spend 相同，交易带有绝对时间锁 lock_time，输入带有 sequence
+    fn spend_locked(
+        wallet: &Wallet,
+        prev_tx: &Transaction,
+        vout: usize,
+        value: Amount,
+        to: &Wallet,
+        lock_time: u32,
+        sequence: u32,
+    ) -> Transaction {
+        let tx = Transaction::new_with_lock_time(
+            vec![TXInput::new_with_sequence(prev_tx.get_id(), vout, sequence)],
+            vec![TXOutput::new(value, &to.get_address()).unwrap()],
+            lock_time,
+        );
+        let prev_outputs = vec![prev_tx.get_vout()[vout].clone()];
+        let mut psbt = PartiallySignedTransaction::new(tx, prev_outputs).unwrap();
+        psbt.sign(wallet).unwrap();
+        psbt.finalize().unwrap()
+    }
+
+    // 花费不存在的输出的交易，只能在依赖 UTXO 集的检查中发现
+    fn missing_inputs_tx() -> Transaction {
+        let to = Wallet::new().unwrap().get_address();
+        Transaction::new(
+            vec![TXInput::new(&[7; 32], 0)],
+            vec![TXOutput::new(coins(1), &to).unwrap()],
+        )
+    }
+
+    fn coins(coins: u64) -> Amount {
+        Amount::from_coins(coins).unwrap()
+    }
+
+    fn hashes(blocks: &[Block]) -> Vec<&str> {
+        blocks.iter().map(|block| block.get_hash()).collect()
+    }
+
+    fn get_entry(blockchain: &BlockChain, tx: &Transaction, vout: usize) -> Option<UTXOEntry> {
+        UTXOSet::new(blockchain.clone())
+            .get_entry(tx.get_id(), vout)
+            .unwrap()
+    }
+
+    #[test]
+    fn reopen_chain_from_store() {
+        let wallet = Wallet::new().unwrap();
+        let store = Arc::new(MemoryStore::new());
+        let blockchain =
+            BlockChain::create_with_store(store.clone(), &wallet.get_address()).unwrap();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let a1 = new_block(&blockchain, &genesis, b"a", &[]);
+        blockchain.add_block(&a1).unwrap();
+
+        // 区块、索引、撤销数据和 UTXO 集都写入存储后端
+        assert!(store.get_block(a1.get_hash()).unwrap().is_some());
+        assert!(store.get_block_index(a1.get_hash()).unwrap().is_some());
+        assert!(store.get_undo(a1.get_hash()).unwrap().is_some());
+        assert_eq!(store.get_tip().unwrap().as_deref(), Some(a1.get_hash()));
+        assert_eq!(store.get_utxos().unwrap().len(), 2);
+
+        // 从同一个存储后端重新打开，链尾和 UTXO 集保持不变
+        let reopened = BlockChain::open_with_store(store).unwrap();
+        assert_eq!(reopened.get_tip_hash(), a1.get_hash());
+        assert!(get_entry(&reopened, &genesis.get_transactions()[0], 0).is_some());
+        assert!(get_entry(&reopened, &a1.get_transactions()[0], 0).is_some());
+    }
+
+    #[test]
+    fn reorganize_to_heavier_branch() {
+        let (blockchain, _) = new_chain();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let a1 = new_block(&blockchain, &genesis, b"a", &[]);
+        blockchain.add_block(&a1).unwrap();
+        let a2 = new_block(&blockchain, &a1, b"a", &[]);
+        blockchain.add_block(&a2).unwrap();
+
+        // 累计工作量不超过主链的分支只保存不切换
+        let b1 = new_block(&blockchain, &genesis, b"b", &[]);
+        assert!(blockchain.add_block(&b1).unwrap().is_side_chain());
+        let b2 = new_block(&blockchain, &b1, b"b", &[]);
+        assert!(blockchain.add_block(&b2).unwrap().is_side_chain());
+        assert_eq!(blockchain.get_tip_hash(), a2.get_hash());
+
+        let b3 = new_block(&blockchain, &b2, b"b", &[]);
+        let change = blockchain.add_block(&b3).unwrap();
+        assert_eq!(
+            hashes(change.get_disconnected()),
+            vec![a2.get_hash(), a1.get_hash()]
+        );
+        assert_eq!(
+            hashes(change.get_connected()),
+            vec![b1.get_hash(), b2.get_hash(), b3.get_hash()]
+        );
+        assert_eq!(blockchain.get_tip_hash(), b3.get_hash());
+        assert_eq!(blockchain.get_best_height().unwrap(), 3);
+        let block = blockchain.get_block_by_height(1).unwrap().unwrap();
+        assert_eq!(block.get_hash(), b1.get_hash());
+
+        // 旧主链的 coinbase 输出从 UTXO 集中移除，新分支的输出加入
+        assert!(get_entry(&blockchain, &a1.get_transactions()[0], 0).is_none());
+        assert!(get_entry(&blockchain, &b3.get_transactions()[0], 0).is_some());
+        assert!(blockchain.add_block(&a2).is_err());
+    }
+
+    #[test]
+    fn reorganize_rolls_back_invalid_branch() {
+        let (blockchain, wallet) = new_chain();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let genesis_coinbase = &genesis.get_transactions()[0];
+        let spend_a = spend(&wallet, genesis_coinbase, 0, coins(9), &wallet);
+        let a1 = new_block(&blockchain, &genesis, b"a", &[spend_a.clone()]);
+        blockchain.add_block(&a1).unwrap();
+        let a2 = new_block(&blockchain, &a1, b"a", &[]);
+        blockchain.add_block(&a2).unwrap();
+
+        // 分支的第一个区块有效，第二个区块花费了不存在的输出
+        let spend_b = spend(&wallet, genesis_coinbase, 0, coins(8), &wallet);
+        let b1 = new_block(&blockchain, &genesis, b"b", &[spend_b.clone()]);
+        blockchain.add_block(&b1).unwrap();
+        let b2 = new_block(&blockchain, &b1, b"b", &[missing_inputs_tx()]);
+        blockchain.add_block(&b2).unwrap();
+        let b3 = new_block(&blockchain, &b2, b"b", &[]);
+        let result = blockchain.add_block(&b3);
+        assert!(matches!(
+            result,
+            Err(Error::Block(BlockError::MissingInputs(_)))
+        ));
+
+        // 回到原来的主链，UTXO 集与切换前一致
+        assert_eq!(blockchain.get_tip_hash(), a2.get_hash());
+        assert!(blockchain.store.get_reorg_target().unwrap().is_none());
+        assert!(get_entry(&blockchain, genesis_coinbase, 0).is_none());
+        assert!(get_entry(&blockchain, &spend_a, 0).is_some());
+        assert!(get_entry(&blockchain, &spend_b, 0).is_none());
+        assert!(get_entry(&blockchain, &b1.get_transactions()[0], 0).is_none());
+        assert!(blockchain.is_failed(b2.get_hash()).unwrap());
+        assert!(!blockchain.is_failed(b1.get_hash()).unwrap());
+
+        // 包含无效区块的分支之后不会再被切换
+        let b4 = new_block(&blockchain, &b3, b"b", &[]);
+        let result = blockchain.add_block(&b4);
+        assert!(matches!(result, Err(Error::Block(BlockError::BadParent))));
+        assert_eq!(blockchain.get_tip_hash(), a2.get_hash());
+    }
+
+    #[test]
+    fn invalidate_block_disconnects_descendants() {
+        let (blockchain, _) = new_chain();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let a1 = new_block(&blockchain, &genesis, b"a", &[]);
+        blockchain.add_block(&a1).unwrap();
+        let a2 = new_block(&blockchain, &a1, b"a", &[]);
+        blockchain.add_block(&a2).unwrap();
+        // 无效区块的另一个后代，累计工作量超过 b 分支
+        let c2 = new_block(&blockchain, &a1, b"c", &[]);
+        assert!(blockchain.add_block(&c2).unwrap().is_side_chain());
+        let b1 = new_block(&blockchain, &genesis, b"b", &[]);
+        assert!(blockchain.add_block(&b1).unwrap().is_side_chain());
+
+        // 断开 a1 之后切换到不包含 a1 的最重分支
+        let change = blockchain.invalidate_block(a1.get_hash()).unwrap().unwrap();
+        assert_eq!(
+            hashes(change.get_disconnected()),
+            vec![a2.get_hash(), a1.get_hash()]
+        );
+        assert_eq!(hashes(change.get_connected()), vec![b1.get_hash()]);
+        assert_eq!(blockchain.get_tip_hash(), b1.get_hash());
+        assert!(blockchain.is_failed(c2.get_hash()).unwrap());
+        assert!(get_entry(&blockchain, &a1.get_transactions()[0], 0).is_none());
+        assert!(get_entry(&blockchain, &b1.get_transactions()[0], 0).is_some());
+
+        // 无效区块之后的区块被拒绝，其他分支可以继续延长
+        let a3 = new_block(&blockchain, &a2, b"a", &[]);
+        let result = blockchain.add_block(&a3);
+        assert!(matches!(result, Err(Error::Block(BlockError::BadParent))));
+        let b2 = new_block(&blockchain, &b1, b"b", &[]);
+        blockchain.add_block(&b2).unwrap();
+        assert_eq!(blockchain.get_tip_hash(), b2.get_hash());
+
+        // 没有其他分支时链尾退回无效区块的上一区块
+        let change = blockchain.invalidate_block(b2.get_hash()).unwrap().unwrap();
+        assert_eq!(hashes(change.get_disconnected()), vec![b2.get_hash()]);
+        assert!(change.get_connected().is_empty());
+        assert_eq!(blockchain.get_tip_hash(), b1.get_hash());
+
+        // 创世块和不存在的区块不能被标记为无效
+        assert!(blockchain
+            .invalidate_block(genesis.get_hash())
+            .unwrap()
+            .is_none());
+        assert!(blockchain
+            .invalidate_block(GENESIS_PRE_HASH)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn spend_output_created_earlier_in_block() {
+        let (blockchain, wallet) = new_chain();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let other = Wallet::new().unwrap();
+        let parent = spend(&wallet, &genesis.get_transactions()[0], 0, coins(9), &other);
+        let child = spend(&other, &parent, 0, coins(8), &wallet);
+
+        // 子交易排在父交易之前时找不到输入
+        let block = new_block(&blockchain, &genesis, b"", &[child.clone(), parent.clone()]);
+        let result = blockchain.add_block(&block);
+        assert!(matches!(
+            result,
+            Err(Error::Block(BlockError::MissingInputs(_)))
+        ));
+
+        let block = new_block(&blockchain, &genesis, b"", &[parent.clone(), child.clone()]);
+        blockchain.add_block(&block).unwrap();
+        assert_eq!(blockchain.get_tip_hash(), block.get_hash());
+        assert!(get_entry(&blockchain, &parent, 0).is_none());
+        assert!(get_entry(&blockchain, &child, 0).is_some());
+    }
+
+    #[test]
+    fn select_transactions_skips_conflicts() {
+        let (blockchain, wallet) = new_chain();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let genesis_coinbase = &genesis.get_transactions()[0];
+        let other = Wallet::new().unwrap();
+        // 两笔交易花费同一个输出，手续费高的被选中，另一笔及其子交易跳过
+        let low_fee = spend(&wallet, genesis_coinbase, 0, coins(9), &other);
+        let low_fee_child = spend(&other, &low_fee, 0, coins(8), &wallet);
+        let high_fee = spend(&wallet, genesis_coinbase, 0, coins(8), &other);
+        let high_fee_child = spend(&other, &high_fee, 0, coins(7), &wallet);
+        let invalid = missing_inputs_tx();
+
+        let candidates = vec![
+            high_fee_child.clone(),
+            low_fee.clone(),
+            invalid.clone(),
+            low_fee_child,
+            high_fee.clone(),
+        ];
+        let selection = blockchain.select_transactions(candidates).unwrap();
+        let selected: Vec<&[u8]> = selection
+            .get_transactions()
+            .iter()
+            .map(|tx| tx.get_id())
+            .collect();
+        assert_eq!(selected, vec![high_fee.get_id(), high_fee_child.get_id()]);
+        assert_eq!(selection.get_fees(), coins(3));
+        // 只有无效的交易需要从内存池中移除
+        assert_eq!(selection.get_rejected().len(), 1);
+        assert_eq!(selection.get_rejected()[0].get_id(), invalid.get_id());
+    }
+
+    fn range_hashes(blocks: impl Iterator<Item = Result<Block>>) -> Vec<String> {
+        blocks
+            .map(|block| block.unwrap().get_hash().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn lookup_blocks_by_height() {
+        let (blockchain, _) = new_chain();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let a1 = new_block(&blockchain, &genesis, b"a", &[]);
+        blockchain.add_block(&a1).unwrap();
+        let a2 = new_block(&blockchain, &a1, b"a", &[]);
+        blockchain.add_block(&a2).unwrap();
+
+        let block = blockchain.get_block_by_height(1).unwrap().unwrap();
+        assert_eq!(block.get_hash(), a1.get_hash());
+        assert!(blockchain.get_block_by_height(3).unwrap().is_none());
+
+        let all = vec![
+            genesis.get_hash().to_string(),
+            a1.get_hash().to_string(),
+            a2.get_hash().to_string(),
+        ];
+        assert_eq!(range_hashes(blockchain.range(..).unwrap()), all);
+        assert_eq!(range_hashes(blockchain.range(1..2).unwrap()), all[1..2]);
+        assert_eq!(range_hashes(blockchain.range(1..).unwrap()), all[1..]);
+        let rev: Vec<String> = all.iter().rev().cloned().collect();
+        assert_eq!(range_hashes(blockchain.range(..).unwrap().rev()), rev);
+        // 超出链尾的部分被忽略，边界为 usize::MAX 时不溢出
+        assert_eq!(range_hashes(blockchain.range(..=usize::MAX).unwrap()), all);
+        assert_eq!(
+            range_hashes(blockchain.range(1..=usize::MAX).unwrap()),
+            all[1..]
+        );
+        let after_max = (Bound::Excluded(usize::MAX), Bound::Unbounded);
+        assert!(range_hashes(blockchain.range(after_max).unwrap()).is_empty());
+        assert!(range_hashes(blockchain.range(5..9).unwrap()).is_empty());
+        assert_eq!(blockchain.range(..).unwrap().size_hint(), (3, Some(3)));
+    }
+
+    #[test]
+    fn height_index_follows_reorg() {
+        let (blockchain, _) = new_chain();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let a1 = new_block(&blockchain, &genesis, b"a", &[]);
+        blockchain.add_block(&a1).unwrap();
+        let b1 = new_block(&blockchain, &genesis, b"b", &[]);
+        blockchain.add_block(&b1).unwrap();
+        let b2 = new_block(&blockchain, &b1, b"b", &[]);
+        blockchain.add_block(&b2).unwrap();
+        assert_eq!(blockchain.get_tip_hash(), b2.get_hash());
+
+        // 切换主链后同一高度指向新分支的区块
+        let hashes_at = |heights: &[usize]| -> Vec<String> {
+            heights
+                .iter()
+                .map(|height| {
+                    let block = blockchain.get_block_by_height(*height).unwrap().unwrap();
+                    block.get_hash().to_string()
+                })
+                .collect()
+        };
+        assert_eq!(
+            hashes_at(&[0, 1, 2]),
+            vec![genesis.get_hash(), b1.get_hash(), b2.get_hash()]
+        );
+        let blocks: Vec<Block> = blockchain
+            .range(..)
+            .unwrap()
+            .rev()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            hashes(&blocks),
+            vec![b2.get_hash(), b1.get_hash(), genesis.get_hash()]
+        );
+
+        // 断开后链尾之上的高度不再有区块
+        blockchain.invalidate_block(b1.get_hash()).unwrap().unwrap();
+        assert_eq!(hashes_at(&[0, 1]), vec![genesis.get_hash(), a1.get_hash()]);
+        assert!(blockchain.get_block_by_height(2).unwrap().is_none());
+    }
+
+    #[test]
+    fn reject_block_with_wrong_difficulty() {
+        let (blockchain, _) = new_chain();
+        let mut parent = blockchain.get_tip_block().unwrap();
+        // 调整周期内沿用上一区块的难度
+        let bits = blockchain.get_next_work_required(&parent).unwrap();
+        assert_eq!(bits, parent.get_bits());
+
+        let address = Wallet::new().unwrap().get_address();
+        let coinbase_tx = Transaction::new_coinbase_tx(&address, 1, Amount::ZERO, &[]).unwrap();
+        let harder = pow::bits_from_target(&(pow::target_from_bits(bits) / 2));
+        let pre_hash = parent.get_hash().to_string();
+        let block = Block::new_block(&[coinbase_tx], pre_hash, 1, harder).unwrap();
+        let result = blockchain.add_block(&block);
+        assert!(matches!(
+            result,
+            Err(Error::Block(BlockError::BadDifficulty { expected, actual }))
+                if expected == bits && actual == harder
+        ));
+
+        // 调整周期结束时按实际出块时间调整，连续快速出块时按调整幅度的下限提高难度
+        for _ in 1..RETARGET_INTERVAL {
+            let block = new_block(&blockchain, &parent, b"a", &[]);
+            blockchain.add_block(&block).unwrap();
+            parent = block;
+        }
+        let next_bits = blockchain.get_next_work_required(&parent).unwrap();
+        assert_eq!(next_bits, pow::calculate_next_bits(bits, 0, 0));
+        assert!(pow::target_from_bits(next_bits) < pow::target_from_bits(bits));
+        let block = new_block(&blockchain, &parent, b"a", &[]);
+        assert_eq!(block.get_bits(), next_bits);
+        blockchain.add_block(&block).unwrap();
+    }
+
+    #[test]
+    fn reject_coinbase_with_wrong_height() {
+        let (blockchain, _) = new_chain();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let address = Wallet::new().unwrap().get_address();
+        let coinbase_tx = Transaction::new_coinbase_tx(&address, 2, Amount::ZERO, &[]).unwrap();
+        let bits = blockchain.get_next_work_required(&genesis).unwrap();
+        let pre_hash = genesis.get_hash().to_string();
+        let block = Block::new_block(&[coinbase_tx], pre_hash, 1, bits).unwrap();
+        let result = blockchain.add_block(&block);
+        assert!(matches!(
+            result,
+            Err(Error::Block(BlockError::BadCoinbaseHeight { expected: 1 }))
+        ));
+        assert_eq!(blockchain.get_tip_hash(), genesis.get_hash());
+    }
+
+    #[test]
+    fn reject_non_final_transaction() {
+        let (blockchain, wallet) = new_chain();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let genesis_coinbase = &genesis.get_transactions()[0];
+        // 高度 2 之后才能打包，即最早进入高度为 3 的区块
+        let tx = spend_locked(&wallet, genesis_coinbase, 0, coins(9), &wallet, 2, 0);
+
+        let mut parent = genesis;
+        for height in 1..=2 {
+            let result = blockchain.validate_transaction(&tx);
+            assert!(matches!(
+                result,
+                Err(Error::Block(BlockError::NonFinalTransaction(_)))
+            ));
+            let block = new_block(&blockchain, &parent, b"a", &[tx.clone()]);
+            let result = blockchain.add_block(&block);
+            assert!(matches!(
+                result,
+                Err(Error::Block(BlockError::NonFinalTransaction(_)))
+            ));
+            let block = new_block(&blockchain, &parent, b"a", &[]);
+            blockchain.add_block(&block).unwrap();
+            assert_eq!(block.get_height(), height);
+            parent = block;
+        }
+        assert!(blockchain.validate_transaction(&tx).is_ok());
+        let block = new_block(&blockchain, &parent, b"a", &[tx.clone()]);
+        blockchain.add_block(&block).unwrap();
+
+        // 所有输入的 sequence 都是 SEQUENCE_FINAL 时不检查 lock_time
+        let final_tx = spend_locked(&wallet, &tx, 0, coins(8), &wallet, u32::MAX, SEQUENCE_FINAL);
+        assert!(blockchain.validate_transaction(&final_tx).is_ok());
+    }
+
+    #[test]
+    fn reject_spend_before_relative_lock_matures() {
+        let (blockchain, wallet) = new_chain();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let genesis_coinbase = &genesis.get_transactions()[0];
+        // 被花费的输出上链之后经过 2 个区块才能花费，即最早进入高度为 2 的区块
+        let tx = spend_locked(&wallet, genesis_coinbase, 0, coins(9), &wallet, 0, 2);
+        let result = blockchain.validate_transaction(&tx);
+        assert!(matches!(
+            result,
+            Err(Error::Block(BlockError::SequenceLocked(_)))
+        ));
+        let block = new_block(&blockchain, &genesis, b"a", &[tx.clone()]);
+        let result = blockchain.add_block(&block);
+        assert!(matches!(
+            result,
+            Err(Error::Block(BlockError::SequenceLocked(_)))
+        ));
+
+        // 以时间为单位的相对时间锁需要中位时间前进 512 秒，同样不能花费
+        let sequence = SEQUENCE_LOCKTIME_TYPE_FLAG | 1;
+        let timed = spend_locked(&wallet, genesis_coinbase, 0, coins(9), &wallet, 0, sequence);
+
+        let a1 = new_block(&blockchain, &genesis, b"a", &[]);
+        blockchain.add_block(&a1).unwrap();
+        let result = blockchain.validate_transaction(&timed);
+        assert!(matches!(
+            result,
+            Err(Error::Block(BlockError::SequenceLocked(_)))
+        ));
+        assert!(blockchain.validate_transaction(&tx).is_ok());
+        let a2 = new_block(&blockchain, &a1, b"a", &[tx]);
+        blockchain.add_block(&a2).unwrap();
+    }
+
+    #[test]
+    fn transaction_index_follows_the_main_chain() {
+        let (blockchain, wallet) = new_chain();
+        blockchain.set_tx_index(true).unwrap();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let genesis_coinbase = &genesis.get_transactions()[0];
+        let tx = spend(&wallet, genesis_coinbase, 0, coins(9), &wallet);
+        let a1 = new_block(&blockchain, &genesis, b"a", &[tx.clone()]);
+        blockchain.add_block(&a1).unwrap();
+        let a2 = new_block(&blockchain, &a1, b"a", &[]);
+        blockchain.add_block(&a2).unwrap();
+
+        // 确认数为链尾高度加一减去交易所在区块的高度
+        let store = blockchain.get_store();
+        let location = store.get_tx_location(tx.get_id()).unwrap().unwrap();
+        assert_eq!(location.get_block_hash(), a1.get_hash());
+        assert_eq!(location.get_position(), 1);
+        let confirmed = blockchain.get_transaction(tx.get_id()).unwrap().unwrap();
+        assert_eq!(confirmed.get_transaction().get_id(), tx.get_id());
+        assert_eq!(confirmed.get_block_hash(), a1.get_hash());
+        assert_eq!(confirmed.get_height(), 1);
+        assert_eq!(confirmed.get_confirmations(), 2);
+        let confirmed = blockchain
+            .get_transaction(genesis_coinbase.get_id())
+            .unwrap()
+            .unwrap();
+        assert_eq!(confirmed.get_confirmations(), 3);
+
+        // 不维护索引时逐个区块查找，结果相同
+        blockchain.set_tx_index(false).unwrap();
+        assert!(store.get_tx_location(tx.get_id()).unwrap().is_none());
+        let confirmed = blockchain.get_transaction(tx.get_id()).unwrap().unwrap();
+        assert_eq!(confirmed.get_block_hash(), a1.get_hash());
+        assert_eq!(confirmed.get_confirmations(), 2);
+        blockchain.set_tx_index(true).unwrap();
+
+        // 区块断开后索引中的交易被移除
+        blockchain.invalidate_block(a1.get_hash()).unwrap().unwrap();
+        assert!(store.get_tx_location(tx.get_id()).unwrap().is_none());
+        assert!(store
+            .get_tx_location(a2.get_transactions()[0].get_id())
+            .unwrap()
+            .is_none());
+        assert!(blockchain.get_transaction(tx.get_id()).unwrap().is_none());
+        let confirmed = blockchain
+            .get_transaction(genesis_coinbase.get_id())
+            .unwrap()
+            .unwrap();
+        assert_eq!(confirmed.get_confirmations(), 1);
+    }
+
+    #[test]
+    fn address_index_follows_connect_disconnect_and_reorg() {
+        let (blockchain, alice) = new_chain();
+        let bob = Wallet::new().unwrap();
+        let carol = Wallet::new().unwrap();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let genesis_coinbase = &genesis.get_transactions()[0];
+        let tx1 = spend(&alice, genesis_coinbase, 0, coins(9), &bob);
+        let a1 = new_block(&blockchain, &genesis, b"a", &[tx1.clone()]);
+        blockchain.add_block(&a1).unwrap();
+        let tx2 = spend(&bob, &tx1, 0, coins(8), &carol);
+        let a2 = new_block(&blockchain, &a1, b"a", &[tx2.clone()]);
+        blockchain.add_block(&a2).unwrap();
+
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let script = |wallet: &Wallet| address_to_script(&wallet.get_address()).unwrap();
+        let unspent = |wallet: &Wallet| -> Vec<Vec<u8>> {
+            let entries = utxo_set.find_unspent(&script(wallet)).unwrap();
+            entries
+                .iter()
+                .map(|entry| entry.get_txid().to_vec())
+                .collect()
+        };
+        let history = |wallet: &Wallet| -> Vec<(usize, Vec<u8>)> {
+            let history = utxo_set.find_history(&script(wallet)).unwrap();
+            history
+                .iter()
+                .map(|tx| (tx.get_height(), tx.get_txid().to_vec()))
+                .collect()
+        };
+        // 地址索引中的未花费输出与扫描主链得到的结果一致
+        let check_unspent = || {
+            let utxos = blockchain.find_utxo().unwrap();
+            for wallet in [&alice, &bob, &carol] {
+                let script = script(wallet);
+                let mut scanned: Vec<(Vec<u8>, usize)> = utxos
+                    .iter()
+                    .filter(|entry| entry.get_output().is_locked_with(&script))
+                    .map(|entry| (entry.get_txid().to_vec(), entry.get_vout()))
+                    .collect();
+                scanned.sort();
+                let indexed: Vec<(Vec<u8>, usize)> = utxo_set
+                    .find_unspent(&script)
+                    .unwrap()
+                    .iter()
+                    .map(|entry| (entry.get_txid().to_vec(), entry.get_vout()))
+                    .collect();
+                assert_eq!(indexed, scanned);
+            }
+        };
+        let genesis_txid = genesis_coinbase.get_id_bytes();
+
+        check_unspent();
+        assert!(unspent(&alice).is_empty());
+        assert!(unspent(&bob).is_empty());
+        assert_eq!(unspent(&carol), vec![tx2.get_id_bytes()]);
+        assert_eq!(
+            history(&alice),
+            vec![(0, genesis_txid.clone()), (1, tx1.get_id_bytes())]
+        );
+        assert_eq!(
+            history(&bob),
+            vec![(1, tx1.get_id_bytes()), (2, tx2.get_id_bytes())]
+        );
+        assert_eq!(history(&carol), vec![(2, tx2.get_id_bytes())]);
+
+        // 断开 a2 之后 tx2 从索引中移除，bob 的输出恢复为未花费
+        let b2 = new_block(&blockchain, &a1, b"b", &[]);
+        blockchain.add_block(&b2).unwrap();
+        let b3 = new_block(&blockchain, &b2, b"b", &[]);
+        blockchain.add_block(&b3).unwrap();
+        assert_eq!(blockchain.get_tip_hash(), b3.get_hash());
+        check_unspent();
+        assert_eq!(unspent(&bob), vec![tx1.get_id_bytes()]);
+        assert!(unspent(&carol).is_empty());
+        assert_eq!(history(&bob), vec![(1, tx1.get_id_bytes())]);
+        assert!(history(&carol).is_empty());
+
+        // 切换到从创世块分叉的分支，alice 的输出在新分支上直接支付给 carol
+        let tx3 = spend(&alice, genesis_coinbase, 0, coins(7), &carol);
+        let mut parent = genesis.clone();
+        for i in 0..4 {
+            let txs = match i {
+                0 => vec![tx3.clone()],
+                _ => vec![],
+            };
+            let block = new_block(&blockchain, &parent, b"c", &txs);
+            blockchain.add_block(&block).unwrap();
+            parent = block;
+        }
+        assert_eq!(blockchain.get_tip_hash(), parent.get_hash());
+        check_unspent();
+        assert!(unspent(&bob).is_empty());
+        assert_eq!(unspent(&carol), vec![tx3.get_id_bytes()]);
+        assert_eq!(
+            history(&alice),
+            vec![(0, genesis_txid), (1, tx3.get_id_bytes())]
+        );
+        assert!(history(&bob).is_empty());
+        assert_eq!(history(&carol), vec![(1, tx3.get_id_bytes())]);
     }
 }
@@ -0,0 +1,337 @@
+use crate::error::Result;
+use crate::server::Package;
+use std::fmt;
+use std::io::{self, ErrorKind, Write};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use utils::coder;
+
+/// 网络标识，不同网络的节点收到对方的消息会直接拒绝
+pub const NETWORK_MAGIC: [u8; 4] = *b"LKBC";
+
+/// 帧格式版本，帧头格式变化时递增
+pub const WIRE_VERSION: u8 = 1;
+
+/// 消息体的最大长度，超过时不读取消息体
+/// 只有 block 和 inv 消息可以达到该长度，其他命令的上限见 COMMANDS
+pub const MAX_PAYLOAD_SIZE: usize = 32 * 1024 * 1024;
+
+/// 单笔交易消息体的最大长度
+const MAX_TX_PAYLOAD_SIZE: usize = 1024 * 1024;
+
+/// addr 消息体的最大长度，足够容纳 MAX_ADDR_PER_MESSAGE 个地址
+const MAX_ADDR_PAYLOAD_SIZE: usize = 128 * 1024;
+
+/// 只包含地址、哈希等少量字段的消息体的最大长度
+const MAX_SMALL_PAYLOAD_SIZE: usize = 1024;
+
+/// 读取消息体时预先分配的最大长度，之后随实际收到的数据增长
+/// 避免对方只发送帧头就让节点按声明的长度分配内存
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// 命令名的长度，不足时以 0 填充
+const COMMAND_SIZE: usize = 12;
+
+/// 校验和的长度，取消息体双重 sha256 哈希的前 4 个字节
+const CHECKSUM_SIZE: usize = 4;
+
+/// 帧头长度：网络标识 + 帧格式版本 + 命令名 + 消息体长度 + 校验和
+pub const HEADER_SIZE: usize = NETWORK_MAGIC.len() + 1 + COMMAND_SIZE + 4 + CHECKSUM_SIZE;
+
+/// 网络消息帧解码失败的原因
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodecError {
+    /// 网络标识不一致，参数为收到的标识
+    BadMagic([u8; 4]),
+    /// 不支持的帧格式版本
+    UnsupportedVersion(u8),
+    /// 命令名不是已知的命令
+    UnknownCommand(String),
+    /// 消息体长度超过命令允许的上限，参数为声明的长度和上限
+    Oversized(usize, usize),
+    /// 消息体的校验和不一致
+    BadChecksum,
+    /// 消息体不是命令名对应的消息
+    CommandMismatch(String),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::BadMagic(magic) => write!(f, "unknown network magic {:02x?}", magic),
+            CodecError::UnsupportedVersion(version) => {
+                write!(f, "unsupported wire version {}", version)
+            }
+            CodecError::UnknownCommand(command) => write!(f, "unknown command {:?}", command),
+            CodecError::Oversized(len, limit) => {
+                write!(f, "payload of {} bytes exceeds {}", len, limit)
+            }
+            CodecError::BadChecksum => write!(f, "payload checksum mismatch"),
+            CodecError::CommandMismatch(command) => {
+                write!(f, "payload does not match command {:?}", command)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+// 消息对应的命令名
+fn command(pkg: &Package) -> &'static str {
+    match pkg {
+        Package::Block { .. } => "block",
+        Package::GetBlocks { .. } => "getblocks",
+        Package::GetData { .. } => "getdata",
+        Package::Inv { .. } => "inv",
+        Package::Tx { .. } => "tx",
+        Package::Version { .. } => "version",
+        Package::Ping { .. } => "ping",
+        Package::Pong { .. } => "pong",
+        Package::GetAddr { .. } => "getaddr",
+        Package::Addr { .. } => "addr",
+    }
+}
+
+// 已知的命令名和各自消息体的最大长度
+// inv 在回复 getblocks 时携带主链上全部区块的哈希，与 block 使用同样的上限
+const COMMANDS: [(&str, usize); 10] = [
+    ("block", MAX_PAYLOAD_SIZE),
+    ("getblocks", MAX_SMALL_PAYLOAD_SIZE),
+    ("getdata", MAX_SMALL_PAYLOAD_SIZE),
+    ("inv", MAX_PAYLOAD_SIZE),
+    ("tx", MAX_TX_PAYLOAD_SIZE),
+    ("version", MAX_SMALL_PAYLOAD_SIZE),
+    ("ping", MAX_SMALL_PAYLOAD_SIZE),
+    ("pong", MAX_SMALL_PAYLOAD_SIZE),
+    ("getaddr", MAX_SMALL_PAYLOAD_SIZE),
+    ("addr", MAX_ADDR_PAYLOAD_SIZE),
+];
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let mut checksum = [0u8; CHECKSUM_SIZE];
+    checksum.copy_from_slice(&coder::double_sha256_digest(payload)[..CHECKSUM_SIZE]);
+    checksum
+}
+
+/// 将消息编码为一帧：帧头 + bincode 编码的消息体
+pub fn encode(pkg: &Package) -> Vec<u8> {
+    let payload = coder::serialized(pkg);
+    let mut command_bytes = [0u8; COMMAND_SIZE];
+    let name = command(pkg).as_bytes();
+    command_bytes[..name.len()].copy_from_slice(name);
+
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend(NETWORK_MAGIC);
+    frame.push(WIRE_VERSION);
+    frame.extend(command_bytes);
+    frame.extend((payload.len() as u32).to_le_bytes());
+    frame.extend(checksum(payload.as_slice()));
+    frame.extend(payload);
+    frame
+}
+
+/// 将消息编码后写入
+pub fn write_package<W: Write>(writer: &mut W, pkg: &Package) -> Result<()> {
+    writer.write_all(encode(pkg).as_slice())?;
+    Ok(())
+}
+
+/// 将消息编码后异步写入
+pub async fn write_package_async<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    pkg: &Package,
+) -> Result<()> {
+    writer.write_all(encode(pkg).as_slice()).await?;
+    Ok(())
+}
+
+/// 异步读取一帧并解码为消息，对方在帧边界关闭连接时返回 None
+/// 帧头校验通过后才读取消息体，校验和一致后才反序列化
+/// 消息体按实际收到的数据逐步分配内存，不会按声明的长度一次分配
+pub async fn read_package_async<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Package>> {
+    let mut header = [0u8; HEADER_SIZE];
+    // 第一个字节读不到说明连接正常关闭
+    if reader.read(&mut header[..1]).await? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut header[1..]).await?;
+    let (command, len, expected_checksum) = decode_header(&header)?;
+
+    let mut payload = Vec::with_capacity(len.min(READ_BUFFER_SIZE));
+    (&mut *reader)
+        .take(len as u64)
+        .read_to_end(&mut payload)
+        .await?;
+    if payload.len() < len {
+        return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    decode_payload(command, expected_checksum, payload.as_slice()).map(Some)
+}
+
+// 校验消息体并反序列化，消息必须与帧头中的命令名一致
+fn decode_payload(
+    command: &str,
+    expected_checksum: [u8; CHECKSUM_SIZE],
+    payload: &[u8],
+) -> Result<Package> {
+    if checksum(payload) != expected_checksum {
+        return Err(CodecError::BadChecksum.into());
+    }
+    let pkg: Package = coder::deserialized(payload)?;
+    if self::command(&pkg) != command {
+        return Err(CodecError::CommandMismatch(command.to_string()).into());
+    }
+    Ok(pkg)
+}
+
+// 校验帧头，返回命令名、消息体长度和校验和
+fn decode_header(
+    header: &[u8; HEADER_SIZE],
+) -> std::result::Result<(&'static str, usize, [u8; CHECKSUM_SIZE]), CodecError> {
+    let (magic, rest) = header.split_at(NETWORK_MAGIC.len());
+    if magic != NETWORK_MAGIC {
+        let mut bad_magic = [0u8; 4];
+        bad_magic.copy_from_slice(magic);
+        return Err(CodecError::BadMagic(bad_magic));
+    }
+    let (version, rest) = rest.split_at(1);
+    if version[0] != WIRE_VERSION {
+        return Err(CodecError::UnsupportedVersion(version[0]));
+    }
+    let (command_bytes, rest) = rest.split_at(COMMAND_SIZE);
+    let name_len = command_bytes
+        .iter()
+        .position(|b| *b == 0)
+        .unwrap_or(COMMAND_SIZE);
+    let name = String::from_utf8_lossy(&command_bytes[..name_len]);
+    let (command, limit) = COMMANDS
+        .iter()
+        .find(|(command, _)| *command == name)
+        .ok_or_else(|| CodecError::UnknownCommand(name.to_string()))?;
+    let (len_bytes, checksum_bytes) = rest.split_at(4);
+    let mut len = [0u8; 4];
+    len.copy_from_slice(len_bytes);
+    let len = u32::from_le_bytes(len) as usize;
+    if len > *limit {
+        return Err(CodecError::Oversized(len, *limit));
+    }
+    let mut checksum = [0u8; CHECKSUM_SIZE];
+    checksum.copy_from_slice(checksum_bytes);
+    Ok((command, len, checksum))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    fn ping() -> Package {
+        Package::Ping { nonce: 42 }
+    }
+
+    fn block() -> Package {
+        Package::Block {
+            addr_from: String::from("127.0.0.1:2001"),
+            block: vec![7; 1000],
+        }
+    }
+
+    async fn read(frame: &[u8]) -> Result<Option<Package>> {
+        let mut reader = frame;
+        read_package_async(&mut reader).await
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let mut frame = encode(&ping());
+        frame.extend(encode(&block()));
+        let mut reader = frame.as_slice();
+        let pkg = read_package_async(&mut reader).await.unwrap();
+        assert!(matches!(pkg, Some(Package::Ping { nonce: 42 })));
+        let pkg = read_package_async(&mut reader).await.unwrap();
+        assert!(matches!(pkg, Some(Package::Block { block, .. }) if block == vec![7; 1000]));
+        // 在帧边界关闭连接
+        assert!(read_package_async(&mut reader).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn reject_bad_checksum() {
+        let mut frame = encode(&block());
+        let last = frame.len() - 1;
+        frame[last] ^= 1;
+        let result = read(&frame).await;
+        assert!(matches!(result, Err(Error::Codec(CodecError::BadChecksum))));
+    }
+
+    // 只保留帧头，并修改声明的消息体长度
+    fn header_with_len(pkg: &Package, len: usize) -> Vec<u8> {
+        let mut frame = encode(pkg);
+        frame.truncate(HEADER_SIZE);
+        let len_offset = NETWORK_MAGIC.len() + 1 + COMMAND_SIZE;
+        frame[len_offset..len_offset + 4].copy_from_slice(&(len as u32).to_le_bytes());
+        frame
+    }
+
+    #[tokio::test]
+    async fn reject_oversized_payload_before_reading_it() {
+        // 只有帧头，声明的消息体不会被读取
+        let frame = header_with_len(&block(), MAX_PAYLOAD_SIZE + 1);
+        let result = read(&frame).await;
+        assert!(matches!(
+            result,
+            Err(Error::Codec(CodecError::Oversized(len, MAX_PAYLOAD_SIZE))) if len == MAX_PAYLOAD_SIZE + 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn reject_payload_over_command_limit() {
+        // 允许 block 使用的长度对 ping 来说过大
+        let frame = header_with_len(&ping(), MAX_SMALL_PAYLOAD_SIZE + 1);
+        let result = read(&frame).await;
+        assert!(matches!(
+            result,
+            Err(Error::Codec(CodecError::Oversized(
+                _,
+                MAX_SMALL_PAYLOAD_SIZE
+            )))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reject_bad_header() {
+        let mut frame = encode(&ping());
+        frame[0] = b'X';
+        let result = read(&frame).await;
+        assert!(matches!(result, Err(Error::Codec(CodecError::BadMagic(_)))));
+
+        let mut frame = encode(&ping());
+        frame[NETWORK_MAGIC.len()] = WIRE_VERSION + 1;
+        let result = read(&frame).await;
+        assert!(matches!(
+            result,
+            Err(Error::Codec(CodecError::UnsupportedVersion(_)))
+        ));
+
+        // 命令名与消息体不一致
+        let mut frame = encode(&ping());
+        let command_offset = NETWORK_MAGIC.len() + 1;
+        frame[command_offset..command_offset + 4].copy_from_slice(b"pong");
+        let result = read(&frame).await;
+        assert!(matches!(
+            result,
+            Err(Error::Codec(CodecError::CommandMismatch(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn truncated_frame_is_an_error() {
+        let frame = encode(&block());
+        let result = read(&frame[..frame.len() - 1]).await;
+        assert!(matches!(result, Err(Error::Io(_))));
+
+        // 声明的长度远大于实际发送的数据
+        let mut frame = header_with_len(&block(), MAX_PAYLOAD_SIZE);
+        frame.extend([0u8; 16]);
+        let result = read(&frame).await;
+        assert!(matches!(result, Err(Error::Io(_))));
+    }
+}
@@ -2,16 +2,24 @@ use log::warn;
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
 use std::env;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::RwLock;
 
 pub static GLOBAL_CONFIG: Lazy<Config> = Lazy::new(|| Config::new(None));
 
-/// 默认的中心节点ip地址
+/// 默认的节点ip地址，同时是本地测试网络默认的种子节点
 static DEFAULT_NODE_ADDR: &str = "127.0.0.1:2001";
 ///节点地址
 const NODE_ADDRESS_KEY: &str = "NODE_ADDRESS";
 ///矿工地址
 const MINING_ADDRESS_KEY: &str = "MINING_ADDRESS";
+///种子节点地址，多个地址以逗号分隔
+const SEED_NODES_KEY: &str = "SEED_NODES";
+///数据目录，保存数据库和地址簿
+const DATA_DIR_KEY: &str = "DATA_DIR";
+///向其他节点通告的外部地址
+const EXTERNAL_ADDRESS_KEY: &str = "EXTERNAL_ADDRESS";
 
 /// Node 配置
 pub struct Config {
@@ -37,6 +45,15 @@ impl Config {
         }
         let mut map = HashMap::new();
         map.insert(String::from(NODE_ADDRESS_KEY), node_addr);
+        // 从环境变量获取种子节点，未设置时使用默认的节点地址
+        if let Ok(seeds) = env::var(SEED_NODES_KEY) {
+            map.insert(String::from(SEED_NODES_KEY), seeds);
+        }
+        for key in [DATA_DIR_KEY, EXTERNAL_ADDRESS_KEY] {
+            if let Ok(val) = env::var(key) {
+                map.insert(String::from(key), val);
+            }
+        }
 
         Config {
             inner: RwLock::new(map),
@@ -76,6 +93,77 @@ impl Config {
         None
     }
 
+    /// 设置种子节点地址，多个地址以逗号分隔
+    pub fn set_seed_nodes(&self, seeds: String) {
+        let mut inner = self.inner.write().unwrap();
+        let _ = inner.insert(String::from(SEED_NODES_KEY), seeds);
+    }
+
+    /// 获取种子节点地址，地址簿中没有可用地址时从种子节点加入网络
+    pub fn get_seed_nodes(&self) -> Vec<String> {
+        let inner = self.inner.read().unwrap();
+        match inner.get(SEED_NODES_KEY) {
+            Some(seeds) => seeds
+                .split(',')
+                .map(|seed| seed.trim())
+                .filter(|seed| !seed.is_empty())
+                .map(String::from)
+                .collect(),
+            None => vec![String::from(DEFAULT_NODE_ADDR)],
+        }
+    }
+
+    /// 设置数据目录
+    pub fn set_data_dir(&self, data_dir: String) {
+        let mut inner = self.inner.write().unwrap();
+        let _ = inner.insert(String::from(DATA_DIR_KEY), data_dir);
+    }
+
+    /// 获取数据目录，未设置时为当前目录
+    pub fn get_data_dir(&self) -> PathBuf {
+        let inner = self.inner.read().unwrap();
+        match inner.get(DATA_DIR_KEY) {
+            Some(data_dir) => PathBuf::from(data_dir),
+            None => PathBuf::new(),
+        }
+    }
+
+    /// 设置向其他节点通告的外部地址
+    pub fn set_external_addr(&self, addr: String) {
+        let mut inner = self.inner.write().unwrap();
+        let _ = inner.insert(String::from(EXTERNAL_ADDRESS_KEY), addr);
+    }
+
+    /// 获取向其他节点通告的外部地址
+    pub fn get_external_addr(&self) -> Option<String> {
+        let inner = self.inner.read().unwrap();
+        inner.get(EXTERNAL_ADDRESS_KEY).cloned()
+    }
+
+    /// 向其他节点通告的监听地址，设置了外部地址时使用外部地址
+    pub fn get_advertised_addr(&self) -> String {
+        self.get_external_addr()
+            .unwrap_or_else(|| self.get_node_addr())
+    }
+
+    /// 节点实际监听的地址
+    /// 设置了外部地址时在所有网卡上监听同一端口，否则只监听节点地址
+    pub fn get_listen_addr(&self) -> String {
+        let node_addr = self.get_node_addr();
+        if self.get_external_addr().is_none() {
+            return node_addr;
+        }
+        match node_addr.parse::<SocketAddr>() {
+            Ok(addr) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), addr.port()).to_string(),
+            Err(_) => node_addr,
+        }
+    }
+
+    /// 地址是否指向本节点
+    pub fn is_local_addr(&self, addr: &str) -> bool {
+        addr == self.get_node_addr() || Some(addr) == self.get_external_addr().as_deref()
+    }
+
     /// 检查矿工节点
     pub fn is_miner(&self) -> bool {
         let inner = self.inner.read().unwrap();
@@ -0,0 +1,175 @@
+use crate::amount::{Amount, COIN};
+use dotenv::dotenv;
+use log::warn;
+use once_cell::sync::Lazy;
+use std::env;
+use std::str::FromStr;
+
+pub static GLOBAL_EMISSION: Lazy<EmissionSchedule> = Lazy::new(EmissionSchedule::from_env);
+
+/// 初始挖矿奖励，以币为单位
+const INITIAL_SUBSIDY_KEY: &str = "INITIAL_SUBSIDY";
+const DEFAULT_INITIAL_SUBSIDY: Amount = Amount::from_base_units(10 * COIN);
+/// 每隔多少个区块奖励减半
+const HALVING_INTERVAL_KEY: &str = "HALVING_INTERVAL";
+const DEFAULT_HALVING_INTERVAL: usize = 100;
+/// 最大供应量，以币为单位，默认值约等于按默认奖励和减半间隔发行的总量
+const MAX_SUPPLY_KEY: &str = "MAX_SUPPLY";
+const DEFAULT_MAX_SUPPLY: Amount = Amount::from_base_units(2000 * COIN);
+
+/// 发行计划，挖矿奖励只由区块高度决定
+/// 每隔 halving_interval 个区块奖励减半，累计发行量达到 max_supply 后不再发行
+pub struct EmissionSchedule {
+    initial_subsidy: Amount, // 创世块开始的挖矿奖励
+    halving_interval: usize, // 奖励减半的区块间隔
+    max_supply: Amount,      // 最大供应量
+}
+
+impl EmissionSchedule {
+    pub fn new(
+        initial_subsidy: Amount,
+        halving_interval: usize,
+        max_supply: Amount,
+    ) -> EmissionSchedule {
+        EmissionSchedule {
+            initial_subsidy,
+            halving_interval,
+            max_supply,
+        }
+    }
+
+    /// 从环境变量读取发行计划，未设置或格式错误时使用默认值
+    pub fn from_env() -> EmissionSchedule {
+        dotenv().ok();
+        let initial_subsidy = read_env(INITIAL_SUBSIDY_KEY, DEFAULT_INITIAL_SUBSIDY);
+        let mut halving_interval = read_env(HALVING_INTERVAL_KEY, DEFAULT_HALVING_INTERVAL);
+        if halving_interval == 0 {
+            warn!("HALVING_INTERVAL 不能为 0，将使用默认值");
+            halving_interval = DEFAULT_HALVING_INTERVAL;
+        }
+        let max_supply = read_env(MAX_SUPPLY_KEY, DEFAULT_MAX_SUPPLY);
+        EmissionSchedule::new(initial_subsidy, halving_interval, max_supply)
+    }
+
+    pub fn get_initial_subsidy(&self) -> Amount {
+        self.initial_subsidy
+    }
+
+    pub fn get_halving_interval(&self) -> usize {
+        self.halving_interval
+    }
+
+    pub fn get_max_supply(&self) -> Amount {
+        self.max_supply
+    }
+
+    /// 指定高度区块的挖矿奖励
+    pub fn subsidy_at(&self, height: usize) -> Amount {
+        let supply = self.supply_at(height);
+        let previous = match height {
+            0 => Amount::ZERO,
+            _ => self.supply_at(height - 1),
+        };
+        supply.checked_sub(previous).unwrap_or(Amount::ZERO)
+    }
+
+    /// 从创世块到指定高度（包括该高度）累计发行的货币总量，不超过最大供应量
+    pub fn supply_at(&self, height: usize) -> Amount {
+        let interval = self.halving_interval as u64;
+        // 高度为 usize::MAX 时加 1 会溢出，饱和到 u64::MAX，发行量随后被最大供应量截断
+        let mut remaining = (height as u64).saturating_add(1);
+        let mut subsidy = self.initial_subsidy;
+        let mut supply = Amount::ZERO;
+        // 按减半周期累加，奖励减为 0 后不再发行
+        while remaining > 0 && subsidy > Amount::ZERO {
+            let blocks = remaining.min(interval);
+            let issued = subsidy
+                .checked_mul(blocks)
+                .and_then(|issued| supply.checked_add(issued));
+            match issued {
+                Some(issued) if issued < self.max_supply => supply = issued,
+                _ => return self.max_supply,
+            }
+            remaining -= blocks;
+            subsidy = subsidy.checked_div(2).unwrap();
+        }
+        supply
+    }
+}
+
+// 读取环境变量并解析，失败时返回默认值
+fn read_env<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(val) => match val.parse() {
+            Ok(val) => val,
+            Err(_) => {
+                warn!("无法解析环境变量{}，将使用默认值", key);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(max_supply: u64) -> EmissionSchedule {
+        EmissionSchedule::new(
+            Amount::from_base_units(100),
+            10,
+            Amount::from_base_units(max_supply),
+        )
+    }
+
+    #[test]
+    fn supply_halves_every_interval() {
+        let schedule = schedule(u64::MAX / 2);
+        assert_eq!(schedule.supply_at(0), Amount::from_base_units(100));
+        assert_eq!(schedule.supply_at(9), Amount::from_base_units(1000));
+        assert_eq!(schedule.supply_at(10), Amount::from_base_units(1050));
+        assert_eq!(schedule.supply_at(19), Amount::from_base_units(1500));
+        assert_eq!(schedule.supply_at(29), Amount::from_base_units(1750));
+        assert_eq!(schedule.subsidy_at(0), Amount::from_base_units(100));
+        assert_eq!(schedule.subsidy_at(9), Amount::from_base_units(100));
+        assert_eq!(schedule.subsidy_at(10), Amount::from_base_units(50));
+        assert_eq!(schedule.subsidy_at(20), Amount::from_base_units(25));
+        assert_eq!(schedule.subsidy_at(30), Amount::from_base_units(12));
+    }
+
+    #[test]
+    fn subsidy_stops_after_halving_to_zero() {
+        let schedule = schedule(u64::MAX / 2);
+        // 100, 50, 25, 12, 6, 3, 1 之后奖励为 0
+        let total = (100 + 50 + 25 + 12 + 6 + 3 + 1) * 10;
+        assert_eq!(schedule.supply_at(69), Amount::from_base_units(total));
+        assert_eq!(schedule.subsidy_at(70), Amount::ZERO);
+        assert_eq!(schedule.supply_at(1000), Amount::from_base_units(total));
+    }
+
+    #[test]
+    fn supply_at_max_height_does_not_overflow() {
+        let schedule = schedule(u64::MAX / 2);
+        let total = (100 + 50 + 25 + 12 + 6 + 3 + 1) * 10;
+        assert_eq!(
+            schedule.supply_at(usize::MAX),
+            Amount::from_base_units(total)
+        );
+        assert_eq!(schedule.subsidy_at(usize::MAX), Amount::ZERO);
+        // 不减半时累计发行量溢出，返回最大供应量
+        let max_supply = Amount::from_base_units(u64::MAX);
+        let schedule = EmissionSchedule::new(Amount::from_base_units(100), usize::MAX, max_supply);
+        assert_eq!(schedule.supply_at(usize::MAX), max_supply);
+    }
+
+    #[test]
+    fn supply_is_capped_by_max_supply() {
+        let schedule = schedule(1020);
+        assert_eq!(schedule.supply_at(9), Amount::from_base_units(1000));
+        assert_eq!(schedule.supply_at(10), Amount::from_base_units(1020));
+        assert_eq!(schedule.subsidy_at(10), Amount::from_base_units(20));
+        assert_eq!(schedule.subsidy_at(11), Amount::ZERO);
+        assert_eq!(schedule.supply_at(100), Amount::from_base_units(1020));
+    }
+}
@@ -0,0 +1,140 @@
+use crate::amount::Amount;
+use crate::codec::CodecError;
+use crate::psbt::PsbtError;
+use crate::validation::BlockError;
+use sled::transaction::TransactionError;
+use std::fmt;
+use std::net::AddrParseError;
+use utils::coder::CoderError;
+
+/// core 中可能失败的操作返回的错误
+#[derive(Debug)]
+pub enum Error {
+    /// 数据库读写失败
+    Db(sled::Error),
+    /// 文件或网络读写失败
+    Io(std::io::Error),
+    /// 网络消息帧格式错误
+    Codec(CodecError),
+    /// 对等节点违反协议或连接不可用，参数为描述
+    Peer(String),
+    /// 数据编解码或密钥操作失败
+    Coder(CoderError),
+    /// 节点地址格式错误
+    AddrParse(AddrParseError),
+    /// 区块或交易校验失败
+    Block(BlockError),
+    /// 部分签名交易处理失败
+    Psbt(PsbtError),
+    /// 缺少环境变量，参数为变量名
+    MissingEnv(&'static str),
+    /// 数据库中还没有区块链
+    NoBlockchain,
+    /// 数据库中的数据缺失或不一致，参数为描述
+    Corrupted(String),
+    /// 地址格式错误，参数为地址
+    InvalidAddress(String),
+    /// 区块哈希不是 64 个小写十六进制字符，参数为哈希
+    InvalidBlockHash(String),
+    /// 本地钱包中没有该地址，参数为地址
+    WalletNotFound(String),
+    /// 可用余额不足以支付金额和手续费
+    InsufficientFunds { available: Amount, required: Amount },
+    /// 金额溢出或超过 MAX_MONEY
+    AmountOverflow,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Db(e) => write!(f, "database error: {}", e),
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Codec(e) => write!(f, "malformed message: {}", e),
+            Error::Peer(what) => write!(f, "peer error: {}", what),
+            Error::Coder(e) => write!(f, "{}", e),
+            Error::AddrParse(e) => write!(f, "invalid node address: {}", e),
+            Error::Block(e) => write!(f, "{}", e),
+            Error::Psbt(e) => write!(f, "{}", e),
+            Error::MissingEnv(key) => write!(f, "environment variable {} is not set", key),
+            Error::NoBlockchain => write!(f, "no existing blockchain found, create one first"),
+            Error::Corrupted(what) => write!(f, "database is corrupted: {}", what),
+            Error::InvalidAddress(address) => write!(f, "address {} is not valid", address),
+            Error::InvalidBlockHash(hash) => write!(f, "block hash {} is not valid", hash),
+            Error::WalletNotFound(address) => write!(f, "no wallet for address {}", address),
+            Error::InsufficientFunds {
+                available,
+                required,
+            } => write!(f, "not enough funds: have {}, need {}", available, required),
+            Error::AmountOverflow => write!(f, "amount is too large"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Db(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Codec(e) => Some(e),
+            Error::Coder(e) => Some(e),
+            Error::AddrParse(e) => Some(e),
+            Error::Block(e) => Some(e),
+            Error::Psbt(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(e: sled::Error) -> Self {
+        Error::Db(e)
+    }
+}
+
+/// 数据库事务中主动放弃时携带的错误原样返回
+impl From<TransactionError<Error>> for Error {
+    fn from(e: TransactionError<Error>) -> Self {
+        match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => Error::Db(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<CodecError> for Error {
+    fn from(e: CodecError) -> Self {
+        Error::Codec(e)
+    }
+}
+
+impl From<CoderError> for Error {
+    fn from(e: CoderError) -> Self {
+        Error::Coder(e)
+    }
+}
+
+impl From<AddrParseError> for Error {
+    fn from(e: AddrParseError) -> Self {
+        Error::AddrParse(e)
+    }
+}
+
+impl From<BlockError> for Error {
+    fn from(e: BlockError) -> Self {
+        Error::Block(e)
+    }
+}
+
+impl From<PsbtError> for Error {
+    fn from(e: PsbtError) -> Self {
+        Error::Psbt(e)
+    }
+}
@@ -1,40 +1,85 @@
+//错误
+mod error;
+pub use error::{Error, Result};
 //区块
 mod block;
-pub use block::Block;
+pub use block::{Block, BlockHeader};
 //区块链
 pub mod blockchain;
-pub use blockchain::BlockChain;
+pub use blockchain::{
+    BlockChain, BlockIndex, BlockRange, ChainChange, ConfirmedTransaction, TxSelection,
+};
+//存储后端
+mod memory_store;
+mod sled_store;
+mod store;
+pub use memory_store::MemoryStore;
+pub use sled_store::SledStore;
+pub use store::{AddressTx, ChainStateUpdate, ChainStore, TxLocation};
+
+//默克尔树
+mod merkle;
+pub use merkle::{MerkleProof, MerkleTree};
 
 //工作量证明
 mod pow;
 pub use pow::ProofOfWork;
+
+//区块校验
+mod validation;
+pub use validation::BlockError;
+//金额
+mod amount;
+pub use amount::{Amount, ParseAmountError, COIN, MAX_MONEY};
+//货币发行计划
+mod emission;
+pub use emission::{EmissionSchedule, GLOBAL_EMISSION};
+//脚本
+mod script;
+pub use script::{Opcode, Script, ScriptError, MAX_MULTISIG_KEYS};
 //交易
 mod transaction;
-pub use transaction::{TXInput, TXOutput, Transaction};
+pub use transaction::{
+    TXInput, TXOutput, Transaction, LOCKTIME_THRESHOLD, SEQUENCE_FINAL,
+    SEQUENCE_LOCKTIME_DISABLE_FLAG, SEQUENCE_LOCKTIME_GRANULARITY, SEQUENCE_LOCKTIME_MASK,
+    SEQUENCE_LOCKTIME_TYPE_FLAG,
+};
+//部分签名交易
+mod psbt;
+pub use psbt::{PartiallySignedTransaction, PsbtError, PsbtInput};
 
 //未花费交易输出（unspent transactions outputs, UTXO）
 mod utxo;
-pub use utxo::UTXOSet;
+pub use utxo::{UTXOEntry, UTXOSet};
 
 //钱包
 mod wallet;
+pub use wallet::address_to_script;
 pub use wallet::convert_address;
+pub use wallet::convert_script_address;
 pub use wallet::hash_pub_key;
+pub use wallet::script_to_address;
 pub use wallet::validate_address;
 pub use wallet::Wallet;
 pub use wallet::ADDRESS_CHECK_SUM_LEN;
 mod wallets;
 pub use wallets::Wallets;
+//网络消息编解码
+mod codec;
+pub use codec::{CodecError, HEADER_SIZE, MAX_PAYLOAD_SIZE, NETWORK_MAGIC, WIRE_VERSION};
 //服务器
 mod server;
 pub use server::send_tx;
+pub use server::submit_tx;
 pub use server::Package;
 pub use server::Server;
-pub use server::CENTER_NODE;
 
-//节点
-mod node;
-pub use node::{Node, Nodes};
+//对等节点连接
+mod peer;
+pub use peer::{PackageHandler, Peer, PeerManager, MAX_PEERS};
+//节点地址簿
+mod addr_book;
+pub use addr_book::{AddrBook, NetAddr, MAX_ADDR_PER_MESSAGE, PEERS_FILE};
 //交易内存池
 mod memory_pool;
 pub use memory_pool::{BlockInTransit, MemoryPool};
@@ -0,0 +1,224 @@
+use crate::block::Block;
+use crate::blockchain::BlockIndex;
+use crate::error::Result;
+use crate::store::{AddressTx, ChainStateUpdate, ChainStore, TxLocation};
+use crate::utxo::{script_key, UTXOEntry};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::RwLock;
+
+/// 内存中的存储后端，进程退出后数据丢失，用于测试和模拟
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    inner: RwLock<MemoryStoreInner>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryStoreInner {
+    blocks: HashMap<String, Block>,
+    block_index: HashMap<String, BlockIndex>,
+    tip: Option<String>,
+    heights: BTreeMap<usize, String>,               // 主链的高度索引
+    tx_index: Option<HashMap<Vec<u8>, TxLocation>>, // 交易索引，None 表示不维护
+    address_utxos: BTreeSet<(Vec<u8>, Vec<u8>, usize)>, // 地址索引的 (脚本哈希, txid, vout)
+    address_history: BTreeSet<(Vec<u8>, usize, Vec<u8>)>, // 地址索引的 (脚本哈希, 高度, txid)
+    address_index: bool,                            // 是否已经建立地址索引的交易历史部分
+    reorg_target: Option<String>,
+    utxos: BTreeMap<(Vec<u8>, usize), UTXOEntry>, // 按 (txid, vout) 排序，与 sled 后端的顺序一致
+    undo: HashMap<String, Vec<UTXOEntry>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl ChainStore for MemoryStore {
+    fn get_block(&self, block_hash: &str) -> Result<Option<Block>> {
+        Ok(self.inner.read().unwrap().blocks.get(block_hash).cloned())
+    }
+
+    fn put_block(&self, block: &Block) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        inner
+            .blocks
+            .insert(block.get_hash().to_string(), block.clone());
+        Ok(())
+    }
+
+    fn get_block_index(&self, block_hash: &str) -> Result<Option<BlockIndex>> {
+        Ok(self
+            .inner
+            .read()
+            .unwrap()
+            .block_index
+            .get(block_hash)
+            .cloned())
+    }
+
+    fn put_block_index(&self, block_hash: &str, index: &BlockIndex) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        inner
+            .block_index
+            .insert(block_hash.to_string(), index.clone());
+        Ok(())
+    }
+
+    fn get_block_indexes(&self) -> Result<Vec<(String, BlockIndex)>> {
+        let inner = self.inner.read().unwrap();
+        Ok(inner
+            .block_index
+            .iter()
+            .map(|(block_hash, index)| (block_hash.clone(), index.clone()))
+            .collect())
+    }
+
+    fn get_tip(&self) -> Result<Option<String>> {
+        Ok(self.inner.read().unwrap().tip.clone())
+    }
+
+    fn get_block_hash_by_height(&self, height: usize) -> Result<Option<String>> {
+        Ok(self.inner.read().unwrap().heights.get(&height).cloned())
+    }
+
+    fn reset_height_index(&self, block_hashes: &[String]) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        inner.heights = block_hashes.iter().cloned().enumerate().collect();
+        Ok(())
+    }
+
+    fn get_reorg_target(&self) -> Result<Option<String>> {
+        Ok(self.inner.read().unwrap().reorg_target.clone())
+    }
+
+    fn set_reorg_target(&self, block_hash: Option<&str>) -> Result<()> {
+        self.inner.write().unwrap().reorg_target = block_hash.map(String::from);
+        Ok(())
+    }
+
+    fn get_utxo(&self, txid: &[u8], vout: usize) -> Result<Option<UTXOEntry>> {
+        let inner = self.inner.read().unwrap();
+        Ok(inner.utxos.get(&(txid.to_vec(), vout)).cloned())
+    }
+
+    fn get_utxos(&self) -> Result<Vec<UTXOEntry>> {
+        Ok(self.inner.read().unwrap().utxos.values().cloned().collect())
+    }
+
+    fn get_undo(&self, block_hash: &str) -> Result<Option<Vec<UTXOEntry>>> {
+        Ok(self.inner.read().unwrap().undo.get(block_hash).cloned())
+    }
+
+    fn has_tx_index(&self) -> Result<bool> {
+        Ok(self.inner.read().unwrap().tx_index.is_some())
+    }
+
+    fn get_tx_location(&self, txid: &[u8]) -> Result<Option<TxLocation>> {
+        let inner = self.inner.read().unwrap();
+        Ok(inner
+            .tx_index
+            .as_ref()
+            .and_then(|tx_index| tx_index.get(txid).cloned()))
+    }
+
+    fn reset_tx_index(&self, locations: Option<&[(Vec<u8>, TxLocation)]>) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        inner.tx_index = locations.map(|locations| locations.iter().cloned().collect());
+        Ok(())
+    }
+
+    fn get_address_utxos(&self, script_key: &[u8]) -> Result<Vec<UTXOEntry>> {
+        let inner = self.inner.read().unwrap();
+        // 同一地址的输出在有序集合中相邻，从该地址的最小键开始取
+        Ok(inner
+            .address_utxos
+            .range((script_key.to_vec(), vec![], 0)..)
+            .take_while(|(key, _, _)| key.as_slice() == script_key)
+            .filter_map(|(_, txid, vout)| inner.utxos.get(&(txid.clone(), *vout)).cloned())
+            .collect())
+    }
+
+    fn get_address_history(&self, script_key: &[u8]) -> Result<Vec<AddressTx>> {
+        let inner = self.inner.read().unwrap();
+        Ok(inner
+            .address_history
+            .range((script_key.to_vec(), 0, vec![])..)
+            .take_while(|(key, _, _)| key.as_slice() == script_key)
+            .map(|(_, height, txid)| AddressTx::new(*height, txid))
+            .collect())
+    }
+
+    fn has_address_index(&self) -> Result<bool> {
+        Ok(self.inner.read().unwrap().address_index)
+    }
+
+    fn reset_address_history(&self, history: &[(Vec<u8>, AddressTx)]) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        inner.address_history = history
+            .iter()
+            .map(|(key, tx)| (key.clone(), tx.get_height(), tx.get_txid().to_vec()))
+            .collect();
+        inner.address_index = true;
+        Ok(())
+    }
+
+    // 持有写锁期间完成全部修改，读者看不到中间状态
+    fn write_chainstate(&self, update: &ChainStateUpdate) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        for outpoint in update.get_removed_utxos() {
+            if let Some(entry) = inner.utxos.remove(outpoint) {
+                inner.address_utxos.remove(&address_utxo_key(&entry));
+            }
+        }
+        for entry in update.get_added_utxos() {
+            let outpoint = (entry.get_txid().to_vec(), entry.get_vout());
+            inner.utxos.insert(outpoint, entry.clone());
+            inner.address_utxos.insert(address_utxo_key(entry));
+        }
+        for (script_key, txid) in update.get_history() {
+            let key = (script_key.clone(), update.get_height(), txid.clone());
+            match update.is_connect() {
+                true => inner.address_history.insert(key),
+                false => inner.address_history.remove(&key),
+            };
+        }
+        let block_hash = update.get_block_hash().to_string();
+        match update.get_undo() {
+            Some(spent) => {
+                inner.undo.insert(block_hash.clone(), spent.to_vec());
+                inner
+                    .heights
+                    .insert(update.get_height(), block_hash.clone());
+            }
+            None => {
+                inner.undo.remove(&block_hash);
+                inner.heights.remove(&update.get_height());
+            }
+        }
+        if let Some(tx_index) = inner.tx_index.as_mut() {
+            for (position, txid) in update.get_txids().iter().enumerate() {
+                match update.is_connect() {
+                    true => tx_index.insert(txid.clone(), TxLocation::new(&block_hash, position)),
+                    false => tx_index.remove(txid),
+                };
+            }
+        }
+        inner.tip = Some(update.get_tip_hash().to_string());
+        Ok(())
+    }
+
+    fn reset_utxos(&self, entries: &[UTXOEntry]) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        inner.utxos = entries
+            .iter()
+            .map(|entry| ((entry.get_txid().to_vec(), entry.get_vout()), entry.clone()))
+            .collect();
+        inner.address_utxos = entries.iter().map(address_utxo_key).collect();
+        Ok(())
+    }
+}
+
+fn address_utxo_key(entry: &UTXOEntry) -> (Vec<u8>, Vec<u8>, usize) {
+    let script_key = script_key(entry.get_output().get_script_pub_key());
+    (script_key, entry.get_txid().to_vec(), entry.get_vout())
+}
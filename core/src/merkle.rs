@@ -0,0 +1,168 @@
+use crate::block::BlockHeader;
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use utils::coder;
+
+/// 默克尔树节点的哈希长度
+const HASH_SIZE: usize = 32;
+
+/// 默克尔树，叶子节点是区块中按顺序排列的交易ID
+/// 父节点是左右子节点拼接后的双重 sha256，某一层节点数为奇数时复制最后一个节点
+pub struct MerkleTree {
+    levels: Vec<Vec<[u8; HASH_SIZE]>>, // 从叶子层到根节点逐层保存
+}
+
+impl MerkleTree {
+    /// 由交易ID构建默克尔树
+    pub fn new(txids: &[Vec<u8>]) -> MerkleTree {
+        let leaves: Vec<[u8; HASH_SIZE]> = txids.iter().map(|txid| leaf(txid)).collect();
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let level = levels.last().unwrap();
+            let mut parents = vec![];
+            for pair in level.chunks(2) {
+                let right = pair.get(1).unwrap_or(&pair[0]);
+                parents.push(hash_nodes(&pair[0], right));
+            }
+            levels.push(parents);
+        }
+        MerkleTree { levels }
+    }
+
+    /// 默克尔根，没有交易时为全 0
+    pub fn get_root(&self) -> [u8; HASH_SIZE] {
+        match self.levels.last().and_then(|level| level.first()) {
+            Some(root) => *root,
+            None => [0; HASH_SIZE],
+        }
+    }
+
+    /// 生成交易的默克尔证明，交易不在树中时返回 None
+    pub fn get_proof(&self, txid: &[u8]) -> Option<MerkleProof> {
+        let index = self.levels[0].iter().position(|id| *id == leaf(txid))?;
+        let mut branch = vec![];
+        let mut position = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = position ^ 1;
+            // 没有右兄弟时，与自身配对
+            branch.push(*level.get(sibling).unwrap_or(&level[position]));
+            position /= 2;
+        }
+        Some(MerkleProof {
+            txid: txid.to_vec(),
+            index,
+            branch,
+        })
+    }
+}
+
+/// 默克尔证明，只需要区块头就能验证交易包含在区块中
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MerkleProof {
+    txid: Vec<u8>,                // 交易ID
+    index: usize,                 // 交易在区块中的位置
+    branch: Vec<[u8; HASH_SIZE]>, // 从叶子到根路径上的兄弟节点
+}
+
+impl MerkleProof {
+    pub fn get_txid(&self) -> &[u8] {
+        self.txid.as_slice()
+    }
+
+    pub fn get_index(&self) -> usize {
+        self.index
+    }
+
+    /// 沿证明路径计算默克尔根
+    pub fn compute_root(&self) -> [u8; HASH_SIZE] {
+        let mut hash = leaf(&self.txid);
+        let mut position = self.index;
+        for sibling in &self.branch {
+            hash = if position.is_multiple_of(2) {
+                hash_nodes(&hash, sibling)
+            } else {
+                hash_nodes(sibling, &hash)
+            };
+            position /= 2;
+        }
+        hash
+    }
+
+    /// 验证证明计算出的默克尔根与区块头中的一致
+    pub fn verify(&self, header: &BlockHeader) -> bool {
+        HEXLOWER.encode(&self.compute_root()) == header.get_merkle_root()
+    }
+}
+
+// 叶子节点，交易ID就是 32 字节的哈希
+// 长度不对的交易ID只会出现在无效交易中，取其双重 sha256 作为叶子，这样的区块会在交易检查中被拒绝
+fn leaf(txid: &[u8]) -> [u8; HASH_SIZE] {
+    match txid.try_into() {
+        Ok(hash) => hash,
+        Err(_) => digest(txid),
+    }
+}
+
+// 计算父节点哈希
+fn hash_nodes(left: &[u8; HASH_SIZE], right: &[u8; HASH_SIZE]) -> [u8; HASH_SIZE] {
+    digest(&[left.as_slice(), right.as_slice()].concat())
+}
+
+fn digest(data: &[u8]) -> [u8; HASH_SIZE] {
+    let mut hash = [0u8; HASH_SIZE];
+    hash.copy_from_slice(&coder::double_sha256_digest(data));
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txids(count: u8) -> Vec<Vec<u8>> {
+        (0..count).map(|i| coder::sha256_digest(&[i])).collect()
+    }
+
+    #[test]
+    fn proofs_verify_on_odd_sized_trees() {
+        for count in [1, 2, 3, 5, 6, 7] {
+            let txids = txids(count);
+            let tree = MerkleTree::new(txids.as_slice());
+            for (index, txid) in txids.iter().enumerate() {
+                let proof = tree.get_proof(txid).unwrap();
+                assert_eq!(proof.get_index(), index);
+                assert_eq!(
+                    proof.compute_root(),
+                    tree.get_root(),
+                    "{} of {}",
+                    index,
+                    count
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn odd_level_duplicates_last_node() {
+        let txids = txids(3);
+        let tree = MerkleTree::new(txids.as_slice());
+        let left = hash_nodes(&leaf(&txids[0]), &leaf(&txids[1]));
+        let right = hash_nodes(&leaf(&txids[2]), &leaf(&txids[2]));
+        assert_eq!(tree.get_root(), hash_nodes(&left, &right));
+        assert_eq!(MerkleTree::new(&txids[..1]).get_root(), txids[0].as_slice());
+        assert_eq!(MerkleTree::new(&[]).get_root(), [0; HASH_SIZE]);
+    }
+
+    #[test]
+    fn tampered_proof_fails() {
+        let txids = txids(5);
+        let tree = MerkleTree::new(txids.as_slice());
+        assert!(tree.get_proof(&coder::sha256_digest(b"missing")).is_none());
+
+        let mut proof = tree.get_proof(&txids[4]).unwrap();
+        proof.index = 3;
+        assert_ne!(proof.compute_root(), tree.get_root());
+        let mut proof = tree.get_proof(&txids[4]).unwrap();
+        proof.branch[0] = leaf(&txids[0]);
+        assert_ne!(proof.compute_root(), tree.get_root());
+    }
+}
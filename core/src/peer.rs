@@ -0,0 +1,462 @@
+use crate::codec;
+use crate::error::{Error, Result};
+use crate::server::Package;
+use chrono::Utc;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+
+/// 连接超时
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 网络写超时
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 发送队列为空超过该时间时发送 ping，保持连接活跃
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// 超过该时间没有收到任何消息时断开连接
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// 每个连接的发送队列长度，队列满说明对方处理不过来，断开连接
+const OUTBOUND_QUEUE_SIZE: usize = 1024;
+
+/// 同时保持的最大连接数，达到上限后拒绝新的连接
+pub const MAX_PEERS: usize = 256;
+
+/// 处理收到的消息，返回错误时断开连接
+/// 处理函数可能访问数据库，在阻塞线程池中执行
+pub type PackageHandler = Arc<dyn Fn(&Arc<Peer>, Package) -> Result<()> + Send + Sync>;
+
+/// 与对等节点之间的长连接
+/// 每个连接由一个异步任务同时负责读写，收到的消息交给处理函数
+pub struct Peer {
+    id: u64,
+    addr: SocketAddr,                    // 连接的对端地址
+    inbound: bool,                       // 是否为对方发起的连接
+    listen_addr: RwLock<Option<String>>, // 对方在 version 消息中声明的监听地址
+    version_sent: AtomicBool,            // 已经发送 version 消息
+    established: AtomicBool,             // 已经收到对方的 version 消息，握手完成
+    sender: Sender<Package>,             // 发送队列
+    closed: watch::Sender<bool>,         // 通知连接任务断开连接
+}
+
+impl Peer {
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn is_inbound(&self) -> bool {
+        self.inbound
+    }
+
+    /// 对方声明的监听地址，握手完成前为 None
+    pub fn get_listen_addr(&self) -> Option<String> {
+        self.listen_addr.read().unwrap().clone()
+    }
+
+    /// 是否已经完成握手
+    pub fn is_established(&self) -> bool {
+        self.established.load(Ordering::SeqCst)
+    }
+
+    /// 收到对方的 version 消息，记录监听地址并完成握手
+    pub fn set_established(&self, listen_addr: &str) {
+        *self.listen_addr.write().unwrap() = Some(listen_addr.to_string());
+        self.established.store(true, Ordering::SeqCst);
+    }
+
+    /// 标记 version 消息已发送，返回之前是否已经发送过
+    pub fn mark_version_sent(&self) -> bool {
+        self.version_sent.swap(true, Ordering::SeqCst)
+    }
+
+    /// 将消息放入发送队列，队列满时断开连接
+    pub fn send(&self, pkg: Package) -> Result<()> {
+        match self.sender.try_send(pkg) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.disconnect();
+                Err(Error::Peer(format!(
+                    "outbound queue to {} is full",
+                    self.addr
+                )))
+            }
+            Err(TrySendError::Closed(_)) => Err(Error::Peer(format!(
+                "connection to {} is closed",
+                self.addr
+            ))),
+        }
+    }
+
+    /// 断开连接，连接任务随后退出
+    pub fn disconnect(&self) {
+        let _ = self.closed.send(true);
+    }
+}
+
+/// 管理与对等节点之间的全部长连接，连接断开后自动移除
+/// 连接数量由信号量限制，每个连接任务持有一个许可，退出时归还
+#[derive(Clone)]
+pub struct PeerManager {
+    peers: Arc<RwLock<HashMap<u64, Arc<Peer>>>>,
+    next_id: Arc<AtomicU64>,
+    permits: Arc<Semaphore>,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+impl Default for PeerManager {
+    fn default() -> Self {
+        PeerManager {
+            peers: Arc::default(),
+            next_id: Arc::default(),
+            permits: Arc::new(Semaphore::new(MAX_PEERS)),
+            shutdown: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl PeerManager {
+    pub fn new() -> PeerManager {
+        PeerManager::default()
+    }
+
+    /// 连接到指定地址的节点
+    pub async fn connect(&self, addr: &str, handler: PackageHandler) -> Result<Arc<Peer>> {
+        let permit = self.acquire()?;
+        let stream = time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+            .await
+            .map_err(|_| Error::Peer(format!("connect to {} timed out", addr)))??;
+        info!("Connected to peer {}", stream.peer_addr()?);
+        self.start(stream, false, handler, permit)
+    }
+
+    /// 接受对方发起的连接，连接数达到上限时直接关闭
+    pub fn accept(&self, stream: TcpStream, handler: PackageHandler) -> Result<Arc<Peer>> {
+        let permit = self.acquire()?;
+        info!("Accepted peer {}", stream.peer_addr()?);
+        self.start(stream, true, handler, permit)
+    }
+
+    fn acquire(&self) -> Result<OwnedSemaphorePermit> {
+        if self.is_shutdown() {
+            return Err(Error::Peer(String::from("node is shutting down")));
+        }
+        self.permits
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| Error::Peer(format!("too many peers, limit is {}", MAX_PEERS)))
+    }
+
+    // 登记连接并启动连接任务
+    fn start(
+        &self,
+        stream: TcpStream,
+        inbound: bool,
+        handler: PackageHandler,
+        permit: OwnedSemaphorePermit,
+    ) -> Result<Arc<Peer>> {
+        stream.set_nodelay(true)?;
+        let (sender, receiver) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+        let (closed, mut closed_rx) = watch::channel(false);
+        let peer = Arc::new(Peer {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            addr: stream.peer_addr()?,
+            inbound,
+            listen_addr: RwLock::new(None),
+            version_sent: AtomicBool::new(false),
+            established: AtomicBool::new(false),
+            sender,
+            closed,
+        });
+        self.peers.write().unwrap().insert(peer.id, peer.clone());
+
+        let manager = self.clone();
+        let mut shutdown = self.shutdown.subscribe();
+        let task_peer = peer.clone();
+        tokio::spawn(async move {
+            // 许可在连接任务结束时释放
+            let _permit = permit;
+            let peer = task_peer;
+            let (read_half, write_half) = stream.into_split();
+            // 任意一方结束时另一方被取消，连接随读写两端一起关闭
+            let result = tokio::select! {
+                result = read_loop(&peer, read_half, handler) => result,
+                result = write_loop(receiver, write_half) => result,
+                _ = closed_rx.changed() => Ok(()),
+                _ = shutdown.changed() => Ok(()),
+            };
+            match result {
+                Ok(()) => info!("Peer {} disconnected", peer.addr),
+                Err(Error::Io(e)) if e.kind() == ErrorKind::TimedOut => {
+                    info!("Peer {} is idle, disconnect", peer.addr)
+                }
+                Err(e) => warn!("Disconnect peer {}: {}", peer.addr, e),
+            }
+            manager.remove(peer.id);
+        });
+        Ok(peer)
+    }
+
+    fn remove(&self, id: u64) {
+        self.peers.write().unwrap().remove(&id);
+    }
+
+    /// 全部连接
+    pub fn get_peers(&self) -> Vec<Arc<Peer>> {
+        self.peers.read().unwrap().values().cloned().collect()
+    }
+
+    /// 已经完成握手的连接
+    pub fn get_established(&self) -> Vec<Arc<Peer>> {
+        self.get_peers()
+            .into_iter()
+            .filter(|peer| peer.is_established())
+            .collect()
+    }
+
+    /// 是否已经与 addr 建立连接，匹配对方声明的监听地址或主动连接的地址
+    pub fn is_connected(&self, addr: &str) -> bool {
+        self.get_peers().iter().any(|peer| {
+            peer.get_listen_addr().as_deref() == Some(addr)
+                || (!peer.inbound && peer.addr.to_string() == addr)
+        })
+    }
+
+    /// 查找监听地址为 addr 的连接
+    pub fn find(&self, addr: &str) -> Option<Arc<Peer>> {
+        self.get_peers()
+            .into_iter()
+            .find(|peer| peer.get_listen_addr().as_deref() == Some(addr))
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 向除 except 之外所有完成握手的节点发送消息
+    pub fn broadcast(&self, pkg: &Package, except: Option<u64>) {
+        for peer in self.get_established() {
+            if Some(peer.id) == except {
+                continue;
+            }
+            if let Err(e) = peer.send(pkg.clone()) {
+                warn!("Failed to send to peer {}: {}", peer.addr, e);
+            }
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// 拒绝新的连接并通知全部连接任务退出
+    pub fn shutdown(&self) {
+        let _ = self.shutdown.send(true);
+    }
+
+    /// 等待全部连接任务退出，超时返回 false
+    /// 正在执行的处理函数无法取消，需要等待其完成
+    pub async fn wait_closed(&self, timeout: Duration) -> bool {
+        time::timeout(timeout, self.permits.acquire_many(MAX_PEERS as u32))
+            .await
+            .is_ok()
+    }
+}
+
+// 接收消息，握手完成前只接受 version 消息，ping 由连接自己应答
+async fn read_loop(peer: &Arc<Peer>, stream: OwnedReadHalf, handler: PackageHandler) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let pkg = match time::timeout(IDLE_TIMEOUT, codec::read_package_async(&mut reader)).await {
+            Ok(pkg) => pkg?,
+            Err(_) => return Err(Error::Io(ErrorKind::TimedOut.into())),
+        };
+        let pkg = match pkg {
+            Some(pkg) => pkg,
+            None => return Ok(()),
+        };
+        match pkg {
+            Package::Ping { nonce } => peer.send(Package::Pong { nonce })?,
+            Package::Pong { .. } => {}
+            Package::Version { .. } => handle(peer, pkg, &handler).await?,
+            _ if !peer.is_established() => {
+                return Err(Error::Peer(String::from(
+                    "message before version handshake",
+                )));
+            }
+            pkg => handle(peer, pkg, &handler).await?,
+        }
+    }
+}
+
+// 在阻塞线程池中执行处理函数，等待完成后再读取下一条消息，保证消息按顺序处理
+async fn handle(peer: &Arc<Peer>, pkg: Package, handler: &PackageHandler) -> Result<()> {
+    let peer = peer.clone();
+    let handler = handler.clone();
+    tokio::task::spawn_blocking(move || handler(&peer, pkg))
+        .await
+        .map_err(|e| Error::Peer(format!("package handler failed: {}", e)))?
+}
+
+// 从发送队列取出消息写入连接，队列空闲时发送 ping
+async fn write_loop(mut receiver: Receiver<Package>, mut stream: OwnedWriteHalf) -> Result<()> {
+    loop {
+        let pkg = match time::timeout(PING_INTERVAL, receiver.recv()).await {
+            Ok(Some(pkg)) => pkg,
+            Ok(None) => return Ok(()),
+            Err(_) => Package::Ping {
+                nonce: Utc::now().timestamp_millis() as u64,
+            },
+        };
+        time::timeout(WRITE_TIMEOUT, async {
+            codec::write_package_async(&mut stream, &pkg).await?;
+            stream.flush().await?;
+            Ok::<(), Error>(())
+        })
+        .await
+        .map_err(|_| Error::Io(ErrorKind::TimedOut.into()))??;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    const TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+    // 收到 version 时完成握手，其他消息转发给除来源之外的节点
+    fn relay_handler(manager: &PeerManager) -> PackageHandler {
+        let manager = manager.clone();
+        Arc::new(move |peer, pkg| {
+            match pkg {
+                Package::Version { addr_from, .. } => peer.set_established(&addr_from),
+                pkg => manager.broadcast(&pkg, Some(peer.get_id())),
+            }
+            Ok(())
+        })
+    }
+
+    // 建立一对回环连接，一端交给 manager，另一端返回给测试直接读写
+    async fn connect_pair(
+        listener: &TcpListener,
+        manager: &PeerManager,
+    ) -> (Arc<Peer>, BufReader<TcpStream>) {
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let peer = manager.accept(stream, relay_handler(manager)).unwrap();
+        (peer, BufReader::new(client))
+    }
+
+    async fn send(client: &mut BufReader<TcpStream>, pkg: &Package) {
+        codec::write_package_async(client.get_mut(), pkg)
+            .await
+            .unwrap();
+    }
+
+    async fn recv(client: &mut BufReader<TcpStream>) -> Result<Option<Package>> {
+        time::timeout(TEST_TIMEOUT, codec::read_package_async(client))
+            .await
+            .expect("no package received")
+    }
+
+    async fn wait_until<F: Fn() -> bool>(condition: F) {
+        time::timeout(TEST_TIMEOUT, async {
+            while !condition() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("condition not met");
+    }
+
+    fn version(addr_from: &str) -> Package {
+        Package::Version {
+            addr_from: addr_from.to_string(),
+            version: 1,
+            best_height: 0,
+        }
+    }
+
+    fn tx() -> Package {
+        Package::Tx {
+            addr_from: String::from("127.0.0.1:3002"),
+            transaction: vec![1, 2, 3],
+        }
+    }
+
+    #[tokio::test]
+    async fn peer_manager_over_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let manager = PeerManager::new();
+        let (a, mut client_a) = connect_pair(&listener, &manager).await;
+        let (b, mut client_b) = connect_pair(&listener, &manager).await;
+        let (c, mut client_c) = connect_pair(&listener, &manager).await;
+        assert!(a.is_inbound());
+        assert_eq!(manager.len(), 3);
+        assert!(manager.get_established().is_empty());
+
+        // 握手完成前发送其他消息的连接被断开并移除
+        send(&mut client_a, &tx()).await;
+        assert!(!matches!(recv(&mut client_a).await, Ok(Some(_))));
+        wait_until(|| manager.len() == 2).await;
+        assert!(manager
+            .get_peers()
+            .iter()
+            .all(|peer| peer.get_id() != a.get_id()));
+
+        // 握手完成后按声明的监听地址查找
+        send(&mut client_b, &version("127.0.0.1:3002")).await;
+        send(&mut client_c, &version("127.0.0.1:3003")).await;
+        wait_until(|| manager.get_established().len() == 2).await;
+        assert!(manager.is_connected("127.0.0.1:3002"));
+        assert_eq!(
+            manager.find("127.0.0.1:3003").map(|peer| peer.get_id()),
+            Some(c.get_id())
+        );
+
+        // b 发来的消息转发给 c，不回送给 b
+        send(&mut client_b, &tx()).await;
+        let pkg = recv(&mut client_c).await.unwrap();
+        assert!(
+            matches!(pkg, Some(Package::Tx { transaction, .. }) if transaction == vec![1, 2, 3])
+        );
+        manager.broadcast(&Package::Ping { nonce: 7 }, None);
+        let pkg = recv(&mut client_b).await.unwrap();
+        assert!(matches!(pkg, Some(Package::Ping { nonce: 7 })));
+        let pkg = recv(&mut client_c).await.unwrap();
+        assert!(matches!(pkg, Some(Package::Ping { nonce: 7 })));
+
+        // 对方关闭连接后移除
+        drop(client_c);
+        wait_until(|| manager.len() == 1).await;
+        assert!(manager.find("127.0.0.1:3003").is_none());
+        assert_eq!(manager.get_peers()[0].get_id(), b.get_id());
+
+        manager.shutdown();
+        assert!(manager.wait_closed(TEST_TIMEOUT).await);
+        assert!(manager.is_empty());
+        assert!(matches!(recv(&mut client_b).await, Ok(None)));
+    }
+}
@@ -1,44 +1,43 @@
-use crate::block::Block;
+use crate::block::BlockHeader;
 use data_encoding::HEXLOWER;
 use num_bigint::{BigInt, Sign};
 use std::borrow::Borrow;
 use std::ops::ShlAssign;
 use utils::coder;
 
-// 难度值，这里表示哈希的前20位必须是0
+// 最低难度，这里表示哈希的前20位必须是0，难度调整后的目标值不能超过这个上限
+#[cfg(not(test))]
 const TARGET_BITS: i32 = 20;
+// 测试中降低最低难度，挖一个区块只需要几百次哈希
+#[cfg(test)]
+const TARGET_BITS: i32 = 8;
+
+/// 难度调整间隔，每隔多少个区块重新计算一次目标值
+pub const RETARGET_INTERVAL: usize = 10;
+
+/// 期望的出块间隔（秒）
+pub const TARGET_SPACING: i64 = 10;
 
 // nonce 最大值,限制 nonce 避免整型溢出
 const MAX_NONCE: i64 = i64::MAX;
 
 pub struct ProofOfWork {
-    block: Block,
+    header: BlockHeader,
     target: BigInt,
 }
 
 //工作量证明
 impl ProofOfWork {
-    //新建工作量证明
-    pub fn new_proof_of_work(block: Block) -> ProofOfWork {
-        //bigInt 初始化为 1
-        let mut target = BigInt::from(1);
-        // target 等于 1 左移 256 - TARGET_BITS 位
-        target.shl_assign(256 - TARGET_BITS);
-        ProofOfWork { block, target }
+    //新建工作量证明，目标值取自区块头中记录的难度
+    pub fn new_proof_of_work(header: BlockHeader) -> ProofOfWork {
+        let target = target_from_bits(header.get_bits());
+        ProofOfWork { header, target }
     }
 
-    // 工作量证明用到的数据
+    // 工作量证明用到的数据，即带上 nonce 的区块头规范编码
     fn prepare_data(&mut self, nonce: i64) -> Vec<u8> {
-        let pre_block_hash = self.block.get_pre_block_hash();
-        let transactions_hash = self.block.hash_transactions();
-        let timestamp = self.block.get_timestamp();
-        let mut data_bytes = vec![];
-        data_bytes.extend(pre_block_hash.as_bytes());
-        data_bytes.extend(transactions_hash.as_bytes());
-        data_bytes.extend(timestamp.to_be_bytes());
-        data_bytes.extend(TARGET_BITS.to_be_bytes());
-        data_bytes.extend(nonce.to_be_bytes());
-        data_bytes
+        self.header.set_nonce(nonce);
+        self.header.serialize()
     }
 
     // 工作量证明的核心就是寻找有效的哈希
@@ -47,14 +46,14 @@ impl ProofOfWork {
         // 2.这里的 20 指的是算出来的哈希前 20 位必须是 0，如果用 16 进制表示，就是前 5 位必须是 0，这一点从
         //   最后的输出可以看出来。
         //   例如：target 16进制输出是 0000100000000000000000000000000000000000000000000000000000000000
-        //   目前我们并不会实现一个动态调整目标的算法，所以将难度定义为一个全局的常量即可。
+        //   难度并不是常量：每隔 RETARGET_INTERVAL 个区块，会根据实际出块时间重新计算目标值并记录在区块中。
         // 3.将哈希与目标数 target 进行比较：先把哈希转换成一个大整数，然后检测它是否小于目标，小就是有效的，反之无效。
         let mut nonce = 0;
         let mut hash = Vec::new();
         println!("Mining the block");
         while nonce <= MAX_NONCE {
             let data = self.prepare_data(nonce); //用来哈希的数据
-            hash = coder::sha256_digest(data.as_slice()); //hash函数
+            hash = coder::double_sha256_digest(data.as_slice()); //hash函数
             let hash_int = BigInt::from_bytes_be(Sign::Plus, hash.as_slice()); //将hash转换为大整数
 
             if hash_int.lt(self.target.borrow()) {
@@ -67,4 +66,136 @@ impl ProofOfWork {
         }
         (nonce, HEXLOWER.encode(hash.as_slice()))
     }
+
+    // 只依据区块头验证：区块头哈希满足区块头声明的难度
+    pub fn validate(&self) -> bool {
+        if self.target > pow_limit() {
+            return false;
+        }
+        let hash = self.header.hash_bytes();
+        let hash_int = BigInt::from_bytes_be(Sign::Plus, hash.as_slice());
+        hash_int.lt(self.target.borrow())
+    }
+}
+
+// 难度下限对应的目标值，target 等于 1 左移 256 - TARGET_BITS 位
+fn pow_limit() -> BigInt {
+    //bigInt 初始化为 1
+    let mut target = BigInt::from(1);
+    target.shl_assign(256 - TARGET_BITS);
+    target
+}
+
+/// 创世块使用的难度，即最低难度
+pub fn pow_limit_bits() -> u32 {
+    bits_from_target(&pow_limit())
+}
+
+/// 满足该难度的区块期望需要计算的哈希次数，即 2^256 / (target + 1)，用来累计链的工作量
+pub fn block_work(bits: u32) -> BigInt {
+    let target = target_from_bits(bits);
+    (BigInt::from(1) << 256) / (target + 1)
+}
+
+/// 将紧凑格式的难度还原为目标值
+/// 与比特币的 nBits 相同：最高字节是目标值的字节长度，低 3 字节是目标值的最高 3 个字节
+pub fn target_from_bits(bits: u32) -> BigInt {
+    let size = bits >> 24;
+    let mantissa = BigInt::from(bits & 0x007f_ffff);
+    if size <= 3 {
+        mantissa >> (8 * (3 - size))
+    } else {
+        mantissa << (8 * (size - 3))
+    }
+}
+
+/// 将目标值编码为紧凑格式的难度
+pub fn bits_from_target(target: &BigInt) -> u32 {
+    let bytes = target.to_bytes_be().1;
+    let mut size = if target.sign() == Sign::NoSign {
+        0
+    } else {
+        bytes.len() as u32
+    };
+    let mut mantissa: u32 = 0;
+    for byte in bytes.iter().take(3) {
+        mantissa = (mantissa << 8) | u32::from(*byte);
+    }
+    if size < 3 {
+        mantissa <<= 8 * (3 - size);
+    }
+    // 最高位是符号位，尾数占用了符号位时需要右移一个字节
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+    (size << 24) | mantissa
+}
+
+/// 难度调整：根据一个调整周期内第一个和最后一个区块的时间戳计算新的难度
+pub fn calculate_next_bits(last_bits: u32, first_timestamp: i64, last_timestamp: i64) -> u32 {
+    // 周期内有 RETARGET_INTERVAL - 1 个出块间隔
+    let target_timespan = TARGET_SPACING * (RETARGET_INTERVAL as i64 - 1);
+    // 限制单次调整幅度在 1/4 到 4 倍之间，防止时间戳被操纵导致难度剧烈波动
+    let actual_timespan =
+        (last_timestamp - first_timestamp).clamp(target_timespan / 4, target_timespan * 4);
+    let mut target = target_from_bits(last_bits) * actual_timespan / target_timespan;
+    let limit = pow_limit();
+    if target > limit {
+        target = limit;
+    }
+    bits_from_target(&target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_bits_round_trip() {
+        for bits in [
+            0x1d00_ffff,
+            0x1b04_04cb,
+            0x207f_ffff,
+            0x0200_8000,
+            pow_limit_bits(),
+        ] {
+            assert_eq!(bits_from_target(&target_from_bits(bits)), bits);
+        }
+        // 最高 3 个字节之后的部分被截断
+        let target = BigInt::from(0x1234_5678u32);
+        assert_eq!(bits_from_target(&target), 0x0412_3456);
+        assert_eq!(target_from_bits(0x0412_3456), BigInt::from(0x1234_5600u32));
+        // 尾数最高位是符号位，占用时长度加一
+        assert_eq!(bits_from_target(&BigInt::from(0x80)), 0x0200_8000);
+        assert_eq!(target_from_bits(0x0200_8000), BigInt::from(0x80));
+        assert_eq!(bits_from_target(&BigInt::from(0)), 0);
+        assert_eq!(target_from_bits(pow_limit_bits()), pow_limit());
+    }
+
+    #[test]
+    fn retarget_is_clamped() {
+        let target_timespan = TARGET_SPACING * (RETARGET_INTERVAL as i64 - 1);
+        let target = pow_limit() >> 4;
+        let bits = bits_from_target(&target);
+        let next_target =
+            |first: i64, last: i64| target_from_bits(calculate_next_bits(bits, first, last));
+
+        // 出块时间符合预期时难度不变，偏差在范围内时按比例调整
+        assert_eq!(calculate_next_bits(bits, 0, target_timespan), bits);
+        assert_eq!(next_target(0, target_timespan * 2), &target * 2);
+        // 出块过快时调整周期按 1/4 计算，过慢时最多按 4 倍计算
+        let min_target = &target * (target_timespan / 4) / target_timespan;
+        let min_target = target_from_bits(bits_from_target(&min_target));
+        assert_eq!(next_target(0, 0), min_target);
+        assert_eq!(next_target(100, 0), min_target);
+        assert_eq!(next_target(0, target_timespan / 4), min_target);
+        assert_eq!(next_target(0, target_timespan * 100), &target * 4);
+        // 调整后的目标值不超过最低难度
+        let limit_bits = pow_limit_bits();
+        assert_eq!(
+            calculate_next_bits(limit_bits, 0, target_timespan * 4),
+            limit_bits
+        );
+    }
 }
@@ -0,0 +1,390 @@
+use crate::amount::Amount;
+use crate::error::Result;
+use crate::script::{Opcode, Script, ScriptError};
+use crate::transaction::{TXOutput, Transaction};
+use crate::utxo::UTXOSet;
+use crate::wallet::{address_to_script, hash_pub_key, Wallet};
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use utils::coder;
+
+/// 部分签名交易，保存未签名交易、每个输入花费的输出和已经收集到的签名
+/// 签名只依赖这些数据，不需要访问区块链，可以在离线的机器上完成，多个签名方的结果可以合并
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PartiallySignedTransaction {
+    tx: Transaction,        // 未签名交易，所有输入的解锁脚本为空
+    inputs: Vec<PsbtInput>, // 与交易输入一一对应
+}
+
+/// 部分签名交易的输入
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PsbtInput {
+    prev_output: TXOutput,                  // 输入花费的输出，签名需要它的锁定脚本
+    redeem_script: Option<Script>,          // 花费 P2SH 输出时的赎回脚本
+    signatures: BTreeMap<Vec<u8>, Vec<u8>>, // 公钥到签名的映射
+}
+
+impl PsbtInput {
+    pub fn get_prev_output(&self) -> &TXOutput {
+        &self.prev_output
+    }
+
+    pub fn get_redeem_script(&self) -> Option<&Script> {
+        self.redeem_script.as_ref()
+    }
+
+    pub fn get_signatures(&self) -> &BTreeMap<Vec<u8>, Vec<u8>> {
+        &self.signatures
+    }
+
+    // 签名时使用的脚本，P2SH 输出为赎回脚本，其他输出为锁定脚本
+    fn script_code(&self) -> Option<&Script> {
+        match self.prev_output.get_script_pub_key().get_p2sh_hash() {
+            Some(_) => self.redeem_script.as_ref(),
+            None => Some(self.prev_output.get_script_pub_key()),
+        }
+    }
+
+    // 公钥能否为该输入签名
+    fn can_sign(&self, pub_key: &[u8]) -> bool {
+        let script_pub_key = self.prev_output.get_script_pub_key();
+        if let Some(pub_key_hash) = script_pub_key.get_p2pkh_hash() {
+            return hash_pub_key(pub_key) == pub_key_hash;
+        }
+        match self.redeem_script.as_ref().and_then(|s| s.get_multisig()) {
+            Some((_, pub_keys)) => pub_keys.contains(&pub_key),
+            None => false,
+        }
+    }
+}
+
+/// 部分签名交易处理失败的原因
+#[derive(Debug, Clone, PartialEq)]
+pub enum PsbtError {
+    /// 交易已经包含解锁脚本
+    AlreadySigned,
+    /// 花费的输出数量与交易输入数量不一致
+    InputCountMismatch,
+    /// 合并的部分签名交易不是同一笔交易
+    TransactionMismatch,
+    /// 赎回脚本与 P2SH 输出的脚本哈希不一致，参数为输入索引
+    RedeemScriptMismatch(usize),
+    /// 输入的签名不足，参数为输入索引
+    Incomplete(usize),
+    /// 输入的解锁脚本验证失败，参数为输入索引和脚本执行失败的原因
+    BadScript(usize, ScriptError),
+}
+
+impl fmt::Display for PsbtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PsbtError::AlreadySigned => write!(f, "transaction already has scriptSigs"),
+            PsbtError::InputCountMismatch => {
+                write!(f, "previous outputs do not match transaction inputs")
+            }
+            PsbtError::TransactionMismatch => {
+                write!(
+                    f,
+                    "partially signed transactions spend different transactions"
+                )
+            }
+            PsbtError::RedeemScriptMismatch(idx) => {
+                write!(f, "redeem script does not match input {}", idx)
+            }
+            PsbtError::Incomplete(idx) => write!(f, "input {} is not fully signed", idx),
+            PsbtError::BadScript(idx, e) => {
+                write!(f, "input {} fails script verification: {}", idx, e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PsbtError {}
+
+impl PartiallySignedTransaction {
+    /// 由未签名交易和它的输入花费的输出创建
+    pub fn new(
+        tx: Transaction,
+        prev_outputs: Vec<TXOutput>,
+    ) -> std::result::Result<PartiallySignedTransaction, PsbtError> {
+        if tx.get_vin().len() != prev_outputs.len() {
+            return Err(PsbtError::InputCountMismatch);
+        }
+        if tx
+            .get_vin()
+            .iter()
+            .any(|vin| !vin.get_script_sig().get_ops().is_empty())
+        {
+            return Err(PsbtError::AlreadySigned);
+        }
+        let inputs = prev_outputs
+            .into_iter()
+            .map(|prev_output| PsbtInput {
+                prev_output,
+                redeem_script: None,
+                signatures: BTreeMap::new(),
+            })
+            .collect();
+        Ok(PartiallySignedTransaction { tx, inputs })
+    }
+
+    /// 从 from 地址的未花费输出中选取输入，创建向 to 支付的部分签名交易，找零返回 from
+    /// 只需要区块链，不需要钱包，可以在只保存地址的联网机器上创建
+    pub fn create(
+        from: &str,
+        to: &str,
+        amount: Amount,
+        fee: Amount,
+        lock_time: u32,
+        utxo_set: &UTXOSet,
+    ) -> Result<PartiallySignedTransaction> {
+        let script_pub_key = address_to_script(from)?;
+        let (tx, prev_outputs) = Transaction::new_unsigned_transaction(
+            &script_pub_key,
+            to,
+            amount,
+            fee,
+            lock_time,
+            utxo_set,
+        )?;
+        Ok(PartiallySignedTransaction::new(tx, prev_outputs)?)
+    }
+
+    /// 为花费 P2SH 输出的输入设置赎回脚本，返回设置的输入数量
+    pub fn add_redeem_script(&mut self, redeem_script: &Script) -> usize {
+        let script_hash = redeem_script.hash160();
+        let mut added = 0;
+        for input in self.inputs.iter_mut() {
+            if input.prev_output.get_script_pub_key().get_p2sh_hash() == Some(&script_hash) {
+                input.redeem_script = Some(redeem_script.clone());
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// 用钱包为能够签名的输入添加签名，返回签名的输入数量
+    pub fn sign(&mut self, wallet: &Wallet) -> Result<usize> {
+        let pub_key = wallet.get_public_key();
+        let mut signed = 0;
+        for (idx, input) in self.inputs.iter_mut().enumerate() {
+            if !input.can_sign(pub_key) {
+                continue;
+            }
+            let script_code = match input.script_code() {
+                Some(script_code) => script_code,
+                None => continue,
+            };
+            let sighash = self.tx.signature_hash(idx, script_code);
+            let signature = coder::ecdsa_p256_sha256_sign_digest(wallet.get_pkcs8(), &sighash)?;
+            input.signatures.insert(pub_key.to_vec(), signature);
+            signed += 1;
+        }
+        Ok(signed)
+    }
+
+    /// 合并另一个签名方对同一笔交易的签名和赎回脚本
+    pub fn combine(
+        &mut self,
+        other: &PartiallySignedTransaction,
+    ) -> std::result::Result<(), PsbtError> {
+        if self.tx.get_id() != other.tx.get_id() {
+            return Err(PsbtError::TransactionMismatch);
+        }
+        for (input, other_input) in self.inputs.iter_mut().zip(other.inputs.iter()) {
+            if input.redeem_script.is_none() {
+                input.redeem_script = other_input.redeem_script.clone();
+            }
+            for (pub_key, signature) in &other_input.signatures {
+                input
+                    .signatures
+                    .entry(pub_key.clone())
+                    .or_insert_with(|| signature.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// 用收集到的签名生成每个输入的解锁脚本，全部通过脚本验证后返回可以广播的交易
+    /// 无效的签名会被忽略，多签输入按公钥在赎回脚本中的顺序选取所需数量的有效签名
+    pub fn finalize(&self) -> std::result::Result<Transaction, PsbtError> {
+        let mut tx = self.tx.clone();
+        for (idx, input) in self.inputs.iter().enumerate() {
+            let script_pub_key = input.prev_output.get_script_pub_key();
+            let script_code = input.script_code().ok_or(PsbtError::Incomplete(idx))?;
+            if let Some(script_hash) = script_pub_key.get_p2sh_hash() {
+                if script_code.hash160() != script_hash {
+                    return Err(PsbtError::RedeemScriptMismatch(idx));
+                }
+            }
+            let sighash = self.tx.signature_hash(idx, script_code);
+            let is_valid = |pub_key: &[u8], signature: &[u8]| {
+                coder::ecdsa_p256_sha256_sign_verify(pub_key, signature, &sighash)
+            };
+
+            let script_sig = if script_pub_key.get_p2pkh_hash().is_some() {
+                let (pub_key, signature) = input
+                    .signatures
+                    .iter()
+                    .find(|(pub_key, signature)| {
+                        input.can_sign(pub_key) && is_valid(pub_key, signature)
+                    })
+                    .ok_or(PsbtError::Incomplete(idx))?;
+                Script::new_p2pkh_sig(signature, pub_key)
+            } else {
+                let (required, pub_keys) = script_code
+                    .get_multisig()
+                    .ok_or(PsbtError::Incomplete(idx))?;
+                let mut ops: Vec<Opcode> = pub_keys
+                    .iter()
+                    .filter_map(|pub_key| {
+                        let signature = input.signatures.get(*pub_key)?;
+                        match is_valid(pub_key, signature) {
+                            true => Some(Opcode::Push(signature.clone())),
+                            false => None,
+                        }
+                    })
+                    .take(required)
+                    .collect();
+                if ops.len() < required {
+                    return Err(PsbtError::Incomplete(idx));
+                }
+                ops.push(Opcode::Push(script_code.to_bytes()));
+                Script::new(ops)
+            };
+            tx.set_script_sig(idx, script_sig);
+        }
+        for (idx, input) in self.inputs.iter().enumerate() {
+            tx.verify_input(idx, input.prev_output.get_script_pub_key())
+                .map_err(|e| PsbtError::BadScript(idx, e))?;
+        }
+        Ok(tx)
+    }
+
+    /// 编码为十六进制，便于在联网机器和离线签名机器之间传递
+    pub fn to_hex(&self) -> String {
+        HEXLOWER.encode(coder::serialized(self).as_slice())
+    }
+
+    /// 从十六进制解码，格式错误时返回 None
+    pub fn from_hex(psbt_hex: &str) -> Option<PartiallySignedTransaction> {
+        let bytes = HEXLOWER.decode(psbt_hex.as_bytes()).ok()?;
+        let psbt: PartiallySignedTransaction = coder::deserialized(bytes.as_slice()).ok()?;
+        match psbt.tx.get_vin().len() == psbt.inputs.len() {
+            true => Some(psbt),
+            false => None,
+        }
+    }
+
+    pub fn get_transaction(&self) -> &Transaction {
+        &self.tx
+    }
+
+    pub fn get_inputs(&self) -> &[PsbtInput] {
+        self.inputs.as_slice()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TXInput;
+
+    fn coins(coins: u64) -> Amount {
+        Amount::from_coins(coins).unwrap()
+    }
+
+    // 花费一个 2-of-3 多签 P2SH 输出的未签名交易
+    fn multisig_psbt(wallets: &[Wallet]) -> (PartiallySignedTransaction, Script) {
+        let pub_keys: Vec<Vec<u8>> = wallets
+            .iter()
+            .map(|wallet| wallet.get_public_key().to_vec())
+            .collect();
+        let redeem_script = Script::new_multisig(2, &pub_keys);
+        let prev_output = TXOutput::new_with_script(
+            coins(10),
+            Script::new_p2sh(redeem_script.hash160().as_slice()),
+        );
+        let to = Wallet::new().unwrap().get_address();
+        let tx = Transaction::new(
+            vec![TXInput::new(&[1; 32], 0)],
+            vec![TXOutput::new(coins(9), &to).unwrap()],
+        );
+        let psbt = PartiallySignedTransaction::new(tx, vec![prev_output]).unwrap();
+        (psbt, redeem_script)
+    }
+
+    #[test]
+    fn combine_and_finalize_multisig() {
+        let wallets: Vec<Wallet> = (0..3).map(|_| Wallet::new().unwrap()).collect();
+        let (psbt, redeem_script) = multisig_psbt(&wallets);
+
+        // 两个签名方分别签名，通过十六进制传递
+        let mut first = PartiallySignedTransaction::from_hex(&psbt.to_hex()).unwrap();
+        assert_eq!(first.add_redeem_script(&redeem_script), 1);
+        assert_eq!(first.sign(&wallets[2]).unwrap(), 1);
+        let mut second = PartiallySignedTransaction::from_hex(&psbt.to_hex()).unwrap();
+        second.add_redeem_script(&redeem_script);
+        assert_eq!(second.sign(&wallets[0]).unwrap(), 1);
+        assert_eq!(first.finalize().unwrap_err(), PsbtError::Incomplete(0));
+
+        // 合并另一方的签名后可以生成完整的交易
+        let mut combined = psbt.clone();
+        combined.combine(&first).unwrap();
+        combined.combine(&second).unwrap();
+        assert_eq!(combined.get_inputs()[0].get_signatures().len(), 2);
+        let tx = combined.finalize().unwrap();
+        let prev_output = combined.get_inputs()[0].get_prev_output();
+        assert_eq!(tx.verify_input(0, prev_output.get_script_pub_key()), Ok(()));
+        assert_ne!(tx.get_id(), psbt.get_transaction().get_id());
+
+        // 合并是幂等的，结果与合并顺序无关
+        let mut reversed = PartiallySignedTransaction::from_hex(&second.to_hex()).unwrap();
+        reversed.combine(&first).unwrap();
+        reversed.combine(&first).unwrap();
+        assert_eq!(reversed.finalize().unwrap().get_id(), tx.get_id());
+    }
+
+    #[test]
+    fn finalize_rejects_missing_redeem_script_and_wrong_signers() {
+        let wallets: Vec<Wallet> = (0..3).map(|_| Wallet::new().unwrap()).collect();
+        let (mut psbt, redeem_script) = multisig_psbt(&wallets);
+        // 没有赎回脚本时无法签名
+        assert_eq!(psbt.sign(&wallets[0]).unwrap(), 0);
+        assert_eq!(psbt.finalize().unwrap_err(), PsbtError::Incomplete(0));
+
+        psbt.add_redeem_script(&redeem_script);
+        assert_eq!(psbt.sign(&Wallet::new().unwrap()).unwrap(), 0);
+        psbt.sign(&wallets[1]).unwrap();
+        assert_eq!(psbt.finalize().unwrap_err(), PsbtError::Incomplete(0));
+    }
+
+    #[test]
+    fn combine_rejects_different_transaction() {
+        let wallets: Vec<Wallet> = (0..3).map(|_| Wallet::new().unwrap()).collect();
+        let (mut psbt, _) = multisig_psbt(&wallets);
+        let (other, _) = multisig_psbt(&wallets);
+        assert_eq!(
+            psbt.combine(&other).unwrap_err(),
+            PsbtError::TransactionMismatch
+        );
+    }
+
+    #[test]
+    fn new_rejects_signed_or_mismatched_inputs() {
+        let wallets: Vec<Wallet> = (0..3).map(|_| Wallet::new().unwrap()).collect();
+        let (psbt, _) = multisig_psbt(&wallets);
+        let tx = psbt.get_transaction().clone();
+        let result = PartiallySignedTransaction::new(tx.clone(), vec![]);
+        assert_eq!(result.unwrap_err(), PsbtError::InputCountMismatch);
+
+        let mut signed = tx;
+        signed.set_script_sig(0, Script::new(vec![Opcode::Push(vec![1])]));
+        let prev_outputs = vec![psbt.get_inputs()[0].get_prev_output().clone()];
+        let result = PartiallySignedTransaction::new(signed, prev_outputs);
+        assert_eq!(result.unwrap_err(), PsbtError::AlreadySigned);
+        assert!(PartiallySignedTransaction::from_hex("zz").is_none());
+    }
+}
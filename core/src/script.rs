@@ -0,0 +1,635 @@
+use crate::transaction::SEQUENCE_LOCKTIME_DISABLE_FLAG;
+use crate::wallet::hash_pub_key;
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use utils::coder;
+
+/// 脚本中操作码的最大数量
+const MAX_SCRIPT_OPS: usize = 201;
+/// 单次压栈数据的最大字节数
+const MAX_PUSH_SIZE: usize = 520;
+/// 执行过程中栈的最大深度
+const MAX_STACK_SIZE: usize = 1000;
+/// 多签脚本中公钥的最大数量
+pub const MAX_MULTISIG_KEYS: usize = 20;
+/// 数字的最大字节数
+const MAX_NUM_SIZE: usize = 8;
+
+/// 操作码
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Opcode {
+    /// 将数据压入栈
+    Push(Vec<u8>),
+    /// 复制栈顶元素
+    Dup,
+    /// 弹出栈顶元素
+    Drop,
+    /// 栈顶元素替换为它的 ripemd160(sha256)
+    Hash160,
+    /// 栈顶元素替换为它的 sha256
+    Sha256,
+    /// 弹出两个元素，相等时压入真，否则压入假
+    Equal,
+    /// 与 Equal 相同，但不相等时脚本立即失败
+    EqualVerify,
+    /// 弹出栈顶元素，为假时脚本失败
+    Verify,
+    /// 弹出公钥和签名，签名有效时压入真，否则压入假
+    CheckSig,
+    /// 与 CheckSig 相同，但签名无效时脚本立即失败
+    CheckSigVerify,
+    /// 依次弹出公钥数量 n、n 个公钥、签名数量 m 和 m 个签名
+    /// 签名按公钥的顺序排列且全部有效时压入真，否则压入假
+    CheckMultiSig,
+    /// 与 CheckMultiSig 相同，但验证失败时脚本立即失败
+    CheckMultiSigVerify,
+    /// 栈顶数字作为绝对时间锁，交易的 lock_time 未达到时脚本失败，不弹出栈顶元素
+    CheckLockTimeVerify,
+    /// 栈顶数字作为相对时间锁，输入的 sequence 未达到时脚本失败，不弹出栈顶元素
+    CheckSequenceVerify,
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Opcode::Push(data) => write!(f, "{}", HEXLOWER.encode(data)),
+            Opcode::Dup => write!(f, "OP_DUP"),
+            Opcode::Drop => write!(f, "OP_DROP"),
+            Opcode::Hash160 => write!(f, "OP_HASH160"),
+            Opcode::Sha256 => write!(f, "OP_SHA256"),
+            Opcode::Equal => write!(f, "OP_EQUAL"),
+            Opcode::EqualVerify => write!(f, "OP_EQUALVERIFY"),
+            Opcode::Verify => write!(f, "OP_VERIFY"),
+            Opcode::CheckSig => write!(f, "OP_CHECKSIG"),
+            Opcode::CheckSigVerify => write!(f, "OP_CHECKSIGVERIFY"),
+            Opcode::CheckMultiSig => write!(f, "OP_CHECKMULTISIG"),
+            Opcode::CheckMultiSigVerify => write!(f, "OP_CHECKMULTISIGVERIFY"),
+            Opcode::CheckLockTimeVerify => write!(f, "OP_CHECKLOCKTIMEVERIFY"),
+            Opcode::CheckSequenceVerify => write!(f, "OP_CHECKSEQUENCEVERIFY"),
+        }
+    }
+}
+
+/// 脚本，输出中的锁定脚本（scriptPubKey）规定花费条件，输入中的解锁脚本（scriptSig）提供满足条件的数据
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Script {
+    ops: Vec<Opcode>,
+}
+
+impl Script {
+    pub fn new(ops: Vec<Opcode>) -> Script {
+        Script { ops }
+    }
+
+    /// 支付到公钥哈希（P2PKH）的锁定脚本：OP_DUP OP_HASH160 <pub_key_hash> OP_EQUALVERIFY OP_CHECKSIG
+    pub fn new_p2pkh(pub_key_hash: &[u8]) -> Script {
+        Script::new(vec![
+            Opcode::Dup,
+            Opcode::Hash160,
+            Opcode::Push(pub_key_hash.to_vec()),
+            Opcode::EqualVerify,
+            Opcode::CheckSig,
+        ])
+    }
+
+    /// P2PKH 的解锁脚本：<signature> <pub_key>
+    pub fn new_p2pkh_sig(signature: &[u8], pub_key: &[u8]) -> Script {
+        Script::new(vec![
+            Opcode::Push(signature.to_vec()),
+            Opcode::Push(pub_key.to_vec()),
+        ])
+    }
+
+    /// 哈希锁的锁定脚本：OP_SHA256 <hash> OP_EQUAL，提供 sha256 原像即可花费
+    pub fn new_hash_lock(hash: &[u8]) -> Script {
+        Script::new(vec![
+            Opcode::Sha256,
+            Opcode::Push(hash.to_vec()),
+            Opcode::Equal,
+        ])
+    }
+
+    /// 哈希锁的解锁脚本：<preimage>
+    pub fn new_hash_lock_sig(preimage: &[u8]) -> Script {
+        Script::new(vec![Opcode::Push(preimage.to_vec())])
+    }
+
+    /// m-of-n 多签脚本：<m> <pub_key_1> ... <pub_key_n> <n> OP_CHECKMULTISIG
+    pub fn new_multisig(required: usize, pub_keys: &[Vec<u8>]) -> Script {
+        let mut ops = vec![Opcode::Push(encode_num(required as u64))];
+        for pub_key in pub_keys {
+            ops.push(Opcode::Push(pub_key.clone()));
+        }
+        ops.push(Opcode::Push(encode_num(pub_keys.len() as u64)));
+        ops.push(Opcode::CheckMultiSig);
+        Script::new(ops)
+    }
+
+    /// 支付到脚本哈希（P2SH）的锁定脚本：OP_HASH160 <script_hash> OP_EQUAL
+    /// 花费时解锁脚本最后压入赎回脚本，赎回脚本的哈希匹配后再用其余数据执行赎回脚本
+    pub fn new_p2sh(script_hash: &[u8]) -> Script {
+        Script::new(vec![
+            Opcode::Hash160,
+            Opcode::Push(script_hash.to_vec()),
+            Opcode::Equal,
+        ])
+    }
+
+    /// 脚本的序列化，作为赎回脚本压栈
+    pub fn to_bytes(&self) -> Vec<u8> {
+        coder::serialized(self)
+    }
+
+    /// 反序列化脚本，格式错误时返回 None
+    pub fn from_bytes(bytes: &[u8]) -> Option<Script> {
+        coder::deserialized(bytes).ok()
+    }
+
+    /// 脚本哈希，即 P2SH 锁定脚本中的哈希
+    pub fn hash160(&self) -> Vec<u8> {
+        hash_pub_key(self.to_bytes().as_slice())
+    }
+
+    pub fn get_ops(&self) -> &[Opcode] {
+        self.ops.as_slice()
+    }
+
+    /// 是 P2PKH 锁定脚本时返回其中的公钥哈希
+    pub fn get_p2pkh_hash(&self) -> Option<&[u8]> {
+        match self.ops.as_slice() {
+            [Opcode::Dup, Opcode::Hash160, Opcode::Push(pub_key_hash), Opcode::EqualVerify, Opcode::CheckSig] => {
+                Some(pub_key_hash.as_slice())
+            }
+            _ => None,
+        }
+    }
+
+    /// 是 P2SH 锁定脚本时返回其中的脚本哈希
+    pub fn get_p2sh_hash(&self) -> Option<&[u8]> {
+        match self.ops.as_slice() {
+            [Opcode::Hash160, Opcode::Push(script_hash), Opcode::Equal] => {
+                Some(script_hash.as_slice())
+            }
+            _ => None,
+        }
+    }
+
+    /// 是多签脚本时返回需要的签名数量和公钥列表
+    pub fn get_multisig(&self) -> Option<(usize, Vec<&[u8]>)> {
+        let (last, rest) = self.ops.split_last()?;
+        if *last != Opcode::CheckMultiSig || rest.len() < 2 {
+            return None;
+        }
+        let mut data = vec![];
+        for op in rest {
+            match op {
+                Opcode::Push(item) => data.push(item.as_slice()),
+                _ => return None,
+            }
+        }
+        let required = decode_num(data[0]).ok()? as usize;
+        let count = decode_num(data[data.len() - 1]).ok()? as usize;
+        let pub_keys = data[1..data.len() - 1].to_vec();
+        if count != pub_keys.len() || required == 0 || required > count {
+            return None;
+        }
+        Some((required, pub_keys))
+    }
+
+    /// 脚本只包含压栈操作
+    pub fn is_push_only(&self) -> bool {
+        self.ops.iter().all(|op| matches!(op, Opcode::Push(_)))
+    }
+
+    /// 只包含压栈操作时返回压入的数据
+    pub fn get_push_data(&self) -> Option<Vec<&[u8]>> {
+        self.ops
+            .iter()
+            .map(|op| match op {
+                Opcode::Push(data) => Some(data.as_slice()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ops: Vec<String> = self.ops.iter().map(|op| op.to_string()).collect();
+        write!(f, "{}", ops.join(" "))
+    }
+}
+
+/// 脚本执行失败的原因
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptError {
+    /// 解锁脚本包含压栈以外的操作
+    SigPushOnly,
+    /// 操作码数量超过上限
+    ScriptSize,
+    /// 压栈数据超过上限
+    PushSize,
+    /// 栈深度超过上限
+    StackSize,
+    /// 栈中元素不足
+    InvalidStackOperation,
+    /// OP_EQUALVERIFY 失败
+    EqualVerify,
+    /// OP_VERIFY 失败
+    Verify,
+    /// OP_CHECKSIGVERIFY 失败
+    CheckSigVerify,
+    /// 执行结束后栈为空或栈顶为假
+    EvalFalse,
+    /// 数字编码超过 8 字节
+    InvalidNumber,
+    /// 多签公钥数量超过上限
+    PubKeyCount,
+    /// 多签签名数量超过公钥数量
+    SigCount,
+    /// OP_CHECKMULTISIGVERIFY 失败
+    CheckMultiSigVerify,
+    /// P2SH 赎回脚本格式错误
+    BadRedeemScript,
+    /// 交易未满足 OP_CHECKLOCKTIMEVERIFY 或 OP_CHECKSEQUENCEVERIFY 要求的时间锁
+    UnsatisfiedLockTime,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::SigPushOnly => write!(f, "scriptSig is not push only"),
+            ScriptError::ScriptSize => write!(f, "script has too many operations"),
+            ScriptError::PushSize => write!(f, "push exceeds {} bytes", MAX_PUSH_SIZE),
+            ScriptError::StackSize => write!(f, "stack exceeds {} items", MAX_STACK_SIZE),
+            ScriptError::InvalidStackOperation => write!(f, "operation on empty stack"),
+            ScriptError::EqualVerify => write!(f, "OP_EQUALVERIFY failed"),
+            ScriptError::Verify => write!(f, "OP_VERIFY failed"),
+            ScriptError::CheckSigVerify => write!(f, "OP_CHECKSIGVERIFY failed"),
+            ScriptError::EvalFalse => write!(f, "script evaluated to false"),
+            ScriptError::InvalidNumber => write!(f, "number exceeds {} bytes", MAX_NUM_SIZE),
+            ScriptError::PubKeyCount => write!(f, "too many public keys for multisig"),
+            ScriptError::SigCount => write!(f, "more signatures than public keys"),
+            ScriptError::CheckMultiSigVerify => write!(f, "OP_CHECKMULTISIGVERIFY failed"),
+            ScriptError::BadRedeemScript => write!(f, "malformed redeem script"),
+            ScriptError::UnsatisfiedLockTime => write!(f, "locktime requirement not satisfied"),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// 签名和时间锁检查，由交易提供被签名的数据、lock_time 和输入的 sequence
+/// script_code 是正在执行的脚本，普通输出为锁定脚本，P2SH 输出为赎回脚本
+pub trait SignatureChecker {
+    fn check_sig(&self, signature: &[u8], pub_key: &[u8], script_code: &Script) -> bool;
+    fn check_lock_time(&self, lock_time: u64) -> bool;
+    fn check_sequence(&self, sequence: u64) -> bool;
+}
+
+/// 先执行解锁脚本，再用得到的栈执行锁定脚本，结束时栈顶为真则验证通过
+/// 锁定脚本是 P2SH 时，还要用解锁脚本压入的其余数据执行赎回脚本
+pub fn verify_script(
+    script_sig: &Script,
+    script_pub_key: &Script,
+    checker: &dyn SignatureChecker,
+) -> Result<(), ScriptError> {
+    if !script_sig.is_push_only() {
+        return Err(ScriptError::SigPushOnly);
+    }
+    let mut stack = vec![];
+    eval_script(&mut stack, script_sig, checker)?;
+    let mut redeem_stack = stack.clone();
+    eval_script(&mut stack, script_pub_key, checker)?;
+    check_top(&stack)?;
+
+    if script_pub_key.get_p2sh_hash().is_some() {
+        let redeem_bytes = pop(&mut redeem_stack)?;
+        let redeem_script =
+            Script::from_bytes(redeem_bytes.as_slice()).ok_or(ScriptError::BadRedeemScript)?;
+        eval_script(&mut redeem_stack, &redeem_script, checker)?;
+        check_top(&redeem_stack)?;
+    }
+    Ok(())
+}
+
+// 执行结束后栈顶必须为真
+fn check_top(stack: &[Vec<u8>]) -> Result<(), ScriptError> {
+    match stack.last() {
+        Some(top) if cast_to_bool(top) => Ok(()),
+        _ => Err(ScriptError::EvalFalse),
+    }
+}
+
+// 在给定的栈上执行脚本
+fn eval_script(
+    stack: &mut Vec<Vec<u8>>,
+    script: &Script,
+    checker: &dyn SignatureChecker,
+) -> Result<(), ScriptError> {
+    if script.ops.len() > MAX_SCRIPT_OPS {
+        return Err(ScriptError::ScriptSize);
+    }
+    for op in &script.ops {
+        match op {
+            Opcode::Push(data) => {
+                if data.len() > MAX_PUSH_SIZE {
+                    return Err(ScriptError::PushSize);
+                }
+                stack.push(data.clone());
+            }
+            Opcode::Dup => {
+                let top = stack.last().ok_or(ScriptError::InvalidStackOperation)?;
+                stack.push(top.clone());
+            }
+            Opcode::Drop => {
+                pop(stack)?;
+            }
+            Opcode::Hash160 => {
+                let data = pop(stack)?;
+                stack.push(hash_pub_key(data.as_slice()));
+            }
+            Opcode::Sha256 => {
+                let data = pop(stack)?;
+                stack.push(coder::sha256_digest(data.as_slice()));
+            }
+            Opcode::Equal | Opcode::EqualVerify => {
+                let a = pop(stack)?;
+                let b = pop(stack)?;
+                let equal = a == b;
+                if *op == Opcode::EqualVerify {
+                    if !equal {
+                        return Err(ScriptError::EqualVerify);
+                    }
+                } else {
+                    stack.push(bool_to_vec(equal));
+                }
+            }
+            Opcode::Verify => {
+                if !cast_to_bool(&pop(stack)?) {
+                    return Err(ScriptError::Verify);
+                }
+            }
+            Opcode::CheckSig | Opcode::CheckSigVerify => {
+                let pub_key = pop(stack)?;
+                let signature = pop(stack)?;
+                let valid = checker.check_sig(signature.as_slice(), pub_key.as_slice(), script);
+                if *op == Opcode::CheckSigVerify {
+                    if !valid {
+                        return Err(ScriptError::CheckSigVerify);
+                    }
+                } else {
+                    stack.push(bool_to_vec(valid));
+                }
+            }
+            Opcode::CheckMultiSig | Opcode::CheckMultiSigVerify => {
+                let count = decode_num(pop(stack)?.as_slice())? as usize;
+                if count > MAX_MULTISIG_KEYS {
+                    return Err(ScriptError::PubKeyCount);
+                }
+                let mut pub_keys = vec![];
+                for _ in 0..count {
+                    pub_keys.push(pop(stack)?);
+                }
+                pub_keys.reverse();
+                let required = decode_num(pop(stack)?.as_slice())? as usize;
+                if required > count {
+                    return Err(ScriptError::SigCount);
+                }
+                let mut signatures = vec![];
+                for _ in 0..required {
+                    signatures.push(pop(stack)?);
+                }
+                signatures.reverse();
+                // 每个签名依次匹配之后的公钥，签名的顺序必须与公钥一致
+                let mut keys = pub_keys.iter();
+                let valid = signatures.iter().all(|signature| {
+                    keys.any(|pub_key| checker.check_sig(signature, pub_key, script))
+                });
+                if *op == Opcode::CheckMultiSigVerify {
+                    if !valid {
+                        return Err(ScriptError::CheckMultiSigVerify);
+                    }
+                } else {
+                    stack.push(bool_to_vec(valid));
+                }
+            }
+            Opcode::CheckLockTimeVerify => {
+                let top = stack.last().ok_or(ScriptError::InvalidStackOperation)?;
+                if !checker.check_lock_time(decode_num(top.as_slice())?) {
+                    return Err(ScriptError::UnsatisfiedLockTime);
+                }
+            }
+            Opcode::CheckSequenceVerify => {
+                let top = stack.last().ok_or(ScriptError::InvalidStackOperation)?;
+                let sequence = decode_num(top.as_slice())?;
+                // 设置了禁用位的数字不表示时间锁，与空操作相同
+                if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG as u64 == 0
+                    && !checker.check_sequence(sequence)
+                {
+                    return Err(ScriptError::UnsatisfiedLockTime);
+                }
+            }
+        }
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::StackSize);
+        }
+    }
+    Ok(())
+}
+
+/// 数字编码为小端序，去掉高位的 0，0 编码为空
+pub fn encode_num(num: u64) -> Vec<u8> {
+    let mut bytes = num.to_le_bytes().to_vec();
+    while bytes.last() == Some(&0) {
+        bytes.pop();
+    }
+    bytes
+}
+
+/// 解码小端序的数字
+pub fn decode_num(data: &[u8]) -> Result<u64, ScriptError> {
+    if data.len() > MAX_NUM_SIZE {
+        return Err(ScriptError::InvalidNumber);
+    }
+    let mut bytes = [0u8; MAX_NUM_SIZE];
+    bytes[..data.len()].copy_from_slice(data);
+    Ok(u64::from_le_bytes(bytes))
+}
+
+// 弹出栈顶元素
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, ScriptError> {
+    stack.pop().ok_or(ScriptError::InvalidStackOperation)
+}
+
+// 空数据和全 0 为假，其余为真
+fn cast_to_bool(data: &[u8]) -> bool {
+    data.iter().any(|b| *b != 0)
+}
+
+fn bool_to_vec(value: bool) -> Vec<u8> {
+    match value {
+        true => vec![1],
+        false => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 签名为 "sig" 加公钥时有效，时间锁按给定的 lock_time 和 sequence 检查
+    struct MockChecker {
+        lock_time: u64,
+        sequence: u64,
+    }
+
+    impl SignatureChecker for MockChecker {
+        fn check_sig(&self, signature: &[u8], pub_key: &[u8], _script_code: &Script) -> bool {
+            signature == sign(pub_key).as_slice()
+        }
+
+        fn check_lock_time(&self, lock_time: u64) -> bool {
+            lock_time <= self.lock_time
+        }
+
+        fn check_sequence(&self, sequence: u64) -> bool {
+            sequence <= self.sequence
+        }
+    }
+
+    const CHECKER: MockChecker = MockChecker {
+        lock_time: 100,
+        sequence: 10,
+    };
+
+    fn sign(pub_key: &[u8]) -> Vec<u8> {
+        [b"sig".as_slice(), pub_key].concat()
+    }
+
+    fn pushes(data: &[Vec<u8>]) -> Script {
+        Script::new(data.iter().cloned().map(Opcode::Push).collect())
+    }
+
+    fn pub_keys() -> Vec<Vec<u8>> {
+        vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()]
+    }
+
+    #[test]
+    fn multisig_requires_ordered_signatures() {
+        let keys = pub_keys();
+        let script_pub_key = Script::new_multisig(2, &keys);
+        let verify =
+            |signatures: &[Vec<u8>]| verify_script(&pushes(signatures), &script_pub_key, &CHECKER);
+
+        assert_eq!(verify(&[sign(&keys[0]), sign(&keys[2])]), Ok(()));
+        assert_eq!(verify(&[sign(&keys[1]), sign(&keys[2])]), Ok(()));
+        // 签名顺序与公钥顺序不一致
+        assert_eq!(
+            verify(&[sign(&keys[2]), sign(&keys[0])]),
+            Err(ScriptError::EvalFalse)
+        );
+        // 同一公钥的签名不能使用两次
+        assert_eq!(
+            verify(&[sign(&keys[0]), sign(&keys[0])]),
+            Err(ScriptError::EvalFalse)
+        );
+        assert_eq!(
+            verify(&[sign(&keys[0]), sign(b"other")]),
+            Err(ScriptError::EvalFalse)
+        );
+        // 签名数量不足
+        assert_eq!(
+            verify(&[sign(&keys[0])]),
+            Err(ScriptError::InvalidStackOperation)
+        );
+    }
+
+    #[test]
+    fn p2sh_multisig_runs_redeem_script() {
+        let keys = pub_keys();
+        let redeem_script = Script::new_multisig(2, &keys);
+        let script_pub_key = Script::new_p2sh(redeem_script.hash160().as_slice());
+        let redeem_bytes = redeem_script.to_bytes();
+
+        let script_sig = pushes(&[sign(&keys[0]), sign(&keys[1]), redeem_bytes.clone()]);
+        assert_eq!(
+            verify_script(&script_sig, &script_pub_key, &CHECKER),
+            Ok(())
+        );
+        // 赎回脚本哈希匹配，但签名无效
+        let script_sig = pushes(&[sign(&keys[0]), sign(b"other"), redeem_bytes]);
+        assert_eq!(
+            verify_script(&script_sig, &script_pub_key, &CHECKER),
+            Err(ScriptError::EvalFalse)
+        );
+        // 赎回脚本与脚本哈希不一致
+        let other_script = Script::new_multisig(1, &keys).to_bytes();
+        let script_sig = pushes(&[sign(&keys[0]), other_script]);
+        assert_eq!(
+            verify_script(&script_sig, &script_pub_key, &CHECKER),
+            Err(ScriptError::EvalFalse)
+        );
+    }
+
+    #[test]
+    fn check_lock_time_verify() {
+        let script_pub_key = |lock_time: u64| {
+            Script::new(vec![
+                Opcode::Push(encode_num(lock_time)),
+                Opcode::CheckLockTimeVerify,
+            ])
+        };
+        let script_sig = Script::default();
+        assert_eq!(
+            verify_script(&script_sig, &script_pub_key(100), &CHECKER),
+            Ok(())
+        );
+        assert_eq!(
+            verify_script(&script_sig, &script_pub_key(101), &CHECKER),
+            Err(ScriptError::UnsatisfiedLockTime)
+        );
+        // 栈为空时没有可以检查的时间锁
+        let script_pub_key = Script::new(vec![Opcode::CheckLockTimeVerify]);
+        assert_eq!(
+            verify_script(&script_sig, &script_pub_key, &CHECKER),
+            Err(ScriptError::InvalidStackOperation)
+        );
+    }
+
+    #[test]
+    fn check_sequence_verify() {
+        let script_pub_key = |sequence: u64| {
+            Script::new(vec![
+                Opcode::Push(encode_num(sequence)),
+                Opcode::CheckSequenceVerify,
+            ])
+        };
+        let script_sig = Script::default();
+        assert_eq!(
+            verify_script(&script_sig, &script_pub_key(10), &CHECKER),
+            Ok(())
+        );
+        assert_eq!(
+            verify_script(&script_sig, &script_pub_key(11), &CHECKER),
+            Err(ScriptError::UnsatisfiedLockTime)
+        );
+        // 设置了禁用位的数字与空操作相同
+        let disabled = SEQUENCE_LOCKTIME_DISABLE_FLAG as u64 | 11;
+        assert_eq!(
+            verify_script(&script_sig, &script_pub_key(disabled), &CHECKER),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn script_sig_must_be_push_only() {
+        let script_sig = Script::new(vec![Opcode::Push(vec![1]), Opcode::Dup]);
+        let script_pub_key = Script::new(vec![Opcode::Equal]);
+        assert_eq!(
+            verify_script(&script_sig, &script_pub_key, &CHECKER),
+            Err(ScriptError::SigPushOnly)
+        );
+    }
+}
@@ -1,43 +1,64 @@
+use crate::block::is_block_hash;
+use crate::codec;
+use crate::peer::{PackageHandler, Peer, PeerManager};
+use crate::validation::BlockError;
 use crate::{
-    Block, BlockChain, BlockInTransit, MemoryPool, Nodes, Transaction, UTXOSet, GLOBAL_CONFIG,
+    AddrBook, Block, BlockChain, BlockInTransit, ChainChange, Error, MemoryPool, NetAddr, Result,
+    Transaction, GLOBAL_CONFIG, MAX_ADDR_PER_MESSAGE,
 };
+use chrono::Utc;
 use data_encoding::HEXLOWER;
-use log::{error, info};
+use log::{error, info, warn};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
-use std::error::Error;
-use std::io::{BufReader, Write};
-use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
-use std::thread;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::runtime;
+use tokio::sync::Notify;
 use utils::coder;
 
 /// 版本硬编码
 const NODE_VERSION: usize = 1;
 
-/// 中心节点硬编码
-pub const CENTER_NODE: &str = "127.0.0.1:2001";
-
 /// 内存池中的交易到达阈值, 触发矿工挖新区块
 pub const TRANSACTION_THRESHOLD: usize = 2;
 
-/// 全网的节点地址
-static GLOBAL_NODES: Lazy<Nodes> = Lazy::new(|| {
-    let nodes = Nodes::new();
-    // 记录中心地址
-    nodes.add_node(String::from(CENTER_NODE));
-    return nodes;
-});
+/// 与其他节点之间的长连接
+static GLOBAL_PEERS: Lazy<PeerManager> = Lazy::new(PeerManager::new);
+
+/// 已知节点的地址簿，从数据目录加载
+static GLOBAL_ADDR_BOOK: Lazy<AddrBook> = Lazy::new(AddrBook::load);
 
 /// 交易内存池
-static GLOBAL_MEMORY_POOL: Lazy<MemoryPool> = Lazy::new(|| MemoryPool::new());
+static GLOBAL_MEMORY_POOL: Lazy<MemoryPool> = Lazy::new(MemoryPool::new);
 
 /// 传输中的Block, 用于来跟踪已下载的块, 这能够实现从不同的节点下载块
-static GLOBAL_BLOCKS_IN_TRANSIT: Lazy<BlockInTransit> = Lazy::new(|| BlockInTransit::new());
+static GLOBAL_BLOCKS_IN_TRANSIT: Lazy<BlockInTransit> = Lazy::new(BlockInTransit::new);
+
+/// 通知挖矿任务内存池中有新的交易
+static GLOBAL_MINING_SIGNAL: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// 网络读写超时
+const TCP_TIMEOUT: u64 = 1000;
+
+/// 阻塞线程池的最大线程数，限制同时执行的消息处理函数数量
+const MAX_BLOCKING_THREADS: usize = 64;
+
+/// 关闭节点时等待连接任务退出的时间
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 主动发起的连接数量目标，不足时从地址簿中挑选节点连接
+const TARGET_OUTBOUND: usize = 8;
+
+/// 检查连接数量并保存地址簿的间隔
+const PEER_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 命令行提交交易时最多尝试的节点数量
+const MAX_SUBMIT_ATTEMPTS: usize = 8;
 
-/// 网络写超时
-const TCP_WRITE_TIMEOUT: u64 = 1000;
 pub struct Server {
     blockchain: BlockChain,
 }
@@ -47,41 +68,159 @@ impl Server {
         Server { blockchain }
     }
 
-    //
-    pub fn start_server(&self, addr: &str) {
-        let listener = TcpListener::bind(addr).expect("连接服务器失败");
-
-        //发送version握手
-        if addr.eq(CENTER_NODE) == false {
-            let best_height = self.blockchain.get_best_height();
-            info!("send version best_height: {}", best_height);
-            send_version(CENTER_NODE, best_height);
+    /// 启动节点，收到 SIGINT 或 SIGTERM 后关闭全部连接再返回
+    pub fn start_server(&self, addr: &str) -> Result<()> {
+        let runtime = runtime::Builder::new_multi_thread()
+            .enable_all()
+            .max_blocking_threads(MAX_BLOCKING_THREADS)
+            .build()?;
+        let result = runtime.block_on(self.run(addr));
+        // 不再等待仍在阻塞线程池中执行的任务，例如正在挖的区块
+        runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+        result
+    }
+
+    /// 在异步运行时中运行节点，直到收到 SIGINT 或 SIGTERM
+    pub async fn run(&self, addr: &str) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        let handler = self.handler();
+        info!(
+            "Start node server on {}, {} known peers",
+            addr,
+            GLOBAL_ADDR_BOOK.len()
+        );
+        tokio::select! {
+            _ = accept_loop(&listener, handler.clone()) => {}
+            _ = self.maintain_peers(handler) => {}
+            _ = self.mining_loop() => {}
+            result = shutdown_signal() => result?,
+        }
+
+        info!("Shutting down node server on {}", addr);
+        GLOBAL_PEERS.shutdown();
+        if !GLOBAL_PEERS.wait_closed(SHUTDOWN_TIMEOUT).await {
+            warn!("Some peers did not close within {:?}", SHUTDOWN_TIMEOUT);
+        }
+        GLOBAL_ADDR_BOOK.save()
+    }
+
+    // 定期补足主动发起的连接并保存地址簿，启动时立即执行一次
+    async fn maintain_peers(&self, handler: PackageHandler) {
+        let mut interval = tokio::time::interval(PEER_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.connect_peers(&handler);
+            if let Err(e) = GLOBAL_ADDR_BOOK.save() {
+                warn!("Failed to save peers: {}", e);
+            }
+        }
+    }
+
+    // 挖矿任务，收到通知后在阻塞线程池中挖矿，不占用收到交易的连接
+    // 挖矿期间收到的通知会保留，挖完后再检查一次内存池
+    async fn mining_loop(&self) {
+        loop {
+            GLOBAL_MINING_SIGNAL.notified().await;
+            if GLOBAL_MEMORY_POOL.len() < TRANSACTION_THRESHOLD || !GLOBAL_CONFIG.is_miner() {
+                continue;
+            }
+            let blockchain = self.blockchain.clone();
+            match tokio::task::spawn_blocking(move || mine_memory_pool(&blockchain)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Failed to mine block: {}", e),
+                Err(e) => error!("Mining task failed: {}", e),
+            }
+        }
+    }
+
+    // 优先连接地址簿中的节点，地址簿中没有可用地址且没有任何连接时连接种子节点
+    fn connect_peers(&self, handler: &PackageHandler) {
+        let outbound = GLOBAL_PEERS
+            .get_peers()
+            .iter()
+            .filter(|peer| !peer.is_inbound())
+            .count();
+        if outbound >= TARGET_OUTBOUND {
+            return;
         }
-        info!("Start node server on {}", addr);
-        for stream in listener.incoming() {
+        let skip =
+            |addr: &str| GLOBAL_CONFIG.is_local_addr(addr) || GLOBAL_PEERS.is_connected(addr);
+        let mut addrs = GLOBAL_ADDR_BOOK.get_candidates(TARGET_OUTBOUND - outbound, skip);
+        if addrs.is_empty() && GLOBAL_PEERS.is_empty() {
+            addrs = GLOBAL_CONFIG
+                .get_seed_nodes()
+                .into_iter()
+                .filter(|addr| !skip(addr))
+                .collect();
+        }
+        for addr in addrs {
+            GLOBAL_ADDR_BOOK.mark_attempt(&addr);
+            let handler = handler.clone();
             let blockchain = self.blockchain.clone();
-            thread::spawn(|| match stream {
-                Ok(stream) => {
-                    if let Err(e) = serve(blockchain, stream) {
-                        error!("Error on serving client: {}", e);
-                    }
-                }
-                Err(err) => {
-                    error!("Connection failed: {}", err);
+            tokio::spawn(async move {
+                let result = match GLOBAL_PEERS.connect(&addr, handler).await {
+                    Ok(peer) => blockchain
+                        .get_best_height()
+                        .and_then(|best_height| send_version(&peer, best_height)),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    info!("Failed to connect to {}: {}", addr, e);
+                    GLOBAL_ADDR_BOOK.mark_failed(&addr);
                 }
             });
         }
     }
+
+    // 每个连接收到的消息都交给同一个处理函数
+    fn handler(&self) -> PackageHandler {
+        let blockchain = self.blockchain.clone();
+        Arc::new(move |peer, pkg| handle_package(&blockchain, peer, pkg))
+    }
+}
+
+// 接受连接，连接数达到上限时新的连接会被直接关闭
+async fn accept_loop(listener: &TcpListener, handler: PackageHandler) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                if let Err(e) = GLOBAL_PEERS.accept(stream, handler.clone()) {
+                    error!("Error on accepting peer: {}", e);
+                }
+            }
+            Err(err) => {
+                error!("Connection failed: {}", err);
+                // 文件描述符耗尽等错误会立即重复出现，稍等再继续
+                tokio::time::sleep(Duration::from_millis(TCP_TIMEOUT / 10)).await;
+            }
+        }
+    }
+}
+
+// 等待 SIGINT 或 SIGTERM
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
 }
 
 //判断是块还是交易
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum OpType {
     Tx,    //交易
     Block, //区块
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Package {
     Block {
         addr_from: String,
@@ -109,253 +248,532 @@ pub enum Package {
         version: usize,     //区块链版本
         best_height: usize, //区块链中节点的高度
     },
+    Ping {
+        nonce: u64,
+    },
+    Pong {
+        nonce: u64,
+    },
+    GetAddr {
+        addr_from: String,
+    },
+    Addr {
+        addr_from: String,
+        addrs: Vec<NetAddr>, //已知节点的监听地址
+    },
 }
 
-fn send_block(addr: &str, block: &Block) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
-    send_data(
-        socket_addr,
-        Package::Block {
-            addr_from: node_addr,
-            block: coder::serialized(block),
-        },
-    );
+fn send_block(peer: &Peer, block: &Block) -> Result<()> {
+    peer.send(Package::Block {
+        addr_from: GLOBAL_CONFIG.get_advertised_addr(),
+        block: coder::serialized(block),
+    })
 }
 
-pub fn send_tx(addr: &str, tx: &Transaction) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
-    send_data(
-        socket_addr,
-        Package::Tx {
-            addr_from: node_addr,
-            transaction: coder::serialized(tx),
-        },
-    );
+fn send_tx_to(peer: &Peer, tx: &Transaction) -> Result<()> {
+    peer.send(Package::Tx {
+        addr_from: GLOBAL_CONFIG.get_advertised_addr(),
+        transaction: coder::serialized(tx),
+    })
 }
 
-fn send_get_data(addr: &str, op_type: OpType, id: &[u8]) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
-    send_data(
-        socket_addr,
-        Package::GetData {
-            addr_from: node_addr,
-            op_type,
-            id: id.to_vec(),
-        },
-    );
+fn send_get_data(peer: &Peer, op_type: OpType, id: &[u8]) -> Result<()> {
+    peer.send(Package::GetData {
+        addr_from: GLOBAL_CONFIG.get_advertised_addr(),
+        op_type,
+        id: id.to_vec(),
+    })
 }
 
-fn send_inv(addr: &str, op_type: OpType, blocks: &[Vec<u8>]) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
-    send_data(
-        socket_addr,
-        Package::Inv {
-            addr_from: node_addr,
-            op_type,
-            items: blocks.to_vec(),
-        },
-    );
+fn send_inv(peer: &Peer, op_type: OpType, blocks: &[Vec<u8>]) -> Result<()> {
+    peer.send(inv_package(op_type, blocks))
 }
 
-fn send_get_blocks(addr: &str) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
-    send_data(
-        socket_addr,
-        Package::GetBlocks {
-            addr_from: node_addr,
-        },
-    );
+fn inv_package(op_type: OpType, items: &[Vec<u8>]) -> Package {
+    Package::Inv {
+        addr_from: GLOBAL_CONFIG.get_advertised_addr(),
+        op_type,
+        items: items.to_vec(),
+    }
 }
 
-fn send_version(addr: &str, height: usize) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
-    send_data(
-        socket_addr,
-        Package::Version {
-            addr_from: node_addr,
-            version: NODE_VERSION,
-            best_height: height,
-        },
-    );
+fn send_get_blocks(peer: &Peer) -> Result<()> {
+    peer.send(Package::GetBlocks {
+        addr_from: GLOBAL_CONFIG.get_advertised_addr(),
+    })
 }
 
-fn send_data(addr: SocketAddr, pkg: Package) {
-    info!("send package: {:?}", &pkg);
-    let stream = TcpStream::connect(addr);
-    if stream.is_err() {
-        error!("The {} is not valid", addr);
-        // 驱逐不健康的 Node
-        GLOBAL_NODES.evict_node(addr.to_string().as_str());
-        return;
+// 每个连接只发送一次 version
+fn send_version(peer: &Peer, height: usize) -> Result<()> {
+    if peer.mark_version_sent() {
+        return Ok(());
+    }
+    peer.send(version_package(height))
+}
+
+fn send_get_addr(peer: &Peer) -> Result<()> {
+    peer.send(Package::GetAddr {
+        addr_from: GLOBAL_CONFIG.get_advertised_addr(),
+    })
+}
+
+fn send_addr(peer: &Peer, addrs: Vec<NetAddr>) -> Result<()> {
+    peer.send(Package::Addr {
+        addr_from: GLOBAL_CONFIG.get_advertised_addr(),
+        addrs,
+    })
+}
+
+fn version_package(height: usize) -> Package {
+    Package::Version {
+        addr_from: GLOBAL_CONFIG.get_advertised_addr(),
+        version: NODE_VERSION,
+        best_height: height,
+    }
+}
+
+/// 向节点发送一笔交易后断开，用于不运行节点的命令行
+/// 先完成 version 握手，对方处理完消息关闭连接后才返回
+pub fn send_tx(addr: &str, tx: &Transaction) -> Result<()> {
+    let socket_addr: SocketAddr = addr.parse()?;
+    let timeout = Duration::from_millis(TCP_TIMEOUT);
+    let mut stream = TcpStream::connect_timeout(&socket_addr, timeout)?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.set_read_timeout(Some(timeout))?;
+    let pkg = Package::Tx {
+        addr_from: GLOBAL_CONFIG.get_advertised_addr(),
+        transaction: coder::serialized(tx),
     };
-    if let Ok(stream) = stream {
-        let mut stream = stream;
-        stream
-            .set_write_timeout(Option::from(Duration::from_millis(TCP_WRITE_TIMEOUT)))
-            .expect("set_write_timeout call failed");
-        serde_json::to_writer(&stream, &pkg).expect("data structure as JSON  failed");
-        stream.flush().expect("not all bytes could be written");
+    info!("send package: {:?}", &pkg);
+    codec::write_package(&mut stream, &version_package(0))?;
+    codec::write_package(&mut stream, &pkg)?;
+    stream.flush()?;
+    stream.shutdown(Shutdown::Write)?;
+    // 丢弃对方的回复，等待对方关闭连接，超时说明对方仍在处理，同样返回
+    let _ = io::copy(
+        &mut stream.take(codec::MAX_PAYLOAD_SIZE as u64),
+        &mut io::sink(),
+    );
+    Ok(())
+}
+
+/// 将交易提交到网络，依次尝试种子节点和地址簿中最近在线的节点，返回接收交易的节点地址
+/// 接收交易的节点会将其转发给其他节点
+pub fn submit_tx(tx: &Transaction) -> Result<String> {
+    let mut addrs = GLOBAL_CONFIG.get_seed_nodes();
+    for addr in AddrBook::load().get_addrs(MAX_SUBMIT_ATTEMPTS) {
+        if !addrs.iter().any(|known| known == addr.get_addr()) {
+            addrs.push(addr.get_addr().to_string());
+        }
+    }
+    for addr in addrs.iter().take(MAX_SUBMIT_ATTEMPTS) {
+        match send_tx(addr, tx) {
+            Ok(()) => return Ok(addr.clone()),
+            Err(e) => warn!("Failed to submit transaction to {}: {}", addr, e),
+        }
+    }
+    Err(Error::Peer(String::from(
+        "no reachable node to submit the transaction to",
+    )))
+}
+
+/// 主链变化后更新交易内存池
+/// 断开区块中的交易放回内存池，接入区块中的交易从内存池移除，再剔除与新主链冲突的交易
+fn update_memory_pool(blockchain: &BlockChain, change: &ChainChange) {
+    for block in change.get_disconnected() {
+        for tx in block.get_transactions() {
+            if !tx.is_coinbase() {
+                GLOBAL_MEMORY_POOL.add(tx.clone());
+            }
+        }
+    }
+    for block in change.get_connected() {
+        for tx in block.get_transactions() {
+            GLOBAL_MEMORY_POOL.remove(HEXLOWER.encode(tx.get_id()).as_str());
+        }
+    }
+    prune_memory_pool(blockchain);
+}
+
+// 剔除内存池中不能在当前主链上打包的交易
+fn prune_memory_pool(blockchain: &BlockChain) {
+    for tx in GLOBAL_MEMORY_POOL.get_all() {
+        if let Err(e) = blockchain.validate_transaction(&tx) {
+            let txid_hex = HEXLOWER.encode(tx.get_id());
+            info!("Drop transaction {} from memory pool: {}", txid_hex, e);
+            GLOBAL_MEMORY_POOL.remove(txid_hex.as_str());
+        }
+    }
+}
+
+// 从内存池中挑选交易挖出新区块并广播
+// 只打包互不冲突的有效交易，无效交易从内存池移除，其余交易留在内存池中
+fn mine_memory_pool(blockchain: &BlockChain) -> Result<()> {
+    // 挖矿奖励
+    let mining_address = match GLOBAL_CONFIG.get_mining_addr() {
+        Some(mining_address) => mining_address,
+        None => return Ok(()),
     };
+    let selection = blockchain.select_transactions(GLOBAL_MEMORY_POOL.get_all())?;
+    for tx in selection.get_rejected() {
+        let txid_hex = HEXLOWER.encode(tx.get_id());
+        info!("Drop invalid transaction {} from memory pool", txid_hex);
+        GLOBAL_MEMORY_POOL.remove(txid_hex.as_str());
+    }
+    if selection.get_transactions().is_empty() {
+        return Ok(());
+    }
+    // 矿工收取打包交易的手续费
+    let height = blockchain.get_best_height()? + 1;
+    let coinbase_tx =
+        Transaction::new_coinbase_tx(mining_address.as_str(), height, selection.get_fees(), &[])?;
+    let mut txs = vec![coinbase_tx];
+    txs.extend_from_slice(selection.get_transactions());
+
+    // 挖区块
+    let new_block = blockchain.mine_block(&txs)?;
+    info!("New block {} is mined!", new_block.get_hash());
+    for tx in selection.get_transactions() {
+        GLOBAL_MEMORY_POOL.remove(HEXLOWER.encode(tx.get_id()).as_str());
+    }
+    // 与新区块中的交易冲突的交易已经无法打包
+    prune_memory_pool(blockchain);
+    // 广播新区块
+    let items = vec![new_block.get_hash_bytes()];
+    GLOBAL_PEERS.broadcast(&inv_package(OpType::Block, &items), None);
+    Ok(())
 }
 
-fn serve(blockchain: BlockChain, stream: TcpStream) -> Result<(), Box<dyn Error>> {
-    let peer_addr = stream.peer_addr()?;
-    let reader = BufReader::new(&stream);
-    let pkg_reader = Deserializer::from_reader(reader).into_iter::<Package>();
-    for pkg in pkg_reader {
-        let pkg = pkg?;
-        info!("Receive request from {}: {:?}", peer_addr, pkg);
-        match pkg {
-            Package::Block { addr_from, block } => {
-                let block = coder::deserialized(block.as_slice());
-                blockchain.add_block(&block);
-                info!("Added block {}", block.get_hash());
-
-                if GLOBAL_BLOCKS_IN_TRANSIT.len() > 0 {
-                    // 继续下载区块
-                    let block_hash = GLOBAL_BLOCKS_IN_TRANSIT.first().unwrap();
-                    send_get_data(addr_from.as_str(), OpType::Block, &block_hash);
-                    // 从下载列表中移除
-                    GLOBAL_BLOCKS_IN_TRANSIT.remove(block_hash.as_slice());
-                } else {
-                    // 区块全部下载后，再重建索引
-                    let utxo_set = UTXOSet::new(blockchain.clone());
-                    utxo_set.reindex();
+// 处理一个连接收到的消息，回复通过同一个连接发送
+fn handle_package(blockchain: &BlockChain, peer: &Arc<Peer>, pkg: Package) -> Result<()> {
+    info!("Receive request from {}: {:?}", peer.get_addr(), pkg);
+    match pkg {
+        Package::Block { block, .. } => {
+            let block: Block = coder::deserialized(block.as_slice())?;
+            match blockchain.add_block(&block) {
+                Ok(change) => {
+                    info!("Added block {}", block.get_hash());
+                    update_memory_pool(blockchain, &change);
+                    // 主链变化且没有待下载的区块时，向其他节点转发新的主链末端
+                    if !change.get_connected().is_empty() && GLOBAL_BLOCKS_IN_TRANSIT.len() == 0 {
+                        let items = vec![blockchain.get_tip_hash().into_bytes()];
+                        GLOBAL_PEERS
+                            .broadcast(&inv_package(OpType::Block, &items), Some(peer.get_id()));
+                    }
+                }
+                // 缺少父区块说明落后不止一个区块，向对方请求全部区块哈希
+                Err(Error::Block(BlockError::UnknownParent)) => {
+                    info!(
+                        "Missing parent of block {}, sync with peer",
+                        block.get_hash()
+                    );
+                    send_get_blocks(peer)?;
                 }
+                Err(e) => warn!("Rejected block {}: {}", block.get_hash(), e),
             }
-            Package::GetBlocks { addr_from } => {
-                let blocks = blockchain.get_block_hashes();
-                send_inv(addr_from.as_str(), OpType::Block, &blocks);
+
+            if let Some(block_hash) = GLOBAL_BLOCKS_IN_TRANSIT.first() {
+                // 继续下载区块
+                send_get_data(peer, OpType::Block, &block_hash)?;
+                // 从下载列表中移除
+                GLOBAL_BLOCKS_IN_TRANSIT.remove(block_hash.as_slice());
             }
-            //某个块或交易的请求，它可以仅包含一个块或交易的 ID
-            Package::GetData {
-                addr_from,
-                op_type,
-                id,
-            } => match op_type {
-                OpType::Block => {
-                    if let Some(block) = blockchain.get_block(id.as_slice()) {
-                        send_block(addr_from.as_str(), &block);
-                    }
+        }
+        Package::GetBlocks { .. } => {
+            let blocks = blockchain.get_block_hashes()?;
+            send_inv(peer, OpType::Block, &blocks)?;
+        }
+        //某个块或交易的请求，它可以仅包含一个块或交易的 ID
+        Package::GetData { op_type, id, .. } => match op_type {
+            OpType::Block if is_block_hash(id.as_slice()) => {
+                if let Some(block) = blockchain.get_block(id.as_slice())? {
+                    send_block(peer, &block)?;
+                }
+            }
+            OpType::Tx => {
+                let txid_hex = HEXLOWER.encode(id.as_slice());
+                if let Some(tx) = GLOBAL_MEMORY_POOL.get(txid_hex.as_str()) {
+                    send_tx_to(peer, &tx)?;
                 }
-                OpType::Tx => {
-                    let txid_hex = HEXLOWER.encode(id.as_slice());
-                    if let Some(tx) = GLOBAL_MEMORY_POOL.get(txid_hex.as_str()) {
-                        send_tx(addr_from.as_str(), &tx);
+            }
+            OpType::Block => {
+                return Err(Error::Peer(String::from(
+                    "getdata with malformed block hash",
+                )));
+            }
+        },
+        Package::Inv { op_type, items, .. } => match op_type {
+            // 两种触发情况：
+            //  1. 当 version 消息检查到区块高度落后，会收到全量的 block hash 列表。
+            //  2. 节点挖出或收到新的区块后，会将新区块的 hash 转发给其他节点。
+            OpType::Block => {
+                // 只下载本地没有的区块
+                let mut unknown = vec![];
+                for block_hash in items {
+                    if !is_block_hash(block_hash.as_slice()) {
+                        return Err(Error::Peer(String::from("inv with malformed block hash")));
+                    }
+                    if blockchain.get_block(block_hash.as_slice())?.is_none() {
+                        unknown.push(block_hash);
                     }
                 }
-            },
-            Package::Inv {
-                addr_from,
-                op_type,
-                items,
-            } => match op_type {
-                // 两种触发情况：
-                //  1. 当 version 消息检查到区块高度落后，会收到全量的 block hash 列表。
-                //  2. 矿工挖出新的区块后，会将新区块的 hash 广播给所有节点。
-                OpType::Block => {
-                    // 初始启动才会触发，不可能有存量数据
-                    GLOBAL_BLOCKS_IN_TRANSIT.add_blocks(items.as_slice());
-
-                    // 下载一个区块
-                    let block_hash = items.get(0).unwrap();
-                    send_get_data(addr_from.as_str(), OpType::Block, block_hash);
+                let items = unknown;
+                GLOBAL_BLOCKS_IN_TRANSIT.add_blocks(items.as_slice());
+
+                // 下载一个区块
+                if let Some(block_hash) = items.first() {
+                    send_get_data(peer, OpType::Block, block_hash)?;
                     // 从下载列表中移除
                     GLOBAL_BLOCKS_IN_TRANSIT.remove(block_hash);
                 }
-                OpType::Tx => {
-                    let txid = items.get(0).unwrap();
+            }
+            OpType::Tx => {
+                for txid in &items {
                     let txid_hex = HEXLOWER.encode(txid);
 
                     // 检查交易池，不包含交易则下载
-                    if GLOBAL_MEMORY_POOL.contain(txid_hex.as_str()) == false {
-                        send_get_data(addr_from.as_str(), OpType::Tx, txid);
-                    }
-                }
-            },
-            Package::Tx {
-                addr_from,
-                transaction,
-            } => {
-                // 记录交易到内存池
-                let tx: Transaction = coder::deserialized(&transaction);
-                let txid = tx.get_id_bytes();
-                GLOBAL_MEMORY_POOL.add(tx);
-
-                let node_addr = GLOBAL_CONFIG.get_node_addr();
-                // 中心节点并不会挖矿。它只会将新的交易推送给网络中的其他节点（广播交易）
-                if node_addr.eq(CENTER_NODE) {
-                    let nodes = GLOBAL_NODES.get_nodes();
-                    for node in &nodes {
-                        if node_addr.eq(node.get_addr().as_str()) {
-                            continue;
-                        }
-                        if addr_from.eq(node.get_addr().as_str()) {
-                            continue;
-                        }
-                        send_inv(node.get_addr().as_str(), OpType::Tx, &vec![txid.clone()])
-                    }
-                }
-                // 矿工节点（内存池中的交易到达一定数量，挖出新区块）
-                if GLOBAL_MEMORY_POOL.len() >= TRANSACTION_THRESHOLD && GLOBAL_CONFIG.is_miner() {
-                    // 挖矿奖励
-                    let mining_address = GLOBAL_CONFIG.get_mining_addr().unwrap();
-                    let coinbase_tx = Transaction::new_coinbase_tx(mining_address.as_str());
-                    let mut txs = GLOBAL_MEMORY_POOL.get_all();
-                    txs.push(coinbase_tx);
-
-                    // 挖区块
-                    let new_block = blockchain.mine_block(&txs);
-                    let utxo_set = UTXOSet::new(blockchain.clone());
-                    utxo_set.reindex();
-                    info!("New block {} is mined!", new_block.get_hash());
-
-                    // 从内存池中移除交易
-                    for tx in &txs {
-                        let txid_hex = HEXLOWER.encode(tx.get_id());
-                        GLOBAL_MEMORY_POOL.remove(txid_hex.as_str());
-                    }
-                    // 广播新区块
-                    let nodes = GLOBAL_NODES.get_nodes();
-                    for node in &nodes {
-                        if node_addr.eq(node.get_addr().as_str()) {
-                            continue;
-                        }
-                        send_inv(
-                            node.get_addr().as_str(),
-                            OpType::Block,
-                            &vec![new_block.get_hash_bytes()],
-                        );
+                    if !GLOBAL_MEMORY_POOL.contain(txid_hex.as_str()) {
+                        send_get_data(peer, OpType::Tx, txid)?;
                     }
                 }
             }
-            Package::Version {
-                addr_from,
-                version,
-                best_height,
-            } => {
-                info!("version = {}, best_height = {}", version, best_height);
-                let local_best_height = blockchain.get_best_height();
-                //从消息中提取的 BestHeight 与自身进行比较.如果自身节点的区块链更长，它会回复 version 消息；否则，它会发送 get_blocks 消息。
-                if local_best_height < best_height {
-                    send_get_blocks(addr_from.as_str());
-                } else {
-                    send_version(addr_from.as_str(), blockchain.get_best_height());
-                }
+        },
+        Package::Tx { transaction, .. } => {
+            // 记录交易到内存池，不能在下一个区块中打包的交易（包括未到期的时间锁）直接丢弃
+            let tx: Transaction = coder::deserialized(&transaction)?;
+            let txid = tx.get_id_bytes();
+            // 已经在内存池中的交易已经转发过，不再处理，避免交易在节点之间循环转发
+            if GLOBAL_MEMORY_POOL.contain(HEXLOWER.encode(&txid).as_str()) {
+                return Ok(());
+            }
+            if let Err(e) = blockchain.validate_transaction(&tx) {
+                warn!("Rejected transaction {}: {}", HEXLOWER.encode(&txid), e);
+                return Ok(());
+            }
+            GLOBAL_MEMORY_POOL.add(tx);
 
-                // 记录节点地址
-                if GLOBAL_NODES.node_is_known(peer_addr.to_string().as_str()) == false {
-                    GLOBAL_NODES.add_node(addr_from);
-                }
+            // 每个节点都将通过校验的新交易转发给除来源之外的其他节点（广播交易）
+            let items = vec![txid.clone()];
+            GLOBAL_PEERS.broadcast(&inv_package(OpType::Tx, &items), Some(peer.get_id()));
+            // 矿工节点（内存池中的交易到达一定数量，通知挖矿任务挖出新区块）
+            if GLOBAL_MEMORY_POOL.len() >= TRANSACTION_THRESHOLD && GLOBAL_CONFIG.is_miner() {
+                GLOBAL_MINING_SIGNAL.notify_one();
+            }
+        }
+        // ping 和 pong 由连接自己处理
+        Package::Ping { .. } | Package::Pong { .. } => {}
+        Package::GetAddr { .. } => {
+            send_addr(peer, GLOBAL_ADDR_BOOK.get_addrs(MAX_ADDR_PER_MESSAGE))?;
+        }
+        Package::Addr { addrs, .. } => {
+            if addrs.len() > MAX_ADDR_PER_MESSAGE {
+                return Err(Error::Peer(format!(
+                    "addr message with {} addresses exceeds {}",
+                    addrs.len(),
+                    MAX_ADDR_PER_MESSAGE
+                )));
+            }
+            let added = addrs
+                .iter()
+                .filter(|addr| !GLOBAL_CONFIG.is_local_addr(addr.get_addr()))
+                .filter(|addr| GLOBAL_ADDR_BOOK.add(addr))
+                .count();
+            info!("Learned {} new peer addresses", added);
+        }
+        Package::Version {
+            addr_from,
+            version,
+            best_height,
+        } => {
+            info!("version = {}, best_height = {}", version, best_height);
+            // 记录对方的监听地址，握手完成
+            peer.set_established(addr_from.as_str());
+            let local_best_height = blockchain.get_best_height()?;
+            // 对方发起的连接还没有发送过 version，回复自己的 version
+            send_version(peer, local_best_height)?;
+            if !peer.is_inbound() {
+                // 主动连接成功说明地址可达，并向对方请求其已知的地址
+                GLOBAL_ADDR_BOOK.mark_good(peer.get_addr().to_string().as_str());
+                send_get_addr(peer)?;
+            }
+            // 向对方通告自己的监听地址，配置了外部地址时通告外部地址
+            let local_addr =
+                NetAddr::new(GLOBAL_CONFIG.get_advertised_addr(), Utc::now().timestamp());
+            send_addr(peer, vec![local_addr])?;
+            //从消息中提取的 BestHeight 与自身进行比较，如果对方的区块链更长，发送 get_blocks 消息。
+            if local_best_height < best_height {
+                send_get_blocks(peer)?;
             }
         }
     }
-    let _ = stream.shutdown(Shutdown::Both);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Amount, MemoryStore, PartiallySignedTransaction, TXInput, TXOutput, Wallet};
+    use tokio::io::BufReader;
+    use tokio::time;
+
+    const TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+    type Client = BufReader<tokio::net::TcpStream>;
+
+    async fn send(client: &mut Client, pkg: &Package) {
+        codec::write_package_async(client.get_mut(), pkg)
+            .await
+            .unwrap();
+    }
+
+    async fn recv(client: &mut Client) -> Package {
+        time::timeout(TEST_TIMEOUT, codec::read_package_async(client))
+            .await
+            .expect("no package received")
+            .unwrap()
+            .expect("connection closed")
+    }
+
+    async fn wait_until<F: Fn() -> bool>(condition: F) {
+        time::timeout(TEST_TIMEOUT, async {
+            while !condition() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("condition not met");
+    }
+
+    // 连接到节点并完成握手，丢弃节点回复的 version 和 addr
+    async fn connect(listener: &TcpListener, handler: &PackageHandler, port: u16) -> Client {
+        let stream = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        GLOBAL_PEERS.accept(accepted, handler.clone()).unwrap();
+        let mut client = BufReader::new(stream);
+        let version = Package::Version {
+            addr_from: format!("127.0.0.1:{}", port),
+            version: NODE_VERSION,
+            best_height: 0,
+        };
+        send(&mut client, &version).await;
+        assert!(matches!(recv(&mut client).await, Package::Version { .. }));
+        assert!(matches!(recv(&mut client).await, Package::Addr { .. }));
+        client
+    }
+
+    // 向全部节点广播标记消息，每个 client 收到的下一条消息都应当是该标记，说明之前没有收到其他消息
+    async fn assert_nothing_received(clients: &mut [&mut Client], nonce: u64) {
+        GLOBAL_PEERS.broadcast(&Package::Ping { nonce }, None);
+        for client in clients.iter_mut() {
+            assert!(matches!(recv(client).await, Package::Ping { nonce: n } if n == nonce));
+        }
+    }
+
+    fn inv_items(pkg: Package, expected: OpType) -> Vec<Vec<u8>> {
+        match (pkg, expected) {
+            (
+                Package::Inv {
+                    op_type: OpType::Tx,
+                    items,
+                    ..
+                },
+                OpType::Tx,
+            )
+            | (
+                Package::Inv {
+                    op_type: OpType::Block,
+                    items,
+                    ..
+                },
+                OpType::Block,
+            ) => items,
+            (pkg, _) => panic!("unexpected package {:?}", pkg),
+        }
+    }
+
+    #[tokio::test]
+    async fn relay_to_other_peers_without_echo() {
+        let wallet = Wallet::new().unwrap();
+        let store = Arc::new(MemoryStore::new());
+        let blockchain = BlockChain::create_with_store(store, &wallet.get_address()).unwrap();
+        let genesis_hash = blockchain.get_tip_hash();
+        let genesis = blockchain
+            .get_block(genesis_hash.as_bytes())
+            .unwrap()
+            .unwrap();
+        let handler: PackageHandler = {
+            let blockchain = blockchain.clone();
+            Arc::new(move |peer, pkg| handle_package(&blockchain, peer, pkg))
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client_a = connect(&listener, &handler, 3001).await;
+        let mut client_b = connect(&listener, &handler, 3002).await;
+        let mut client_c = connect(&listener, &handler, 3003).await;
+        wait_until(|| GLOBAL_PEERS.get_established().len() == 3).await;
+
+        // a 发来的新交易通过 inv 转发给 b 和 c
+        let prev_tx = &genesis.get_transactions()[0];
+        let tx = Transaction::new(
+            vec![TXInput::new(prev_tx.get_id(), 0)],
+            vec![TXOutput::new(Amount::from_coins(1).unwrap(), &wallet.get_address()).unwrap()],
+        );
+        let mut psbt = PartiallySignedTransaction::new(tx, prev_tx.get_vout().to_vec()).unwrap();
+        psbt.sign(&wallet).unwrap();
+        let tx = psbt.finalize().unwrap();
+        let pkg = Package::Tx {
+            addr_from: String::from("127.0.0.1:3001"),
+            transaction: coder::serialized(&tx),
+        };
+        send(&mut client_a, &pkg).await;
+        let txid = tx.get_id_bytes();
+        assert_eq!(
+            inv_items(recv(&mut client_b).await, OpType::Tx),
+            vec![txid.clone()]
+        );
+        assert_eq!(inv_items(recv(&mut client_c).await, OpType::Tx), vec![txid]);
+        assert_nothing_received(&mut [&mut client_a, &mut client_b, &mut client_c], 1).await;
+
+        // 同一笔交易再次收到时不再转发
+        send(&mut client_b, &pkg).await;
+        // 消息按顺序处理，收到 pong 说明交易已经处理完
+        send(&mut client_b, &Package::Ping { nonce: 9 }).await;
+        assert!(matches!(
+            recv(&mut client_b).await,
+            Package::Pong { nonce: 9 }
+        ));
+        assert_nothing_received(&mut [&mut client_a, &mut client_b, &mut client_c], 2).await;
+
+        // b 发来的新区块通过 inv 转发给 a 和 c
+        let coinbase_tx =
+            Transaction::new_coinbase_tx(&wallet.get_address(), 1, Amount::ZERO, &[]).unwrap();
+        let bits = blockchain.get_next_work_required(&genesis).unwrap();
+        let block =
+            Block::new_block(&[coinbase_tx, tx], genesis.get_hash().to_string(), 1, bits).unwrap();
+        send(
+            &mut client_b,
+            &Package::Block {
+                addr_from: String::from("127.0.0.1:3002"),
+                block: coder::serialized(&block),
+            },
+        )
+        .await;
+        let hash = block.get_hash().as_bytes().to_vec();
+        assert_eq!(
+            inv_items(recv(&mut client_a).await, OpType::Block),
+            vec![hash.clone()]
+        );
+        assert_eq!(
+            inv_items(recv(&mut client_c).await, OpType::Block),
+            vec![hash]
+        );
+        assert_nothing_received(&mut [&mut client_a, &mut client_b, &mut client_c], 3).await;
+        assert_eq!(blockchain.get_tip_hash(), block.get_hash());
+        assert_eq!(GLOBAL_MEMORY_POOL.len(), 0);
+
+        drop((client_a, client_b, client_c));
+        wait_until(|| GLOBAL_PEERS.is_empty()).await;
+    }
+}
@@ -0,0 +1,392 @@
+use crate::block::Block;
+use crate::blockchain::BlockIndex;
+use crate::config::GLOBAL_CONFIG;
+use crate::error::{Error, Result};
+use crate::store::{AddressTx, ChainStateUpdate, ChainStore, TxLocation};
+use crate::utxo::{outpoint_key, script_key, UTXOEntry};
+use dotenv::dotenv;
+use sled::transaction::{ConflictableTransactionError, TransactionResult};
+use sled::{Db, IVec, Transactional};
+use std::env;
+use std::path::Path;
+use utils::coder;
+
+const TIP_BLOCK_HASH_KEY: &str = "tip_block_hash";
+const REORG_TARGET_KEY: &str = "reorg_target";
+const TX_INDEX_KEY: &str = "tx_index";
+const ADDRESS_INDEX_KEY: &str = "address_index";
+/// 链尾、切换目标和索引标记，旧版本的数据库把它们和区块一起保存在 blocks 中
+const META_KEYS: [&str; 4] = [
+    TIP_BLOCK_HASH_KEY,
+    REORG_TARGET_KEY,
+    TX_INDEX_KEY,
+    ADDRESS_INDEX_KEY,
+];
+const META_TREE: &str = "meta";
+const BLOCKS_TREE: &str = "blocks";
+const BLOCK_INDEX_TREE: &str = "block_index";
+const HEIGHT_INDEX_TREE: &str = "height_index";
+const TX_INDEX_TREE: &str = "tx_index";
+const ADDRESS_UTXO_TREE: &str = "address_utxo";
+const ADDRESS_HISTORY_TREE: &str = "address_history";
+const UTXO_TREE: &str = "chainstate";
+const UNDO_TREE: &str = "undo";
+
+/// 基于 sled 的存储后端，数据保存在磁盘上
+#[derive(Debug, Clone)]
+pub struct SledStore {
+    db: Db,
+}
+
+impl SledStore {
+    /// 打开指定路径的数据库，不存在时创建
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SledStore> {
+        SledStore::with_db(sled::open(path)?)
+    }
+
+    fn with_db(db: Db) -> Result<SledStore> {
+        let store = SledStore { db };
+        store.migrate_meta()?;
+        Ok(store)
+    }
+
+    // 将旧版本保存在 blocks 中的元数据移到 meta 中，blocks 中只保留区块
+    // 否则以对方提供的哈希查找区块时，元数据会被当作区块解码
+    fn migrate_meta(&self) -> Result<()> {
+        let blocks_tree = self.db.open_tree(BLOCKS_TREE)?;
+        let meta_tree = self.db.open_tree(META_TREE)?;
+        let result: TransactionResult<(), Error> =
+            (&blocks_tree, &meta_tree).transaction(|(tx_blocks, tx_meta)| {
+                for key in META_KEYS {
+                    if let Some(value) = tx_blocks.remove(key)? {
+                        tx_meta.insert(key, value)?;
+                    }
+                }
+                Ok(())
+            });
+        Ok(result?)
+    }
+
+    /// 打开数据目录下环境变量 DBName 指定的数据库
+    pub fn from_env() -> Result<SledStore> {
+        dotenv().ok();
+        let key = "DBName";
+        let name = env::var(key).map_err(|_| Error::MissingEnv(key))?;
+        SledStore::open(GLOBAL_CONFIG.get_data_dir().join(name))
+    }
+
+    // 读取保存为字符串的区块哈希
+    fn get_hash(&self, key: &str) -> Result<Option<String>> {
+        let meta_tree = self.db.open_tree(META_TREE)?;
+        decode_hash(meta_tree.get(key)?)
+    }
+}
+
+// 高度索引的键：大端序的高度，按高度排序
+fn height_key(height: usize) -> [u8; 8] {
+    (height as u64).to_be_bytes()
+}
+
+// 地址索引中未花费输出的键：脚本哈希 + 输出点，同一地址的输出相邻
+fn address_utxo_key(entry: &UTXOEntry) -> Vec<u8> {
+    let mut key = script_key(entry.get_output().get_script_pub_key());
+    key.extend(entry.key());
+    key
+}
+
+// 地址索引中交易历史的键：脚本哈希 + 大端序的高度 + txid，同一地址的交易按高度排序
+fn address_history_key(script_key: &[u8], height: usize, txid: &[u8]) -> Vec<u8> {
+    let mut key = script_key.to_vec();
+    key.extend(height_key(height));
+    key.extend(txid);
+    key
+}
+
+fn decode_hash(hash_bytes: Option<IVec>) -> Result<Option<String>> {
+    match hash_bytes {
+        Some(hash_bytes) => String::from_utf8(hash_bytes.to_vec())
+            .map(Some)
+            .map_err(|_| Error::Corrupted(String::from("block hash is not valid utf-8"))),
+        None => Ok(None),
+    }
+}
+
+impl ChainStore for SledStore {
+    fn get_block(&self, block_hash: &str) -> Result<Option<Block>> {
+        let blocks_tree = self.db.open_tree(BLOCKS_TREE)?;
+        match blocks_tree.get(block_hash)? {
+            Some(block_bytes) => Ok(Some(coder::deserialized(block_bytes.as_ref())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_block(&self, block: &Block) -> Result<()> {
+        let blocks_tree = self.db.open_tree(BLOCKS_TREE)?;
+        blocks_tree.insert(block.get_hash(), coder::serialized(block))?;
+        Ok(())
+    }
+
+    fn get_block_index(&self, block_hash: &str) -> Result<Option<BlockIndex>> {
+        let index_tree = self.db.open_tree(BLOCK_INDEX_TREE)?;
+        match index_tree.get(block_hash)? {
+            Some(index_bytes) => Ok(Some(coder::deserialized(index_bytes.as_ref())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_block_index(&self, block_hash: &str, index: &BlockIndex) -> Result<()> {
+        let index_tree = self.db.open_tree(BLOCK_INDEX_TREE)?;
+        index_tree.insert(block_hash, coder::serialized(index))?;
+        Ok(())
+    }
+
+    fn get_block_indexes(&self) -> Result<Vec<(String, BlockIndex)>> {
+        let index_tree = self.db.open_tree(BLOCK_INDEX_TREE)?;
+        let mut indexes = vec![];
+        for item in index_tree.iter() {
+            let (k, v) = item?;
+            if let Some(block_hash) = decode_hash(Some(k))? {
+                indexes.push((block_hash, coder::deserialized(v.as_ref())?));
+            }
+        }
+        Ok(indexes)
+    }
+
+    fn get_tip(&self) -> Result<Option<String>> {
+        self.get_hash(TIP_BLOCK_HASH_KEY)
+    }
+
+    fn get_block_hash_by_height(&self, height: usize) -> Result<Option<String>> {
+        let height_tree = self.db.open_tree(HEIGHT_INDEX_TREE)?;
+        decode_hash(height_tree.get(height_key(height))?)
+    }
+
+    fn reset_height_index(&self, block_hashes: &[String]) -> Result<()> {
+        let height_tree = self.db.open_tree(HEIGHT_INDEX_TREE)?;
+        height_tree.clear()?;
+        for (height, block_hash) in block_hashes.iter().enumerate() {
+            height_tree.insert(height_key(height), block_hash.as_str())?;
+        }
+        Ok(())
+    }
+
+    fn get_reorg_target(&self) -> Result<Option<String>> {
+        self.get_hash(REORG_TARGET_KEY)
+    }
+
+    fn set_reorg_target(&self, block_hash: Option<&str>) -> Result<()> {
+        let meta_tree = self.db.open_tree(META_TREE)?;
+        match block_hash {
+            Some(block_hash) => meta_tree.insert(REORG_TARGET_KEY, block_hash)?,
+            None => meta_tree.remove(REORG_TARGET_KEY)?,
+        };
+        Ok(())
+    }
+
+    fn get_utxo(&self, txid: &[u8], vout: usize) -> Result<Option<UTXOEntry>> {
+        let utxo_tree = self.db.open_tree(UTXO_TREE)?;
+        match utxo_tree.get(outpoint_key(txid, vout))? {
+            Some(entry_bytes) => Ok(Some(coder::deserialized(entry_bytes.as_ref())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_utxos(&self) -> Result<Vec<UTXOEntry>> {
+        let utxo_tree = self.db.open_tree(UTXO_TREE)?;
+        let mut entries = vec![];
+        for item in utxo_tree.iter() {
+            let (_, v) = item?;
+            entries.push(coder::deserialized(v.as_ref())?);
+        }
+        Ok(entries)
+    }
+
+    fn get_undo(&self, block_hash: &str) -> Result<Option<Vec<UTXOEntry>>> {
+        let undo_tree = self.db.open_tree(UNDO_TREE)?;
+        match undo_tree.get(block_hash)? {
+            Some(undo_bytes) => Ok(Some(coder::deserialized(undo_bytes.as_ref())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn has_tx_index(&self) -> Result<bool> {
+        let meta_tree = self.db.open_tree(META_TREE)?;
+        Ok(meta_tree.contains_key(TX_INDEX_KEY)?)
+    }
+
+    fn get_tx_location(&self, txid: &[u8]) -> Result<Option<TxLocation>> {
+        let tx_index_tree = self.db.open_tree(TX_INDEX_TREE)?;
+        match tx_index_tree.get(txid)? {
+            Some(location_bytes) => Ok(Some(coder::deserialized(location_bytes.as_ref())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn reset_tx_index(&self, locations: Option<&[(Vec<u8>, TxLocation)]>) -> Result<()> {
+        let meta_tree = self.db.open_tree(META_TREE)?;
+        let tx_index_tree = self.db.open_tree(TX_INDEX_TREE)?;
+        // 先清除标记，重建中途退出时不会把不完整的索引当作可用
+        meta_tree.remove(TX_INDEX_KEY)?;
+        tx_index_tree.clear()?;
+        if let Some(locations) = locations {
+            for (txid, location) in locations {
+                tx_index_tree.insert(txid.as_slice(), coder::serialized(location))?;
+            }
+            meta_tree.insert(TX_INDEX_KEY, &[1u8])?;
+        }
+        Ok(())
+    }
+
+    fn get_address_utxos(&self, script_key: &[u8]) -> Result<Vec<UTXOEntry>> {
+        let address_tree = self.db.open_tree(ADDRESS_UTXO_TREE)?;
+        let utxo_tree = self.db.open_tree(UTXO_TREE)?;
+        let mut entries = vec![];
+        for item in address_tree.scan_prefix(script_key) {
+            let (k, _) = item?;
+            let entry_bytes = utxo_tree
+                .get(&k[script_key.len()..])?
+                .ok_or_else(|| Error::Corrupted(String::from("address index out of date")))?;
+            entries.push(coder::deserialized(entry_bytes.as_ref())?);
+        }
+        Ok(entries)
+    }
+
+    fn get_address_history(&self, script_key: &[u8]) -> Result<Vec<AddressTx>> {
+        let history_tree = self.db.open_tree(ADDRESS_HISTORY_TREE)?;
+        let mut history = vec![];
+        let txid_start = script_key.len() + 8;
+        for item in history_tree.scan_prefix(script_key) {
+            let (k, _) = item?;
+            if k.len() < txid_start {
+                return Err(Error::Corrupted(String::from("bad address history key")));
+            }
+            let mut height = [0u8; 8];
+            height.copy_from_slice(&k[script_key.len()..txid_start]);
+            let height = u64::from_be_bytes(height) as usize;
+            history.push(AddressTx::new(height, &k[txid_start..]));
+        }
+        Ok(history)
+    }
+
+    fn has_address_index(&self) -> Result<bool> {
+        let meta_tree = self.db.open_tree(META_TREE)?;
+        Ok(meta_tree.contains_key(ADDRESS_INDEX_KEY)?)
+    }
+
+    fn reset_address_history(&self, history: &[(Vec<u8>, AddressTx)]) -> Result<()> {
+        let meta_tree = self.db.open_tree(META_TREE)?;
+        let history_tree = self.db.open_tree(ADDRESS_HISTORY_TREE)?;
+        meta_tree.remove(ADDRESS_INDEX_KEY)?;
+        history_tree.clear()?;
+        for (script_key, tx) in history {
+            let key = address_history_key(script_key, tx.get_height(), tx.get_txid());
+            history_tree.insert(key, &[])?;
+        }
+        meta_tree.insert(ADDRESS_INDEX_KEY, &[1u8])?;
+        Ok(())
+    }
+
+    // 链尾、各个索引、UTXO 集和撤销数据在同一个数据库事务中更新，不会出现不一致的状态
+    fn write_chainstate(&self, update: &ChainStateUpdate) -> Result<()> {
+        let meta_tree = self.db.open_tree(META_TREE)?;
+        let height_tree = self.db.open_tree(HEIGHT_INDEX_TREE)?;
+        let tx_index_tree = self.db.open_tree(TX_INDEX_TREE)?;
+        let address_tree = self.db.open_tree(ADDRESS_UTXO_TREE)?;
+        let history_tree = self.db.open_tree(ADDRESS_HISTORY_TREE)?;
+        let utxo_tree = self.db.open_tree(UTXO_TREE)?;
+        let undo_tree = self.db.open_tree(UNDO_TREE)?;
+        let trees = (
+            &meta_tree,
+            &height_tree,
+            &tx_index_tree,
+            &address_tree,
+            &history_tree,
+            &utxo_tree,
+            &undo_tree,
+        );
+        let result: TransactionResult<(), Error> = trees.transaction(
+            |(tx_meta, tx_height, tx_txids, tx_address, tx_history, tx_utxo, tx_undo)| {
+                for (txid, vout) in update.get_removed_utxos() {
+                    // 删除的输出锁定的脚本只能从 UTXO 集中取得
+                    if let Some(entry_bytes) = tx_utxo.remove(outpoint_key(txid, *vout))? {
+                        let entry: UTXOEntry = coder::deserialized(entry_bytes.as_ref())
+                            .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+                        tx_address.remove(address_utxo_key(&entry))?;
+                    }
+                }
+                for entry in update.get_added_utxos() {
+                    tx_utxo.insert(entry.key(), coder::serialized(entry))?;
+                    tx_address.insert(address_utxo_key(entry), &[])?;
+                }
+                for (script_key, txid) in update.get_history() {
+                    let key = address_history_key(script_key, update.get_height(), txid);
+                    match update.is_connect() {
+                        true => tx_history.insert(key, &[])?,
+                        false => tx_history.remove(key)?,
+                    };
+                }
+                let height = height_key(update.get_height());
+                match update.get_undo() {
+                    Some(spent) => {
+                        tx_undo.insert(update.get_block_hash(), coder::serialized(&spent))?;
+                        tx_height.insert(&height, update.get_block_hash())?;
+                    }
+                    None => {
+                        tx_undo.remove(update.get_block_hash())?;
+                        tx_height.remove(&height)?;
+                    }
+                }
+                for (position, txid) in update.get_txids().iter().enumerate() {
+                    match update.is_connect() {
+                        true => {
+                            let location = TxLocation::new(update.get_block_hash(), position);
+                            tx_txids.insert(txid.as_slice(), coder::serialized(&location))?;
+                        }
+                        false => {
+                            tx_txids.remove(txid.as_slice())?;
+                        }
+                    }
+                }
+                tx_meta.insert(TIP_BLOCK_HASH_KEY, update.get_tip_hash())?;
+                Ok(())
+            },
+        );
+        Ok(result?)
+    }
+
+    fn reset_utxos(&self, entries: &[UTXOEntry]) -> Result<()> {
+        let utxo_tree = self.db.open_tree(UTXO_TREE)?;
+        let address_tree = self.db.open_tree(ADDRESS_UTXO_TREE)?;
+        utxo_tree.clear()?; //清空utxo数据集
+        address_tree.clear()?;
+        for entry in entries {
+            utxo_tree.insert(entry.key(), coder::serialized(entry))?;
+            address_tree.insert(address_utxo_key(entry), &[])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrate_meta_out_of_blocks_tree() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let blocks_tree = db.open_tree(BLOCKS_TREE).unwrap();
+        let tip_hash = "00".repeat(32);
+        blocks_tree
+            .insert(TIP_BLOCK_HASH_KEY, tip_hash.as_str())
+            .unwrap();
+        blocks_tree.insert(TX_INDEX_KEY, &[1u8]).unwrap();
+
+        let store = SledStore::with_db(db).unwrap();
+        assert_eq!(store.get_tip().unwrap(), Some(tip_hash));
+        assert!(store.has_tx_index().unwrap());
+        assert!(!store.has_address_index().unwrap());
+        // blocks 中只剩下区块，以元数据的键查找区块不会解码失败
+        assert!(store.get_block(TIP_BLOCK_HASH_KEY).unwrap().is_none());
+        assert!(store.get_block(TX_INDEX_KEY).unwrap().is_none());
+    }
+}
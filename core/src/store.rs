@@ -0,0 +1,204 @@
+use crate::block::Block;
+use crate::blockchain::BlockIndex;
+use crate::error::Result;
+use crate::utxo::UTXOEntry;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+
+/// 区块链的存储后端，保存区块、区块索引、链尾和链状态（UTXO 集和撤销数据）
+/// 链状态只通过 write_chainstate 整体修改，后端必须保证一次修改是原子的
+pub trait ChainStore: Debug + Send + Sync {
+    /// 通过区块哈希查询区块
+    fn get_block(&self, block_hash: &str) -> Result<Option<Block>>;
+
+    /// 保存区块，包括侧链上的区块
+    fn put_block(&self, block: &Block) -> Result<()>;
+
+    /// 查询区块索引
+    fn get_block_index(&self, block_hash: &str) -> Result<Option<BlockIndex>>;
+
+    /// 保存区块索引
+    fn put_block_index(&self, block_hash: &str, index: &BlockIndex) -> Result<()>;
+
+    /// 全部区块索引及对应的区块哈希，包括侧链区块
+    fn get_block_indexes(&self) -> Result<Vec<(String, BlockIndex)>>;
+
+    /// 主链链尾的区块哈希，还没有创世块时返回 None
+    fn get_tip(&self) -> Result<Option<String>>;
+
+    /// 主链上指定高度的区块哈希
+    fn get_block_hash_by_height(&self, height: usize) -> Result<Option<String>>;
+
+    /// 用给定的区块哈希替换整个高度索引，第 i 个哈希的高度为 i，用于重建
+    fn reset_height_index(&self, block_hashes: &[String]) -> Result<()>;
+
+    /// 正在切换主链的目标区块
+    fn get_reorg_target(&self) -> Result<Option<String>>;
+
+    /// 记录正在切换主链的目标区块，None 表示切换已完成
+    fn set_reorg_target(&self, block_hash: Option<&str>) -> Result<()>;
+
+    /// 查找交易的第 vout 个输出，不存在或已花费时返回 None
+    fn get_utxo(&self, txid: &[u8], vout: usize) -> Result<Option<UTXOEntry>>;
+
+    /// 全部未花费输出，按 (txid, vout) 排序，同一交易的输出相邻
+    fn get_utxos(&self) -> Result<Vec<UTXOEntry>>;
+
+    /// 区块的撤销数据，即区块花费的输出
+    fn get_undo(&self, block_hash: &str) -> Result<Option<Vec<UTXOEntry>>>;
+
+    /// 是否维护交易索引
+    fn has_tx_index(&self) -> Result<bool>;
+
+    /// 通过交易ID查询交易在主链上的位置，没有交易索引时总是返回 None
+    fn get_tx_location(&self, txid: &[u8]) -> Result<Option<TxLocation>>;
+
+    /// 用给定的交易位置替换整个交易索引并开始维护，None 表示删除交易索引并停止维护
+    fn reset_tx_index(&self, locations: Option<&[(Vec<u8>, TxLocation)]>) -> Result<()>;
+
+    /// 锁定到指定脚本的未花费输出，键为 script_key 计算的脚本哈希
+    fn get_address_utxos(&self, script_key: &[u8]) -> Result<Vec<UTXOEntry>>;
+
+    /// 与指定脚本相关的主链交易，按高度从低到高排列
+    fn get_address_history(&self, script_key: &[u8]) -> Result<Vec<AddressTx>>;
+
+    /// 是否已经建立地址索引的交易历史部分，旧版本的数据库没有
+    fn has_address_index(&self) -> Result<bool>;
+
+    /// 用给定的 (脚本哈希, 交易) 替换地址索引的交易历史部分
+    fn reset_address_history(&self, history: &[(Vec<u8>, AddressTx)]) -> Result<()>;
+
+    /// 原子地修改链尾、高度索引、交易索引、地址索引、UTXO 集和撤销数据
+    /// 地址索引的未花费输出部分由后端根据 UTXO 集的修改维护
+    fn write_chainstate(&self, update: &ChainStateUpdate) -> Result<()>;
+
+    /// 用给定的未花费输出替换整个 UTXO 集，同时重建地址索引的未花费输出部分
+    fn reset_utxos(&self, entries: &[UTXOEntry]) -> Result<()>;
+}
+
+/// 交易在主链上的位置
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TxLocation {
+    block_hash: String, // 交易所在的区块
+    position: usize,    // 交易在区块中的索引
+}
+
+impl TxLocation {
+    pub fn new(block_hash: &str, position: usize) -> TxLocation {
+        TxLocation {
+            block_hash: block_hash.to_string(),
+            position,
+        }
+    }
+
+    pub fn get_block_hash(&self) -> &str {
+        self.block_hash.as_str()
+    }
+
+    pub fn get_position(&self) -> usize {
+        self.position
+    }
+}
+
+/// 与某个地址相关的交易：交易的输出锁定到该地址，或者交易的输入花费了该地址的输出
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AddressTx {
+    height: usize, // 交易所在区块的高度
+    txid: Vec<u8>,
+}
+
+impl AddressTx {
+    pub fn new(height: usize, txid: &[u8]) -> AddressTx {
+        AddressTx {
+            height,
+            txid: txid.to_vec(),
+        }
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    pub fn get_txid(&self) -> &[u8] {
+        self.txid.as_slice()
+    }
+}
+
+/// 接入或断开一个区块对链状态的修改
+/// 接入时写入区块的撤销数据和高度索引，断开时删除
+#[derive(Debug)]
+pub struct ChainStateUpdate {
+    block_hash: String,                   // 接入或断开的区块
+    height: usize,                        // 区块高度
+    tip_hash: String,                     // 修改后的链尾
+    removed_utxos: Vec<(Vec<u8>, usize)>, // 删除的输出点 (txid, vout)
+    added_utxos: Vec<UTXOEntry>,          // 加入的未花费输出
+    undo: Option<Vec<UTXOEntry>>,         // 接入时为区块的撤销数据，断开时为 None
+    txids: Vec<Vec<u8>>,                  // 需要写入或删除交易索引的交易，按在区块中的顺序
+    history: Vec<(Vec<u8>, Vec<u8>)>,     // 需要写入或删除的地址交易历史 (脚本哈希, txid)
+}
+
+impl ChainStateUpdate {
+    pub(crate) fn new(
+        block_hash: String,
+        height: usize,
+        tip_hash: String,
+        removed_utxos: Vec<(Vec<u8>, usize)>,
+        added_utxos: Vec<UTXOEntry>,
+        undo: Option<Vec<UTXOEntry>>,
+        history: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> ChainStateUpdate {
+        ChainStateUpdate {
+            block_hash,
+            height,
+            tip_hash,
+            removed_utxos,
+            added_utxos,
+            undo,
+            txids: vec![],
+            history,
+        }
+    }
+
+    // 维护交易索引时，记录区块中的交易
+    pub(crate) fn set_txids(&mut self, txids: Vec<Vec<u8>>) {
+        self.txids = txids;
+    }
+
+    /// 是否为接入区块
+    pub fn is_connect(&self) -> bool {
+        self.undo.is_some()
+    }
+
+    pub fn get_block_hash(&self) -> &str {
+        self.block_hash.as_str()
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    pub fn get_tip_hash(&self) -> &str {
+        self.tip_hash.as_str()
+    }
+
+    pub fn get_removed_utxos(&self) -> &[(Vec<u8>, usize)] {
+        self.removed_utxos.as_slice()
+    }
+
+    pub fn get_added_utxos(&self) -> &[UTXOEntry] {
+        self.added_utxos.as_slice()
+    }
+
+    pub fn get_undo(&self) -> Option<&[UTXOEntry]> {
+        self.undo.as_deref()
+    }
+
+    pub fn get_txids(&self) -> &[Vec<u8>] {
+        self.txids.as_slice()
+    }
+
+    pub fn get_history(&self) -> &[(Vec<u8>, Vec<u8>)] {
+        self.history.as_slice()
+    }
+}
@@ -1,13 +1,32 @@
-use crate::wallet::{hash_pub_key, ADDRESS_CHECK_SUM_LEN};
+use crate::amount::Amount;
+use crate::emission::GLOBAL_EMISSION;
+use crate::error::{Error, Result};
+use crate::script::{self, Opcode, Script, ScriptError, SignatureChecker};
+use crate::wallet::{address_to_script, hash_pub_key, Wallet};
 use crate::wallets::Wallets;
-use crate::BlockChain;
 use crate::UTXOSet;
 use data_encoding::HEXLOWER;
 use serde::{Deserialize, Serialize};
 use utils::coder;
 
-// 挖矿奖励金
-const SUBSIDY: i32 = 10;
+/// coinbase 输入中区块高度之后额外数据的最大长度
+pub const MAX_COINBASE_EXTRA_LEN: usize = 92;
+
+// coinbase 输入中区块高度占用的字节数
+const COINBASE_HEIGHT_LEN: usize = 8;
+
+/// lock_time 小于该值时表示区块高度，否则表示 Unix 时间戳（秒）
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+/// 所有输入的 sequence 都是该值时交易的 lock_time 不生效，输入也不启用相对时间锁
+pub const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+/// sequence 设置该位时输入不启用相对时间锁
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+/// sequence 设置该位时相对时间锁以时间为单位，否则以区块数为单位
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+/// sequence 中相对时间锁的数值部分
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000_ffff;
+/// 以时间为单位的相对时间锁，数值的单位是 2^9 = 512 秒
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
 
 //交易
 #[derive(Clone, Default, Serialize, Deserialize, Debug)]
@@ -15,45 +34,51 @@ pub struct Transaction {
     id: Vec<u8>,         //整个交易的哈希值转换为交易id
     vin: Vec<TXInput>,   //输入
     vout: Vec<TXOutput>, //输出
+    lock_time: u32,      //交易最早可以被打包的区块高度或时间，0 表示不限制
 }
 
 //交易输出
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct TXOutput {
-    value: i32,            //币的数量
-    pub_key_hash: Vec<u8>, // 地址公钥哈希
+    value: Amount,          //币的数量
+    script_pub_key: Script, // 锁定脚本，规定花费该输出的条件
 }
 
 impl TXOutput {
-    pub fn new(value: i32, address: &str) -> TXOutput {
-        let mut output = TXOutput {
+    /// 新建支付到地址的输出，普通地址使用 P2PKH 锁定脚本，脚本哈希地址使用 P2SH 锁定脚本
+    pub fn new(value: Amount, address: &str) -> Result<TXOutput> {
+        Ok(TXOutput::new_with_script(
             value,
-            pub_key_hash: vec![],
-        };
-        output.lock(address);
-        output
+            address_to_script(address)?,
+        ))
+    }
+
+    /// 新建使用任意锁定脚本的输出
+    pub fn new_with_script(value: Amount, script_pub_key: Script) -> TXOutput {
+        TXOutput {
+            value,
+            script_pub_key,
+        }
     }
 
     //获取输出币值
-    pub fn get_value(&self) -> i32 {
+    pub fn get_value(&self) -> Amount {
         self.value
     }
 
-    //获取公钥
-    pub fn get_pub_key_hash(&self) -> &[u8] {
-        self.pub_key_hash.as_slice()
+    //获取锁定脚本
+    pub fn get_script_pub_key(&self) -> &Script {
+        &self.script_pub_key
     }
 
-    //通过地址锁定输出的公钥hash
-    fn lock(&mut self, address: &str) {
-        let payload = coder::base58_decode(address);
-        let pub_key_hash = payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN].to_vec();
-        self.pub_key_hash = pub_key_hash;
+    //获取 P2PKH 输出锁定的公钥哈希，其他脚本返回 None
+    pub fn get_pub_key_hash(&self) -> Option<&[u8]> {
+        self.script_pub_key.get_p2pkh_hash()
     }
 
-    //检查是否提供的公钥哈希被用于锁定输出
-    pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
-        self.pub_key_hash.eq(pub_key_hash)
+    //检查输出是否使用指定的锁定脚本
+    pub fn is_locked_with(&self, script_pub_key: &Script) -> bool {
+        self.script_pub_key.eq(script_pub_key)
     }
 }
 
@@ -62,25 +87,37 @@ impl TXOutput {
 pub struct TXInput {
     tx_id: Vec<u8>,     // 一个交易输入引用了前一笔交易的一个输出，ID表明是之前的哪一笔交易
     vout: usize,        // 交易中所有输出的索引
-    signature: Vec<u8>, // 签名
-    pub_key: Vec<u8>,   // 原生的公钥
+    script_sig: Script, // 解锁脚本，提供满足被花费输出锁定脚本的数据
+    sequence: u32,      // 相对时间锁，被花费的输出上链之后需要经过的区块数或时间
 }
 
 impl TXInput {
-    //新建输入
+    //新建输入，不启用时间锁
     pub fn new(txid: &[u8], vout: usize) -> TXInput {
+        TXInput::new_with_sequence(txid, vout, SEQUENCE_FINAL)
+    }
+
+    /// 新建指定 sequence 的输入
+    pub fn new_with_sequence(txid: &[u8], vout: usize, sequence: u32) -> TXInput {
         TXInput {
             tx_id: txid.to_vec(),
             vout,
-            signature: vec![],
-            pub_key: vec![],
+            script_sig: Script::default(),
+            sequence,
         }
     }
 
-    // 检查输入使用了指定密钥来解锁一个输出
-    pub fn uses_key(&self, pub_key_hash: &[u8]) -> bool {
-        let locking_hash = hash_pub_key(self.pub_key.as_slice());
-        return locking_hash.eq(pub_key_hash);
+    // 新建 coinbase 输入，不引用任何输出
+    // 解锁脚本只压入一项数据：区块高度（8 字节大端序）和任意额外数据
+    fn new_coinbase(height: usize, extra: &[u8]) -> TXInput {
+        let mut data = (height as u64).to_be_bytes().to_vec();
+        data.extend(extra);
+        TXInput {
+            tx_id: vec![],
+            vout: 0,
+            script_sig: Script::new(vec![Opcode::Push(data)]),
+            sequence: SEQUENCE_FINAL,
+        }
     }
 
     pub fn get_txid(&self) -> &[u8] {
@@ -91,162 +128,355 @@ impl TXInput {
         self.vout
     }
 
-    pub fn get_pub_key(&self) -> &[u8] {
-        self.pub_key.as_slice()
+    pub fn get_script_sig(&self) -> &Script {
+        &self.script_sig
+    }
+
+    pub fn get_sequence(&self) -> u32 {
+        self.sequence
+    }
+}
+
+// 交易输入的签名检查，签名数据是交易的签名哈希
+struct TransactionSignatureChecker<'a> {
+    tx: &'a Transaction,
+    input: usize,
+}
+
+impl SignatureChecker for TransactionSignatureChecker<'_> {
+    fn check_sig(&self, signature: &[u8], pub_key: &[u8], script_code: &Script) -> bool {
+        let sighash = self.tx.signature_hash(self.input, script_code);
+        coder::ecdsa_p256_sha256_sign_verify(pub_key, signature, sighash.as_slice())
+    }
+
+    // 交易的 lock_time 与脚本要求的类型相同且不早于脚本要求，并且输入没有关闭 lock_time
+    fn check_lock_time(&self, lock_time: u64) -> bool {
+        let tx_lock_time = self.tx.lock_time as u64;
+        let threshold = LOCKTIME_THRESHOLD as u64;
+        if (tx_lock_time < threshold) != (lock_time < threshold) {
+            return false;
+        }
+        lock_time <= tx_lock_time && self.tx.vin[self.input].sequence != SEQUENCE_FINAL
+    }
+
+    // 输入的相对时间锁与脚本要求的类型相同且不短于脚本要求
+    fn check_sequence(&self, sequence: u64) -> bool {
+        let tx_sequence = self.tx.vin[self.input].sequence as u64;
+        if tx_sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG as u64 != 0 {
+            return false;
+        }
+        let type_flag = SEQUENCE_LOCKTIME_TYPE_FLAG as u64;
+        if tx_sequence & type_flag != sequence & type_flag {
+            return false;
+        }
+        let mask = SEQUENCE_LOCKTIME_MASK as u64;
+        sequence & mask <= tx_sequence & mask
     }
 }
 
 impl Transaction {
-    // 创建一个 coinbase 交易，该没有输入，只有一个输出
-    pub fn new_coinbase_tx(to: &str) -> Transaction {
-        let tx_out = TXOutput::new(SUBSIDY, to);
-        let tx_input = TXInput::default();
+    /// 由输入和输出创建交易，输入的解锁脚本可以之后通过 set_script_sig 设置
+    pub fn new(vin: Vec<TXInput>, vout: Vec<TXOutput>) -> Transaction {
+        Transaction::new_with_lock_time(vin, vout, 0)
+    }
+
+    /// 创建在指定区块高度或时间之后才能被打包的交易
+    /// 至少要有一个输入的 sequence 不是 SEQUENCE_FINAL，lock_time 才会生效
+    pub fn new_with_lock_time(
+        vin: Vec<TXInput>,
+        vout: Vec<TXOutput>,
+        lock_time: u32,
+    ) -> Transaction {
+        let mut tx = Transaction {
+            id: vec![],
+            vin,
+            vout,
+            lock_time,
+        };
+        tx.id = tx.hash();
+        tx
+    }
+
+    /// 设置输入的解锁脚本，并重新计算交易ID
+    pub fn set_script_sig(&mut self, input: usize, script_sig: Script) {
+        self.vin[input].script_sig = script_sig;
+        self.id = self.hash();
+    }
+
+    /// 创建一个 coinbase 交易，只有一个不引用任何输出的输入和一个奖励输出
+    /// 输入中写入区块高度和任意额外数据（extra nonce 或留言），不同区块的 coinbase 交易ID不会相同
+    /// 奖励为发行计划中该高度的挖矿奖励加上区块中交易的手续费总额
+    pub fn new_coinbase_tx(
+        to: &str,
+        height: usize,
+        fees: Amount,
+        extra: &[u8],
+    ) -> Result<Transaction> {
+        let reward = GLOBAL_EMISSION
+            .subsidy_at(height)
+            .checked_add(fees)
+            .ok_or(Error::AmountOverflow)?;
+        let tx_out = TXOutput::new(reward, to)?;
+        let tx_input = TXInput::new_coinbase(height, extra);
         let mut tx = Transaction {
             id: vec![],
             vin: vec![tx_input],
             vout: vec![tx_out],
+            lock_time: 0,
         };
         tx.id = tx.hash();
-        tx
+        Ok(tx)
     }
 
-    // 创建一笔 UTXO 的交易
+    // 创建一笔 UTXO 的交易，输入减去输出的差额即为支付给矿工的手续费
+    // lock_time 不为 0 时，交易在该区块高度或时间之后才能被打包
     pub fn new_utxo_transaction(
         from: &str,
         to: &str,
-        amount: i32,
+        amount: Amount,
+        fee: Amount,
+        lock_time: u32,
         utxo_set: &UTXOSet,
-    ) -> Transaction {
+    ) -> Result<Transaction> {
         // 1.查找钱包
-        let wallets = Wallets::new();
-        let wallet = wallets.get_wallet(from).expect("unable to found wallet");
+        let wallets = Wallets::new()?;
+        let wallet = wallets
+            .get_wallet(from)
+            .ok_or_else(|| Error::WalletNotFound(from.to_string()))?;
         let public_key_hash = hash_pub_key(wallet.get_public_key());
-        // 2.找到足够的未花费输出
+        let script_pub_key = Script::new_p2pkh(public_key_hash.as_slice());
+        // 2.选取输入，生成交易
+        let (mut tx, prev_outputs) = Transaction::new_unsigned_transaction(
+            &script_pub_key,
+            to,
+            amount,
+            fee,
+            lock_time,
+            utxo_set,
+        )?;
+        // 3.交易中的 TXInput 签名
+        tx.sign(
+            prev_outputs.as_slice(),
+            wallet.get_pkcs8(),
+            wallet.get_public_key(),
+        )?;
+        // 生成交易ID，交易ID包含签名，需要在签名之后计算
+        tx.id = tx.hash();
+        Ok(tx)
+    }
+
+    /// 创建一笔花费多签 P2SH 地址的交易，找零返回同一地址
+    /// 每个输入的解锁脚本先只压入赎回脚本，再由各个钱包通过 sign_multisig 添加签名
+    pub fn new_multisig_transaction(
+        redeem_script: &Script,
+        to: &str,
+        amount: Amount,
+        fee: Amount,
+        utxo_set: &UTXOSet,
+    ) -> Result<Transaction> {
+        let script_pub_key = Script::new_p2sh(redeem_script.hash160().as_slice());
+        let (mut tx, _) =
+            Transaction::new_unsigned_transaction(&script_pub_key, to, amount, fee, 0, utxo_set)?;
+        let redeem_push = Opcode::Push(redeem_script.to_bytes());
+        for vin in tx.vin.iter_mut() {
+            vin.script_sig = Script::new(vec![redeem_push.clone()]);
+        }
+        tx.id = tx.hash();
+        Ok(tx)
+    }
+
+    // 从锁定脚本为 script_pub_key 的未花费输出中选取足够支付金额和手续费的输入，找零使用同一锁定脚本
+    // 返回未签名的交易和输入花费的输出
+    pub(crate) fn new_unsigned_transaction(
+        script_pub_key: &Script,
+        to: &str,
+        amount: Amount,
+        fee: Amount,
+        lock_time: u32,
+        utxo_set: &UTXOSet,
+    ) -> Result<(Transaction, Vec<TXOutput>)> {
+        // 1.找到足够支付金额和手续费的未花费输出
+        let required = match amount.checked_add(fee) {
+            Some(required) if required.is_valid() => required,
+            _ => return Err(Error::AmountOverflow),
+        };
         let (accumulated, valid_outputs) =
-            utxo_set.find_spendable_outputs(public_key_hash.as_slice(), amount);
-        if accumulated < amount {
-            panic!("Error: Not enough funds")
+            utxo_set.find_spendable_outputs(script_pub_key, required)?;
+        if accumulated < required {
+            return Err(Error::InsufficientFunds {
+                available: accumulated,
+                required,
+            });
+        };
+        // 2.交易的输入，设置了 lock_time 时输入的 sequence 不能是 SEQUENCE_FINAL
+        let sequence = match lock_time {
+            0 => SEQUENCE_FINAL,
+            _ => SEQUENCE_FINAL - 1,
         };
-        // 3.交易数据
-        // 3.1.交易的输入
         let mut inputs = vec![];
+        let mut prev_outputs = vec![];
         for (txid_hex, outs) in valid_outputs {
-            let txid = HEXLOWER.decode(txid_hex.as_bytes()).unwrap();
+            let txid = HEXLOWER
+                .decode(txid_hex.as_bytes())
+                .map_err(|_| Error::Corrupted(format!("bad txid {}", txid_hex)))?;
             for out in outs {
-                let input = TXInput {
-                    tx_id: txid.clone(), // 上一笔交易的ID
-                    vout: out,           // 输出的索引
-                    signature: vec![],
-                    pub_key: wallet.get_public_key().to_vec(),
-                };
-                inputs.push(input);
+                let entry = utxo_set.get_entry(txid.as_slice(), out)?.ok_or_else(|| {
+                    Error::Corrupted(format!("missing output {}:{}", txid_hex, out))
+                })?;
+                prev_outputs.push(entry.get_output().clone());
+                inputs.push(TXInput::new_with_sequence(txid.as_slice(), out, sequence));
             }
         }
-        // 3.2.交易的输出
-        let mut outputs = vec![TXOutput::new(amount, to)];
-        // 如果 UTXO 总数超过所需，则产生找零
-        if accumulated > amount {
-            outputs.push(TXOutput::new(accumulated - amount, from)) // to: 币收入
-        };
-        // 4.生成交易
-        let mut tx = Transaction {
-            id: vec![],
-            vin: inputs,
-            vout: outputs,
+        // 3.交易的输出
+        let mut outputs = vec![TXOutput::new(amount, to)?];
+        // 如果 UTXO 总数超过金额和手续费，则产生找零
+        if accumulated > required {
+            let change = accumulated.checked_sub(required).unwrap();
+            outputs.push(TXOutput::new_with_script(change, script_pub_key.clone()))
         };
-        // 生成交易ID
-        tx.id = tx.hash();
-        // 5.交易中的 TXInput 签名
-        tx.sign(utxo_set.get_blockchain(), wallet.get_pkcs8());
-        tx
+        Ok((
+            Transaction::new_with_lock_time(inputs, outputs, lock_time),
+            prev_outputs,
+        ))
     }
 
-    /// 创建一个修剪后的交易副本
-    fn trimmed_copy(&self) -> Transaction {
-        let mut inputs = vec![];
-        let mut outputs = vec![];
-        for input in &self.vin {
-            let tx_input = TXInput::new(input.get_txid(), input.get_vout());
-            inputs.push(tx_input);
-        }
-        for output in &self.vout {
-            outputs.push(output.clone());
-        }
-        Transaction {
-            id: self.id.clone(),
-            vin: inputs,
-            vout: outputs,
+    /// 输入的签名哈希：清空所有输入的解锁脚本，再将被签名输入的解锁脚本替换为被花费输出的锁定脚本
+    pub fn signature_hash(&self, input: usize, script_pub_key: &Script) -> Vec<u8> {
+        let mut tx_copy = self.clone();
+        for vin in tx_copy.vin.iter_mut() {
+            vin.script_sig = Script::default();
         }
+        tx_copy.vin[input].script_sig = script_pub_key.clone();
+        tx_copy.hash()
     }
 
-    /// 对交易的每个输入进行签名
-    fn sign(&mut self, blockchain: &BlockChain, pkcs8: &[u8]) {
-        let mut tx_copy = self.trimmed_copy();
+    /// 使用私钥为每个花费 P2PKH 输出的输入生成解锁脚本
+    fn sign(&mut self, prev_outputs: &[TXOutput], pkcs8: &[u8], pub_key: &[u8]) -> Result<()> {
+        for (idx, prev_output) in prev_outputs.iter().enumerate() {
+            let sighash = self.signature_hash(idx, prev_output.get_script_pub_key());
+            let signature = coder::ecdsa_p256_sha256_sign_digest(pkcs8, sighash.as_slice())?;
+            self.vin[idx].script_sig = Script::new_p2pkh_sig(signature.as_slice(), pub_key);
+        }
+        Ok(())
+    }
 
-        for (idx, vin) in self.vin.iter_mut().enumerate() {
-            // 查找输入引用的交易
-            let prev_tx_option = blockchain.find_transaction(vin.get_txid());
-            if prev_tx_option.is_none() {
-                panic!("ERROR: Previous transaction is not correct")
+    /// 用钱包为花费多签赎回脚本的输入添加签名，返回签名的输入数量
+    /// 解锁脚本为 <签名...> <赎回脚本>，签名按公钥在赎回脚本中的顺序排列，不同钱包可以依次签名
+    pub fn sign_multisig(&mut self, wallet: &Wallet) -> Result<usize> {
+        let mut signed = 0;
+        for idx in 0..self.vin.len() {
+            let push_data = match self.vin[idx].script_sig.get_push_data() {
+                Some(push_data) if !push_data.is_empty() => push_data,
+                _ => continue,
+            };
+            let redeem_script = match Script::from_bytes(push_data[push_data.len() - 1]) {
+                Some(redeem_script) => redeem_script,
+                None => continue,
+            };
+            let (required, pub_keys) = match redeem_script.get_multisig() {
+                Some(multisig) => multisig,
+                None => continue,
+            };
+            let key_index = match pub_keys
+                .iter()
+                .position(|pk| *pk == wallet.get_public_key())
+            {
+                Some(key_index) => key_index,
+                None => continue,
+            };
+            let sighash = self.signature_hash(idx, &redeem_script);
+            // 已有的签名找出对应的公钥位置，无效的签名丢弃
+            let mut signatures = vec![];
+            for signature in &push_data[..push_data.len() - 1] {
+                let position = pub_keys.iter().position(|pk| {
+                    coder::ecdsa_p256_sha256_sign_verify(pk, signature, sighash.as_slice())
+                });
+                if let Some(position) = position {
+                    signatures.push((position, signature.to_vec()));
+                }
             }
-            if let Some(prev_tx) = prev_tx_option {
-                tx_copy.vin[idx].pub_key = prev_tx.vout[vin.vout].pub_key_hash.clone();
+            if !signatures
+                .iter()
+                .any(|(position, _)| *position == key_index)
+            {
+                let signature =
+                    coder::ecdsa_p256_sha256_sign_digest(wallet.get_pkcs8(), sighash.as_slice())?;
+                signatures.push((key_index, signature));
             }
-            tx_copy.vin[idx].signature = vec![];
-            tx_copy.id = tx_copy.hash();
-            tx_copy.vin[idx].pub_key = vec![];
+            signatures.sort_by_key(|(position, _)| *position);
+            signatures.truncate(required);
 
-            // 使用私钥对数据签名
-            let signature = coder::ecdsa_p256_sha256_sign_digest(pkcs8, tx_copy.get_id());
-            vin.signature = signature;
+            let mut ops: Vec<Opcode> = signatures
+                .into_iter()
+                .map(|(_, signature)| Opcode::Push(signature))
+                .collect();
+            ops.push(Opcode::Push(redeem_script.to_bytes()));
+            self.vin[idx].script_sig = Script::new(ops);
+            signed += 1;
         }
+        self.id = self.hash();
+        Ok(signed)
     }
 
-    /// 对交易的每个输入进行签名验证
-    pub fn verify(&self, blockchain: &BlockChain) -> bool {
-        if self.is_coinbase() {
-            return true;
-        }
-        let mut tx_copy = self.trimmed_copy();
-        for (idx, vin) in self.vin.iter().enumerate() {
-            let prev_tx_option = blockchain.find_transaction(vin.get_txid());
-            if prev_tx_option.is_none() {
-                panic!("ERROR: Previous transaction is not correct")
-            }
-            if let Some(prev_tx) = prev_tx_option {
-                tx_copy.vin[idx].pub_key = prev_tx.vout[vin.vout].pub_key_hash.clone();
-            }
-            tx_copy.vin[idx].signature = vec![];
-            tx_copy.id = tx_copy.hash();
-            tx_copy.vin[idx].pub_key = vec![];
-
-            // 使用公钥验证签名
-            let verify = coder::ecdsa_p256_sha256_sign_verify(
-                vin.pub_key.as_slice(),
-                vin.signature.as_slice(),
-                tx_copy.get_id(),
-            );
-            if !verify {
-                return false;
-            }
-        }
-        true
+    /// 验证输入的解锁脚本满足被花费输出的锁定脚本
+    pub fn verify_input(
+        &self,
+        input: usize,
+        script_pub_key: &Script,
+    ) -> std::result::Result<(), ScriptError> {
+        let checker = TransactionSignatureChecker { tx: self, input };
+        script::verify_script(&self.vin[input].script_sig, script_pub_key, &checker)
     }
 
     // 生成交易的哈希
-    fn hash(&self) -> Vec<u8> {
+    pub(crate) fn hash(&self) -> Vec<u8> {
         let tx_copy = Transaction {
             id: vec![],
             vin: self.vin.clone(),
             vout: self.vout.clone(),
+            lock_time: self.lock_time,
         };
         let tx_ser = coder::serialized(&tx_copy);
         coder::sha256_digest(tx_ser.as_slice())
     }
 
-    /// 判断是否是 coinbase 交易
+    /// 判断交易能否在指定高度、上一区块中位时间为 block_time 的区块中被打包
+    /// lock_time 为 0、已经到达 lock_time 或者所有输入的 sequence 都是 SEQUENCE_FINAL 时可以打包
+    pub fn is_final(&self, height: usize, block_time: i64) -> bool {
+        if self.lock_time == 0 {
+            return true;
+        }
+        let cutoff = match self.lock_time < LOCKTIME_THRESHOLD {
+            true => height as i64,
+            false => block_time,
+        };
+        if (self.lock_time as i64) < cutoff {
+            return true;
+        }
+        self.vin.iter().all(|vin| vin.sequence == SEQUENCE_FINAL)
+    }
+
+    /// 判断是否是 coinbase 交易，coinbase 只有一个不引用任何交易的输入
     pub fn is_coinbase(&self) -> bool {
-        return self.vin.len() == 1 && self.vin[0].pub_key.len() == 0;
+        self.vin.len() == 1 && self.vin[0].tx_id.is_empty()
+    }
+
+    /// coinbase 输入中写入的区块高度，不是 coinbase 或者数据长度不合法时返回 None
+    pub fn get_coinbase_height(&self) -> Option<usize> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        let data = match self.vin[0].script_sig.get_ops() {
+            [Opcode::Push(data)] => data.as_slice(),
+            _ => return None,
+        };
+        if data.len() < COINBASE_HEIGHT_LEN
+            || data.len() > COINBASE_HEIGHT_LEN + MAX_COINBASE_EXTRA_LEN
+        {
+            return None;
+        }
+        let height = u64::from_be_bytes(data[..COINBASE_HEIGHT_LEN].try_into().unwrap());
+        Some(height as usize)
     }
 
     pub fn get_id(&self) -> &[u8] {
@@ -264,4 +494,40 @@ impl Transaction {
     pub fn get_vout(&self) -> &[TXOutput] {
         self.vout.as_slice()
     }
+
+    pub fn get_lock_time(&self) -> u32 {
+        self.lock_time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::Wallet;
+
+    #[test]
+    fn coinbase_height_makes_txid_unique() {
+        let address = Wallet::new().unwrap().get_address();
+        let coinbase = |height| Transaction::new_coinbase_tx(&address, height, Amount::ZERO, &[]);
+        let first = coinbase(1).unwrap();
+        let second = coinbase(2).unwrap();
+        // 奖励和收款地址相同，只有高度不同
+        let (out1, out2) = (&first.get_vout()[0], &second.get_vout()[0]);
+        assert_eq!(out1.get_value(), out2.get_value());
+        assert!(out1.is_locked_with(out2.get_script_pub_key()));
+        assert_ne!(first.get_id(), second.get_id());
+        assert_eq!(first.get_coinbase_height(), Some(1));
+        assert_eq!(second.get_coinbase_height(), Some(2));
+        assert_eq!(coinbase(1).unwrap().get_id(), first.get_id());
+
+        let with_extra = Transaction::new_coinbase_tx(&address, 1, Amount::ZERO, b"extra").unwrap();
+        assert_ne!(with_extra.get_id(), first.get_id());
+        assert_eq!(with_extra.get_coinbase_height(), Some(1));
+
+        let tx = Transaction::new(
+            vec![TXInput::new(first.get_id(), 0)],
+            vec![TXOutput::new(Amount::ZERO, &address).unwrap()],
+        );
+        assert_eq!(tx.get_coinbase_height(), None);
+    }
 }
@@ -1,11 +1,105 @@
+use crate::amount::Amount;
 use crate::block::Block;
 use crate::blockchain::BlockChain;
-use crate::transaction::TXOutput;
+use crate::error::{Error, Result};
+use crate::script::Script;
+use crate::store::{AddressTx, ChainStateUpdate, ChainStore};
+use crate::transaction::{TXOutput, Transaction};
 use data_encoding::HEXLOWER;
-use std::collections::HashMap;
-use utils::coder;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// 未花费交易输出，以 (txid, vout) 为键保存，保留输出在原交易中的位置和创建时的区块高度
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UTXOEntry {
+    txid: Vec<u8>,    // 产生该输出的交易ID
+    vout: usize,      // 输出在原交易中的索引
+    output: TXOutput, // 金额和锁定的公钥哈希
+    height: usize,    // 产生该输出的区块高度
+}
+
+impl UTXOEntry {
+    pub fn new(txid: &[u8], vout: usize, output: TXOutput, height: usize) -> UTXOEntry {
+        UTXOEntry {
+            txid: txid.to_vec(),
+            vout,
+            output,
+            height,
+        }
+    }
+
+    pub fn get_txid(&self) -> &[u8] {
+        self.txid.as_slice()
+    }
+
+    pub fn get_vout(&self) -> usize {
+        self.vout
+    }
+
+    pub fn get_output(&self) -> &TXOutput {
+        &self.output
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    // 在 UTXO 集中的键
+    pub(crate) fn key(&self) -> Vec<u8> {
+        outpoint_key(self.txid.as_slice(), self.vout)
+    }
+}
+
+/// 输出点的键：txid + 大端序的 vout，同一交易的输出在树中相邻
+pub(crate) fn outpoint_key(txid: &[u8], vout: usize) -> Vec<u8> {
+    let mut key = txid.to_vec();
+    key.extend((vout as u32).to_be_bytes());
+    key
+}
+
+/// 地址索引的键：锁定脚本的哈希，长度固定，P2PKH 和 P2SH 等各类脚本统一处理
+pub(crate) fn script_key(script_pub_key: &Script) -> Vec<u8> {
+    script_pub_key.hash160()
+}
+
+/// 区块中每笔交易涉及的地址，spent 为区块的撤销数据，即按交易和输入的顺序花费的输出
+pub(crate) fn block_address_history(
+    block: &Block,
+    spent: &[UTXOEntry],
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut history = vec![];
+    let mut spent_start = 0;
+    for tx in block.get_transactions() {
+        let spent_end = match tx.is_coinbase() {
+            true => spent_start,
+            false => spent_start + tx.get_vin().len(),
+        };
+        let tx_spent = spent.get(spent_start..spent_end).ok_or_else(|| {
+            Error::Corrupted(format!(
+                "undo data of block {} is truncated",
+                block.get_hash()
+            ))
+        })?;
+        history.extend(address_history(tx, tx_spent));
+        spent_start = spent_end;
+    }
+    Ok(history)
+}
+
+// 交易涉及的地址：输出锁定的脚本和输入花费的输出锁定的脚本，spent 为交易按输入顺序花费的输出
+fn address_history(tx: &Transaction, spent: &[UTXOEntry]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let script_keys: BTreeSet<Vec<u8>> = spent
+        .iter()
+        .map(|entry| entry.get_output())
+        .chain(tx.get_vout().iter())
+        .map(|out| script_key(out.get_script_pub_key()))
+        .collect();
+    script_keys
+        .into_iter()
+        .map(|key| (key, tx.get_id_bytes()))
+        .collect()
+}
 
-const UTXO_TREE: &str = "chainstate";
 //未花费交易输出
 pub struct UTXOSet {
     blockchain: BlockChain,
@@ -22,108 +116,159 @@ impl UTXOSet {
         &self.blockchain
     }
 
-    // 找到未花费的输出
+    // 找到锁定脚本为 script_pub_key 的未花费输出，返回的索引是输出在原交易中的 vout
     pub fn find_spendable_outputs(
         &self,
-        pub_key_hash: &[u8],
-        amount: i32,
-    ) -> (i32, HashMap<String, Vec<usize>>) {
+        script_pub_key: &Script,
+        amount: Amount,
+    ) -> Result<(Amount, HashMap<String, Vec<usize>>)> {
         let mut unspent_outputs: HashMap<String, Vec<usize>> = HashMap::new();
-        let mut accmulated = 0;
-        let db = self.blockchain.get_db(); //获取UTXO数据库
-        let utxo_tree = db.open_tree(UTXO_TREE).expect("无法找到UTXO集");
-        for item in utxo_tree.iter() {
-            let (k, v) = item.expect("迭代失败");
-            let txid_hex = HEXLOWER.encode(k.to_vec().as_slice());
-            let outs: Vec<TXOutput> = coder::deserialized(v.to_vec().as_slice());
-            for (idx, out) in outs.iter().enumerate() {
-                if out.is_locked_with_key(pub_key_hash) && accmulated < amount {
-                    accmulated += out.get_value();
-                    if unspent_outputs.contains_key(txid_hex.as_str()) {
-                        unspent_outputs
-                            .get_mut(txid_hex.as_str())
-                            .unwrap()
-                            .push(idx);
-                    } else {
-                        unspent_outputs.insert(txid_hex.clone(), vec![idx]);
-                    }
-                }
-            }
-        }
-        (accmulated, unspent_outputs)
-    }
-
-    // 通过公钥哈希查找 UTXO 集
-    pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Vec<TXOutput> {
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).expect("无法找到UTXO集");
-        let mut utxos = vec![];
-        for item in utxo_tree.iter() {
-            let (_, v) = item.expect("迭代失败");
-            let outs: Vec<TXOutput> = coder::deserialized(v.to_vec().as_slice());
-            for out in outs.iter() {
-                if out.is_locked_with_key(pub_key_hash) {
-                    utxos.push(out.clone())
-                }
+        let mut accmulated = Amount::ZERO;
+        for entry in self.find_unspent(script_pub_key)? {
+            let out = entry.get_output();
+            if accmulated < amount {
+                accmulated = accmulated
+                    .checked_add(out.get_value())
+                    .ok_or(Error::AmountOverflow)?;
+                let txid_hex = HEXLOWER.encode(entry.get_txid());
+                unspent_outputs
+                    .entry(txid_hex)
+                    .or_default()
+                    .push(entry.get_vout());
             }
         }
-        utxos
+        Ok((accmulated, unspent_outputs))
+    }
+
+    /// 查找交易的第 vout 个输出，不存在或已花费时返回 None
+    pub fn get_entry(&self, txid: &[u8], vout: usize) -> Result<Option<UTXOEntry>> {
+        self.blockchain.get_store().get_utxo(txid, vout)
+    }
+
+    // 通过锁定脚本查找 UTXO 集
+    pub fn find_utxo(&self, script_pub_key: &Script) -> Result<Vec<TXOutput>> {
+        let utxos = self.find_unspent(script_pub_key)?;
+        Ok(utxos
+            .iter()
+            .map(|entry| entry.get_output().clone())
+            .collect())
+    }
+
+    /// 通过地址索引查找锁定脚本为 script_pub_key 的未花费输出，按 (txid, vout) 排序
+    pub fn find_unspent(&self, script_pub_key: &Script) -> Result<Vec<UTXOEntry>> {
+        let entries = self
+            .blockchain
+            .get_store()
+            .get_address_utxos(script_key(script_pub_key).as_slice())?;
+        // 不同脚本的哈希可能相同，按脚本再过滤一次
+        Ok(entries
+            .into_iter()
+            .filter(|entry| entry.get_output().is_locked_with(script_pub_key))
+            .collect())
+    }
+
+    /// 通过地址索引查找与锁定脚本相关的主链交易，按高度从低到高排列
+    pub fn find_history(&self, script_pub_key: &Script) -> Result<Vec<AddressTx>> {
+        self.blockchain
+            .get_store()
+            .get_address_history(script_key(script_pub_key).as_slice())
     }
 
     // 统计 UTXO 集合中的交易数量
-    pub fn count_transactions(&self) -> i32 {
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).expect("无法找到UTXO集");
+    pub fn count_transactions(&self) -> Result<i32> {
         let mut counter = 0;
-        for _ in utxo_tree.iter() {
-            counter += 1;
+        let mut last_txid = vec![];
+        // 同一交易的输出相邻，只在 txid 变化时计数
+        for entry in self.blockchain.get_store().get_utxos()? {
+            if entry.get_txid() != last_txid.as_slice() {
+                last_txid = entry.get_txid().to_vec();
+                counter += 1;
+            }
         }
-        counter
+        Ok(counter)
     }
 
-    // 重建 UTXO 集
-    pub fn reindex(&self) {
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).expect("无法找到UTXO集");
-        utxo_tree.clear().expect("清空utxo数据集失败"); //清空utxo数据集
-
-        let utxo_map = self.blockchain.find_utxo();
-        for (txid_hex, outs) in &utxo_map {
-            let txid = HEXLOWER.decode(txid_hex.as_bytes()).unwrap();
-            let value = coder::serialized(outs);
-            let _ = utxo_tree.insert(txid.as_slice(), value).unwrap();
-        }
+    // 重建 UTXO 集和地址索引
+    pub fn reindex(&self) -> Result<()> {
+        let entries = self.blockchain.find_utxo()?;
+        self.blockchain
+            .get_store()
+            .reset_utxos(entries.as_slice())?;
+        self.blockchain.reindex_address_history()
     }
 
-    /// 使用来自区块的交易更新 UTXO 集
-    pub fn update(&self, block: &Block) {
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+    /// 接入区块对链状态的修改：删除被花费的输出，加入新产生的输出，链尾移到该区块
+    /// 被花费的输出按交易和输入的顺序作为区块的撤销数据，其中包括同一区块中产生又被花费的输出
+    pub(crate) fn update(store: &dyn ChainStore, block: &Block) -> Result<ChainStateUpdate> {
+        let mut removed = HashSet::new();
+        let mut added: HashMap<(Vec<u8>, usize), UTXOEntry> = HashMap::new();
+        let mut spent = vec![];
         for tx in block.get_transactions() {
-            if tx.is_coinbase() == false {
+            if !tx.is_coinbase() {
                 for vin in tx.get_vin() {
-                    let mut updated_outs = vec![];
-                    let outs_bytes = utxo_tree.get(vin.get_txid()).unwrap().unwrap();
-                    let outs: Vec<TXOutput> = coder::deserialized(outs_bytes.as_ref());
-                    for (idx, out) in outs.iter().enumerate() {
-                        if idx != vin.get_vout() {
-                            updated_outs.push(out.clone())
-                        }
-                    }
-                    if updated_outs.len() == 0 {
-                        let _ = utxo_tree.remove(vin.get_txid()).unwrap();
-                    } else {
-                        let outs_bytes = coder::serialized(&updated_outs);
-                        utxo_tree.insert(vin.get_txid(), outs_bytes).unwrap();
-                    }
+                    let outpoint = (vin.get_txid().to_vec(), vin.get_vout());
+                    // 被花费的输出可能由同一区块中前面的交易产生，否则必须存在于 UTXO 集中
+                    let entry = match added.remove(&outpoint) {
+                        Some(entry) => entry,
+                        None => match store.get_utxo(vin.get_txid(), vin.get_vout())? {
+                            Some(entry) if removed.insert(outpoint) => entry,
+                            _ => {
+                                return Err(Error::Corrupted(format!(
+                                    "missing output {}:{}",
+                                    HEXLOWER.encode(vin.get_txid()),
+                                    vin.get_vout()
+                                )))
+                            }
+                        },
+                    };
+                    spent.push(entry);
                 }
             }
-            let mut new_outputs = vec![];
-            for out in tx.get_vout() {
-                new_outputs.push(out.clone())
+            for (idx, out) in tx.get_vout().iter().enumerate() {
+                let entry = UTXOEntry::new(tx.get_id(), idx, out.clone(), block.get_height());
+                added.insert((tx.get_id_bytes(), idx), entry);
+            }
+        }
+        let history = block_address_history(block, spent.as_slice())?;
+        Ok(ChainStateUpdate::new(
+            block.get_hash().to_string(),
+            block.get_height(),
+            block.get_hash().to_string(),
+            removed.into_iter().collect(),
+            added.into_values().collect(),
+            Some(spent),
+            history,
+        ))
+    }
+
+    /// 断开链尾区块对链状态的修改：删除区块产生的输出，恢复撤销数据中被花费的输出，链尾退回上一区块
+    pub(crate) fn revert(store: &dyn ChainStore, block: &Block) -> Result<ChainStateUpdate> {
+        // 没有撤销数据的区块无法断开
+        let spent = store.get_undo(block.get_hash())?.ok_or_else(|| {
+            Error::Corrupted(format!("undo data of block {} not found", block.get_hash()))
+        })?;
+        let history = block_address_history(block, spent.as_slice())?;
+        let mut txids = HashSet::new();
+        let mut removed = vec![];
+        for tx in block.get_transactions() {
+            txids.insert(tx.get_id());
+            for idx in 0..tx.get_vout().len() {
+                removed.push((tx.get_id_bytes(), idx));
             }
-            let outs_bytes = coder::serialized(&new_outputs);
-            let _ = utxo_tree.insert(tx.get_id(), outs_bytes).unwrap();
         }
+        // 在同一区块中产生又被花费的输出，断开后不应存在
+        let added = spent
+            .into_iter()
+            .filter(|entry| !txids.contains(entry.get_txid()))
+            .collect();
+        Ok(ChainStateUpdate::new(
+            block.get_hash().to_string(),
+            block.get_height(),
+            block.get_pre_block_hash(),
+            removed,
+            added,
+            None,
+            history,
+        ))
     }
 }
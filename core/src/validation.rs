@@ -0,0 +1,189 @@
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::pow::ProofOfWork;
+use crate::script::ScriptError;
+use crate::transaction::Transaction;
+use data_encoding::HEXLOWER;
+use std::collections::HashSet;
+use std::fmt;
+
+/// 区块校验失败的原因
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockError {
+    /// 区块已经存在
+    Duplicate,
+    /// 区块哈希不是区块头哈希
+    BadHash,
+    /// 区块头哈希不满足声明的难度
+    HighHash,
+    /// 难度不符合难度调整规则
+    BadDifficulty { expected: u32, actual: u32 },
+    /// 找不到上一区块
+    UnknownParent,
+    /// 上一区块不是当前链尾
+    PrevNotTip,
+    /// 上一区块所在的分支已被判定无效
+    BadParent,
+    /// 区块高度不是上一区块高度加一
+    BadHeight { expected: usize, actual: usize },
+    /// 时间戳早于过去若干区块时间戳的中位数
+    TimeTooOld,
+    /// 时间戳超前本地时间太多
+    TimeTooNew,
+    /// 区块中没有交易
+    NoTransactions,
+    /// 默克尔根与区块头不一致
+    BadMerkleRoot,
+    /// 第一笔交易不是 coinbase，或者存在多笔 coinbase
+    BadCoinbase,
+    /// coinbase 输入中的区块高度与区块高度不一致
+    BadCoinbaseHeight { expected: usize },
+    /// coinbase 奖励超过上限
+    BadCoinbaseAmount { max: Amount, actual: Amount },
+    /// 交易格式错误，参数为交易ID
+    BadTransaction(String),
+    /// 与链上仍有未花费输出的交易ID重复，参数为交易ID
+    DuplicateTransaction(String),
+    /// 引用的输出不存在，参数为交易ID
+    MissingInputs(String),
+    /// 引用的输出已被花费，参数为交易ID
+    DoubleSpend(String),
+    /// 输入金额小于输出金额，参数为交易ID
+    InsufficientInputs(String),
+    /// 解锁脚本不满足锁定脚本，参数为交易ID和脚本执行失败的原因
+    BadScript(String, ScriptError),
+    /// 还没有到达交易的 lock_time，参数为交易ID
+    NonFinalTransaction(String),
+    /// 输入的相对时间锁还没有到期，参数为交易ID
+    SequenceLocked(String),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::Duplicate => write!(f, "block already known"),
+            BlockError::BadHash => write!(f, "block hash does not match header"),
+            BlockError::HighHash => write!(f, "proof of work failed"),
+            BlockError::BadDifficulty { expected, actual } => write!(
+                f,
+                "incorrect difficulty bits {:#010x}, expected {:#010x}",
+                actual, expected
+            ),
+            BlockError::UnknownParent => write!(f, "previous block not found"),
+            BlockError::PrevNotTip => write!(f, "previous block is not the chain tip"),
+            BlockError::BadParent => write!(f, "previous block is invalid"),
+            BlockError::BadHeight { expected, actual } => {
+                write!(f, "incorrect height {}, expected {}", actual, expected)
+            }
+            BlockError::TimeTooOld => write!(f, "block timestamp too early"),
+            BlockError::TimeTooNew => write!(f, "block timestamp too far in the future"),
+            BlockError::NoTransactions => write!(f, "block has no transactions"),
+            BlockError::BadMerkleRoot => write!(f, "merkle root mismatch"),
+            BlockError::BadCoinbase => write!(f, "first transaction must be the only coinbase"),
+            BlockError::BadCoinbaseHeight { expected } => {
+                write!(f, "coinbase does not commit to height {}", expected)
+            }
+            BlockError::BadCoinbaseAmount { max, actual } => {
+                write!(f, "coinbase pays {}, limit is {}", actual, max)
+            }
+            BlockError::BadTransaction(txid) => write!(f, "malformed transaction {}", txid),
+            BlockError::DuplicateTransaction(txid) => {
+                write!(f, "transaction {} already has unspent outputs", txid)
+            }
+            BlockError::MissingInputs(txid) => {
+                write!(f, "transaction {} spends unknown outputs", txid)
+            }
+            BlockError::DoubleSpend(txid) => {
+                write!(f, "transaction {} spends already spent outputs", txid)
+            }
+            BlockError::InsufficientInputs(txid) => {
+                write!(f, "transaction {} spends more than its inputs", txid)
+            }
+            BlockError::BadScript(txid, e) => {
+                write!(f, "transaction {} fails script verification: {}", txid, e)
+            }
+            BlockError::NonFinalTransaction(txid) => {
+                write!(f, "transaction {} is not final", txid)
+            }
+            BlockError::SequenceLocked(txid) => {
+                write!(
+                    f,
+                    "transaction {} spends outputs under relative locktime",
+                    txid
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+/// 上下文无关的区块检查，只依赖区块本身
+pub fn check_block(block: &Block) -> Result<(), BlockError> {
+    // 区块哈希必须是区块头哈希，任何节点都可以只凭区块头重新计算并校验
+    if block.get_hash() != block.get_header().hash() {
+        return Err(BlockError::BadHash);
+    }
+    if !ProofOfWork::new_proof_of_work(block.get_header().clone()).validate() {
+        return Err(BlockError::HighHash);
+    }
+
+    let transactions = block.get_transactions();
+    if transactions.is_empty() {
+        return Err(BlockError::NoTransactions);
+    }
+    let merkle_root = block.build_merkle_tree().get_root();
+    if HEXLOWER.encode(&merkle_root) != block.get_header().get_merkle_root() {
+        return Err(BlockError::BadMerkleRoot);
+    }
+    // 交易ID不能重复，否则可以通过复制末尾交易构造出默克尔根相同的另一组交易
+    let mut txids = HashSet::new();
+    for tx in transactions {
+        if !txids.insert(tx.get_id()) {
+            return Err(BlockError::BadTransaction(HEXLOWER.encode(tx.get_id())));
+        }
+    }
+
+    // 第一笔交易必须是 coinbase，并且只能有一笔 coinbase
+    let coinbase = &transactions[0];
+    if coinbase.get_coinbase_height().is_none() || coinbase.get_vout().is_empty() {
+        return Err(BlockError::BadCoinbase);
+    }
+    if transactions[1..].iter().any(|tx| tx.is_coinbase()) {
+        return Err(BlockError::BadCoinbase);
+    }
+    for tx in transactions {
+        check_transaction(tx)?;
+    }
+    Ok(())
+}
+
+/// 上下文无关的交易检查
+pub fn check_transaction(tx: &Transaction) -> Result<(), BlockError> {
+    let txid_hex = HEXLOWER.encode(tx.get_id());
+    if tx.get_id() != tx.hash().as_slice() {
+        return Err(BlockError::BadTransaction(txid_hex));
+    }
+    if tx.get_vin().is_empty() || tx.get_vout().is_empty() {
+        return Err(BlockError::BadTransaction(txid_hex));
+    }
+    // 输出金额不能为 0，输出总额不能溢出或超过 MAX_MONEY
+    if tx
+        .get_vout()
+        .iter()
+        .any(|out| out.get_value() == Amount::ZERO)
+    {
+        return Err(BlockError::BadTransaction(txid_hex));
+    }
+    if Amount::checked_sum(tx.get_vout().iter().map(|out| out.get_value())).is_none() {
+        return Err(BlockError::BadTransaction(txid_hex));
+    }
+    // 同一笔交易不能重复引用同一个输出
+    let mut outpoints = HashSet::new();
+    for vin in tx.get_vin() {
+        if !outpoints.insert((vin.get_txid(), vin.get_vout())) {
+            return Err(BlockError::BadTransaction(txid_hex));
+        }
+    }
+    Ok(())
+}
@@ -1,7 +1,13 @@
+use crate::error::{Error, Result};
+use crate::script::Script;
 use serde::{Deserialize, Serialize};
 use utils::{coder, EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
 
 const VERSION: u8 = 0x00;
+/// 脚本哈希（P2SH）地址的版本，多签地址使用这种格式
+const SCRIPT_VERSION: u8 = 0x05;
+/// 地址中公钥哈希或脚本哈希的长度
+const HASH_LEN: usize = 20;
 pub const ADDRESS_CHECK_SUM_LEN: usize = 4;
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -12,16 +18,16 @@ pub struct Wallet {
 
 impl Wallet {
     // 创建一个钱包
-    pub fn new() -> Wallet {
-        let private_key = coder::new_key_pair();
+    pub fn new() -> Result<Wallet> {
+        let private_key = coder::new_key_pair()?;
         let key_pair =
             EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, private_key.as_ref())
-                .expect("生成密钥失败");
+                .map_err(|_| coder::CoderError::Key)?;
         let public_key = key_pair.public_key().as_ref().to_vec();
-        Wallet {
+        Ok(Wallet {
             private_key,
             public_key,
-        }
+        })
     }
 
     // 获取钱包地址
@@ -62,9 +68,18 @@ fn checksum(payload: &[u8]) -> Vec<u8> {
     second_sha[0..ADDRESS_CHECK_SUM_LEN].to_vec()
 }
 
-/// 验证地址有效
+/// 验证地址有效，支持普通地址和脚本哈希地址
 pub fn validate_address(address: &str) -> bool {
-    let payload = coder::base58_decode(address);
+    let payload = match coder::base58_decode(address) {
+        Ok(payload) => payload,
+        Err(_) => return false,
+    };
+    if payload.len() != 1 + HASH_LEN + ADDRESS_CHECK_SUM_LEN {
+        return false;
+    }
+    if payload[0] != VERSION && payload[0] != SCRIPT_VERSION {
+        return false;
+    }
     let actual_checksum = payload[payload.len() - ADDRESS_CHECK_SUM_LEN..].to_vec();
     let version = payload[0];
     let pub_key_hash = payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN].to_vec();
@@ -78,11 +93,41 @@ pub fn validate_address(address: &str) -> bool {
 
 /// 通过公钥哈希计算地址
 pub fn convert_address(pub_hash_key: &[u8]) -> String {
+    encode_address(VERSION, pub_hash_key)
+}
+
+/// 通过脚本哈希计算 P2SH 地址
+pub fn convert_script_address(script_hash: &[u8]) -> String {
+    encode_address(SCRIPT_VERSION, script_hash)
+}
+
+// version + hash + checksum
+fn encode_address(version: u8, hash: &[u8]) -> String {
     let mut payload: Vec<u8> = vec![];
-    payload.push(VERSION);
-    payload.extend(pub_hash_key);
+    payload.push(version);
+    payload.extend(hash);
     let checksum = checksum(payload.as_slice());
     payload.extend(checksum.as_slice());
-    // version + pub_key_hash + checksum
     coder::base58_encode(payload.as_slice())
 }
+
+/// 地址对应的锁定脚本：普通地址为 P2PKH，脚本哈希地址为 P2SH
+pub fn address_to_script(address: &str) -> Result<Script> {
+    if !validate_address(address) {
+        return Err(Error::InvalidAddress(address.to_string()));
+    }
+    let payload = coder::base58_decode(address)?;
+    let hash = &payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN];
+    match payload[0] {
+        SCRIPT_VERSION => Ok(Script::new_p2sh(hash)),
+        _ => Ok(Script::new_p2pkh(hash)),
+    }
+}
+
+/// 锁定脚本对应的地址，不是 P2PKH 或 P2SH 时返回 None
+pub fn script_to_address(script: &Script) -> Option<String> {
+    if let Some(pub_key_hash) = script.get_p2pkh_hash() {
+        return Some(convert_address(pub_key_hash));
+    }
+    script.get_p2sh_hash().map(convert_script_address)
+}
@@ -1,3 +1,4 @@
+use crate::error::Result;
 use crate::Wallet;
 use std::collections::HashMap;
 use std::env::current_dir;
@@ -12,21 +13,21 @@ pub struct Wallets {
 }
 
 impl Wallets {
-    pub fn new() -> Wallets {
+    pub fn new() -> Result<Wallets> {
         let mut wallets = Wallets {
             wallets: HashMap::new(),
         };
-        wallets.load_from_file();
-        wallets
+        wallets.load_from_file()?;
+        Ok(wallets)
     }
 
     /// 创建一个钱包
-    pub fn create_wallet(&mut self) -> String {
-        let wallet = Wallet::new();
+    pub fn create_wallet(&mut self) -> Result<String> {
+        let wallet = Wallet::new()?;
         let address = wallet.get_address();
         self.wallets.insert(address.clone(), wallet);
-        self.save_to_file();
-        return address;
+        self.save_to_file()?;
+        Ok(address)
     }
 
     //获取地址
@@ -47,30 +48,30 @@ impl Wallets {
     }
 
     /// 从本地文件加载钱包
-    pub fn load_from_file(&mut self) {
-        let path = current_dir().unwrap().join(WALLET_FILE);
+    pub fn load_from_file(&mut self) -> Result<()> {
+        let path = current_dir()?.join(WALLET_FILE);
         if !path.exists() {
-            return;
+            return Ok(());
         }
-        let mut file = File::open(path).unwrap();
-        let metadata = file.metadata().expect("unable to read metadata");
-        let mut buf = vec![0; metadata.len() as usize];
-        let _ = file.read(&mut buf).expect("buffer overflow");
-        let wallets = coder::deserialized(&buf[..]);
-        self.wallets = wallets;
+        let mut file = File::open(path)?;
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)?;
+        self.wallets = coder::deserialized(&buf[..])?;
+        Ok(())
     }
 
     /// 钱包持久化到本地文件
-    fn save_to_file(&self) {
-        let path = current_dir().unwrap().join(WALLET_FILE);
+    fn save_to_file(&self) -> Result<()> {
+        let path = current_dir()?.join(WALLET_FILE);
         let file = OpenOptions::new()
             .create(true)
             .write(true)
-            .open(&path)
-            .expect("unable to open wallet.dat");
+            .truncate(true)
+            .open(&path)?;
         let mut writer = BufWriter::new(file);
         let wallets_bytes = coder::serialized(&self.wallets);
-        writer.write(wallets_bytes.as_slice()).unwrap();
-        let _ = writer.flush();
+        writer.write_all(wallets_bytes.as_slice())?;
+        writer.flush()?;
+        Ok(())
     }
 }
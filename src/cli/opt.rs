@@ -1,6 +1,7 @@
 use clap::Parser;
 use log::info;
 use std::error::Error;
+use std::net::SocketAddr;
 
 #[derive(Parser, Debug)]
 pub struct Opts {
@@ -16,14 +17,34 @@ pub struct Opts {
     )]
     pub port: usize,
 
-    #[clap(short, long, help = "数据库目录位置")]
+    #[clap(
+        short,
+        long,
+        help = "数据目录位置，保存数据库和地址簿，默认读取环境变量 DATA_DIR"
+    )]
     pub data_dir: Option<String>,
+
+    #[clap(
+        short,
+        long,
+        help = "向其他节点通告的外部地址 ip:port，设置后在所有网卡上监听，默认读取环境变量 EXTERNAL_ADDRESS"
+    )]
+    pub external_addr: Option<String>,
+
+    #[clap(
+        short,
+        long,
+        help = "种子节点地址，多个地址以逗号分隔，默认读取环境变量 SEED_NODES"
+    )]
+    pub seeds: Option<String>,
 }
 
 pub struct Config {
     pub config: String,
     pub port: String,
-    pub data_dir: String,
+    pub data_dir: Option<String>,
+    pub external_addr: Option<String>,
+    pub seeds: Option<String>,
 }
 
 impl Opts {
@@ -32,12 +53,10 @@ impl Opts {
         info!("配置项，config opt is {:#?}", self);
 
         let port = self.port.to_string();
-        let data_dir;
         let config;
 
-        match &self.data_dir {
-            Some(dir) => data_dir = dir.to_owned(),
-            None => data_dir = String::from("dir"),
+        if let Some(addr) = &self.external_addr {
+            addr.parse::<SocketAddr>()?;
         }
         match &self.config {
             Some(cfg) => config = cfg.to_owned(),
@@ -46,7 +65,9 @@ impl Opts {
         let cfg = Config {
             config,
             port,
-            data_dir,
+            data_dir: self.data_dir.clone(),
+            external_addr: self.external_addr.clone(),
+            seeds: self.seeds.clone(),
         };
         Ok(cfg)
     }
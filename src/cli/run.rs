@@ -1,171 +1,319 @@
-use super::subcommand::{CheckList, Commands, Mode};
+use super::subcommand::{CheckList, Commands, Mode, MultisigMode, PsbtMode, Switch};
 use core::{
-    convert_address, hash_pub_key, send_tx, validate_address, BlockChain, Server, Transaction,
-    UTXOSet, Wallets, ADDRESS_CHECK_SUM_LEN, CENTER_NODE, GLOBAL_CONFIG,
+    address_to_script, convert_script_address, script_to_address, submit_tx, validate_address,
+    AddrBook, Amount, BlockChain, PartiallySignedTransaction, Script, Server, Transaction, UTXOSet,
+    Wallets, GLOBAL_CONFIG, GLOBAL_EMISSION, MAX_MULTISIG_KEYS,
 };
 use data_encoding::HEXLOWER;
 use log::info;
-use utils::coder::base58_decode;
+use std::error::Error;
+use utils::coder;
 
 /// mine 标志指的是块会立刻被同一节点挖出来。必须要有这个标志，因为初始状态时，网络中没有矿工节点。
 const MINE_TRUE: i32 = 1;
+
+/// 子命令的执行结果，失败时打印错误并以非零状态码退出
+type CmdResult<T = ()> = Result<T, Box<dyn Error>>;
+
 //运行子命令
 pub fn run_cmd(command: Commands) {
     info!("子命令，cmd is {:#?}", command);
+    if let Err(e) = execute(command) {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn execute(command: Commands) -> CmdResult {
     match command {
         Commands::Wallet { opt } => match opt {
             Some(address) => {
-                get_balance(&address);
+                get_balance(&address)?;
             }
             None => {
-                new_wallet();
+                new_wallet()?;
             }
         },
-        Commands::Center { opt } => match opt {
-            Some(address) => {
-                new_blockchain(&address);
-            }
-            None => {}
-        },
         Commands::Miner { opt } => {
             if let Some(address) = opt {
                 info!("新建矿工节点，钱包地址为 {}，new a miner", address);
-                new_node(Some(address));
+                new_node(Some(address))?;
             }
         }
         Commands::Check { opt } => match opt {
             CheckList::Chain => {
                 info!("查看区块链列表，check chain");
-                println_chain();
+                println_chain()?;
             }
             CheckList::Utxo => {
                 info!("查看未打包交易，check Utxo");
-                rest_utxo();
+                rest_utxo()?;
             }
             CheckList::WalletList => {
                 info!("查看钱包列表，check wallet-list");
-                println_wallet();
+                println_wallet()?;
             }
         },
         Commands::New { opt } => match opt {
             Mode::Wallet { params } => {
                 if let None = params {
                     info!("新建钱包，new a wallet");
-                    new_wallet();
+                    new_wallet()?;
                 }
             }
             Mode::Miner { params } => {
                 if let None = params {
                     info!("生成新矿工节点，new a miner");
-                    new_node(None);
+                    new_node(None)?;
                 }
             }
-            Mode::Center { params } => {
+            Mode::Chain { params } => {
                 if let Some(address) = params {
                     info!("新建区块，new a blockchain");
-                    new_blockchain(&address);
+                    new_blockchain(&address)?;
                 }
             }
         },
         Commands::Send { opt } => {
             info!("发生转账！");
-            send_data(&opt.from, &opt.to, opt.amount, opt.mine);
+            send_data(
+                &opt.from,
+                &opt.to,
+                opt.amount,
+                opt.fee,
+                opt.lock_time,
+                opt.mine,
+            )?;
+        }
+        Commands::Invalidate { hash } => {
+            info!("标记区块无效，invalidate {}", hash);
+            invalidate_block(&hash)?;
+        }
+        Commands::History { address } => {
+            info!("查看地址历史，history {}", address);
+            println_history(&address)?;
+        }
+        Commands::Tx { txid } => {
+            info!("查看交易，tx {}", txid);
+            println_transaction(&txid)?;
         }
+        Commands::Txindex { opt } => {
+            info!("交易索引，txindex {:?}", opt);
+            let blockchain = BlockChain::new_blockchain()?;
+            blockchain.set_tx_index(matches!(opt, Switch::On))?;
+            println!("Done! Transaction index is {:?}", opt);
+        }
+        Commands::Peers => {
+            info!("查看已知节点，peers");
+            println_peers();
+        }
+        Commands::Supply { height } => {
+            info!("查看流通量，supply");
+            println_supply(height)?;
+        }
+        Commands::Multisig { opt } => match opt {
+            MultisigMode::Create { required, keys } => {
+                info!("创建多签地址，multisig create");
+                multisig_create(required, &keys)?;
+            }
+            MultisigMode::Spend {
+                redeem_script,
+                to,
+                amount,
+                fee,
+            } => {
+                info!("创建多签交易，multisig spend");
+                multisig_spend(&redeem_script, &to, amount, fee)?;
+            }
+            MultisigMode::Sign { tx, address } => {
+                info!("多签交易签名，multisig sign");
+                multisig_sign(&tx, &address)?;
+            }
+        },
+        Commands::Broadcast { tx, miner } => {
+            info!("广播交易，broadcast");
+            broadcast_transaction(decode_transaction(&tx)?, miner)?;
+        }
+        Commands::Psbt { opt } => match opt {
+            PsbtMode::Create {
+                from,
+                to,
+                amount,
+                fee,
+                lock_time,
+                redeem_script,
+            } => {
+                info!("创建部分签名交易，psbt create");
+                psbt_create(&from, &to, amount, fee, lock_time, redeem_script)?;
+            }
+            PsbtMode::Sign { psbt, address } => {
+                info!("部分签名交易签名，psbt sign");
+                psbt_sign(&psbt, &address)?;
+            }
+            PsbtMode::Combine { psbts } => {
+                info!("合并部分签名交易，psbt combine");
+                psbt_combine(&psbts)?;
+            }
+            PsbtMode::Finalize { psbt } => {
+                info!("生成最终交易，psbt finalize");
+                let tx = psbt_finalize(&psbt)?;
+                println!("{}", encode_transaction(&tx));
+            }
+            PsbtMode::Broadcast { psbt, miner } => {
+                info!("广播部分签名交易，psbt broadcast");
+                broadcast_transaction(psbt_finalize(&psbt)?, miner)?;
+            }
+        },
     }
+    Ok(())
 }
 
 //创建新区块链
-fn new_blockchain(address: &str) {
-    let blockchain = BlockChain::create_blockchain(address);
+fn new_blockchain(address: &str) -> CmdResult {
+    let blockchain = BlockChain::create_blockchain(address)?;
     let utxo_set = UTXOSet::new(blockchain);
-    utxo_set.reindex();
+    utxo_set.reindex()?;
     println!("new_blockchain Done!");
+    Ok(())
 }
 
 //创建新钱包地址
-fn new_wallet() {
-    let mut wallet = Wallets::new();
-    let address = wallet.create_wallet();
+fn new_wallet() -> CmdResult {
+    let mut wallet = Wallets::new()?;
+    let address = wallet.create_wallet()?;
     println!("Your new address: {}", address);
+    Ok(())
 }
 
 //运行新节点
-fn new_node(miner: Option<String>) {
+fn new_node(miner: Option<String>) -> CmdResult {
     if let Some(addr) = miner {
-        if validate_address(&addr) == false {
-            panic!("Wrong miner address!")
+        if !validate_address(&addr) {
+            return Err("Wrong miner address!".into());
         }
         println!("Mining is on. Address to receive rewards: {}", addr);
         GLOBAL_CONFIG.set_mining_addr(addr);
     }
-    let blockchain = BlockChain::new_blockchain();
-    //节点IP地址
-    let socket_addr = GLOBAL_CONFIG.get_node_addr();
-    Server::new(blockchain).start_server(socket_addr.as_str());
+    let blockchain = BlockChain::new_blockchain()?;
+    //节点监听地址
+    let socket_addr = GLOBAL_CONFIG.get_listen_addr();
+    Server::new(blockchain).start_server(socket_addr.as_str())?;
+    Ok(())
 }
 
-//转账交易
-fn send_data(from: &str, to: &str, amount: i32, mine: i32) {
-    println!("{from}向{to}发送{amount}个币,{mine}");
+//转账交易，设置了锁定时间且还不能打包时只输出签名后的交易，到期后再广播
+fn send_data(
+    from: &str,
+    to: &str,
+    amount: Amount,
+    fee: Amount,
+    lock_time: u32,
+    mine: i32,
+) -> CmdResult {
+    println!("{from}向{to}发送{amount}个币,手续费{fee},锁定时间{lock_time},{mine}");
     if !validate_address(from) {
-        panic!("ERROR: Sender address is not valid")
+        return Err("Sender address is not valid".into());
     }
     if !validate_address(to) {
-        panic!("ERROR: Recipient address is not valid")
+        return Err("Recipient address is not valid".into());
     }
-    let blockchain = BlockChain::new_blockchain();
+    let blockchain = BlockChain::new_blockchain()?;
     let utxo_set = UTXOSet::new(blockchain.clone());
     // 创建 UTXO 交易
-    let transaction = Transaction::new_utxo_transaction(from, to, amount, &utxo_set);
+    let transaction =
+        Transaction::new_utxo_transaction(from, to, amount, fee, lock_time, &utxo_set)?;
+    let tip_block = blockchain
+        .get_block(blockchain.get_tip_hash().as_bytes())?
+        .ok_or("The tip hash is not valid")?;
+    let median_time_past = blockchain.get_median_time_past(&tip_block)?;
+    if !transaction.is_final(tip_block.get_height() + 1, median_time_past) {
+        println!(
+            "Transaction is locked until {}, broadcast it later:",
+            lock_time
+        );
+        println!("{}", encode_transaction(&transaction));
+        return Ok(());
+    }
 
     if mine == MINE_TRUE {
-        //  挖矿奖励
-        let coinbase_tx = Transaction::new_coinbase_tx(from);
-        // 挖新区块
-        let block = blockchain.mine_block(&vec![transaction, coinbase_tx]);
-        // 更新 UTXO 集
-        utxo_set.update(&block);
+        //  挖矿奖励，手续费由挖出区块的发送方收取
+        let height = blockchain.get_best_height()? + 1;
+        let coinbase_tx = Transaction::new_coinbase_tx(from, height, fee, &[])?;
+        // 挖新区块，区块写入后会同步更新 UTXO 集
+        blockchain.mine_block(&vec![coinbase_tx, transaction])?;
     } else {
-        send_tx(CENTER_NODE, &transaction);
+        let addr = submit_tx(&transaction)?;
+        info!("交易已提交到节点 {}", addr);
     }
-    println!("Success!")
+    println!("Success!");
+    Ok(())
 }
 
 //获取钱包地址余额
-fn get_balance(address: &str) {
+fn get_balance(address: &str) -> CmdResult {
     let address_valid = validate_address(address);
-    if address_valid == false {
-        panic!("ERROR: Address is not valid")
+    if !address_valid {
+        return Err("Address is not valid".into());
     }
-    let payload = base58_decode(address);
-    let pub_key_hash = &payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN];
-    let blockchain = BlockChain::new_blockchain();
+    let blockchain = BlockChain::new_blockchain()?;
     let utxo_set = UTXOSet::new(blockchain);
-    let utxos = utxo_set.find_utxo(pub_key_hash);
-    let mut balance = 0;
-    for utxo in utxos {
-        balance += utxo.get_value();
-    }
+    let utxos = utxo_set.find_utxo(&address_to_script(address)?)?;
+    let balance =
+        Amount::checked_sum(utxos.iter().map(|utxo| utxo.get_value())).ok_or("余额溢出")?;
     println!("Balance of {}: {}", address, balance);
+    Ok(())
+}
+
+//打印地址的交易历史和未花费输出
+fn println_history(address: &str) -> CmdResult {
+    if !validate_address(address) {
+        return Err("Address is not valid".into());
+    }
+    let script_pub_key = address_to_script(address)?;
+    let utxo_set = UTXOSet::new(BlockChain::new_blockchain()?);
+    for tx in utxo_set.find_history(&script_pub_key)? {
+        println!(
+            "Height: {}, txid_hex: {}",
+            tx.get_height(),
+            HEXLOWER.encode(tx.get_txid())
+        );
+    }
+    for entry in utxo_set.find_unspent(&script_pub_key)? {
+        println!(
+            "- Unspent txid_hex = {}, vout = {}, value = {}",
+            HEXLOWER.encode(entry.get_txid()),
+            entry.get_vout(),
+            entry.get_output().get_value()
+        );
+    }
+    Ok(())
+}
+
+//打印地址簿中已知的节点，最近在线的在前
+fn println_peers() {
+    let book = AddrBook::load();
+    for addr in book.get_addrs(book.len()) {
+        println!(
+            "Address: {}, last seen: {}",
+            addr.get_addr(),
+            addr.get_last_seen()
+        );
+    }
 }
 
 //打印钱包列表
-fn println_wallet() {
-    let wallets = Wallets::new();
+fn println_wallet() -> CmdResult {
+    let wallets = Wallets::new()?;
     for address in wallets.get_addresses() {
         println!("{}", address)
     }
+    Ok(())
 }
 
 //打印区块链列表
-fn println_chain() {
-    let mut block_iterator = BlockChain::new_blockchain().iterator();
-    loop {
-        let option = block_iterator.next();
-        if option.is_none() {
-            break;
-        }
-        let block = option.unwrap();
+fn println_chain() -> CmdResult {
+    let mut block_iterator = BlockChain::new_blockchain()?.iterator();
+    while let Some(block) = block_iterator.next()? {
         println!("Pre block hash: {}", block.get_pre_block_hash());
         println!("Cur block hash: {}", block.get_hash());
         println!("Cur block Timestamp: {}", block.get_timestamp());
@@ -173,34 +321,239 @@ fn println_chain() {
             let cur_txid_hex = HEXLOWER.encode(tx.get_id());
             println!("- Transaction txid_hex: {}", cur_txid_hex);
 
-            if tx.is_coinbase() == false {
+            if !tx.is_coinbase() {
                 for input in tx.get_vin() {
                     let txid_hex = HEXLOWER.encode(input.get_txid());
-                    let pub_key_hash = hash_pub_key(input.get_pub_key());
-                    let address = convert_address(pub_key_hash.as_slice());
                     println!(
-                        "-- Input txid = {}, vout = {}, from = {}",
+                        "-- Input txid = {}, vout = {}, script = {}",
                         txid_hex,
                         input.get_vout(),
-                        address,
+                        input.get_script_sig(),
                     )
                 }
             }
             for output in tx.get_vout() {
-                let pub_key_hash = output.get_pub_key_hash();
-                let address = convert_address(pub_key_hash);
-                println!("-- Output value = {}, to = {}", output.get_value(), address,)
+                // P2PKH 和 P2SH 输出显示地址，其他输出显示锁定脚本
+                match script_to_address(output.get_script_pub_key()) {
+                    Some(address) => {
+                        println!("-- Output value = {}, to = {}", output.get_value(), address,)
+                    }
+                    None => println!(
+                        "-- Output value = {}, script = {}",
+                        output.get_value(),
+                        output.get_script_pub_key(),
+                    ),
+                }
             }
         }
         println!()
     }
+    Ok(())
 }
 
 //查看未打包交易
-fn rest_utxo() {
-    let blockchain = BlockChain::new_blockchain();
+fn rest_utxo() -> CmdResult {
+    let blockchain = BlockChain::new_blockchain()?;
     let utxo_set = UTXOSet::new(blockchain);
-    utxo_set.reindex();
-    let count = utxo_set.count_transactions();
+    utxo_set.reindex()?;
+    let count = utxo_set.count_transactions()?;
     println!("Done! There are {} transactions in the UTXO set.", count);
+    Ok(())
+}
+
+//标记区块无效，从主链上断开该区块及之后的区块
+fn invalidate_block(block_hash: &str) -> CmdResult {
+    let blockchain = BlockChain::new_blockchain()?;
+    match blockchain.invalidate_block(block_hash)? {
+        Some(change) => {
+            println!(
+                "Done! Disconnected {} blocks, connected {} blocks, tip is {}",
+                change.get_disconnected().len(),
+                change.get_connected().len(),
+                blockchain.get_tip_hash()
+            );
+            Ok(())
+        }
+        None => Err(format!("Block {} not found or cannot be invalidated", block_hash).into()),
+    }
+}
+
+//打印主链上的交易及其所在区块和确认数
+fn println_transaction(txid_hex: &str) -> CmdResult {
+    let txid = HEXLOWER
+        .decode(txid_hex.as_bytes())
+        .map_err(|_| "Transaction id is not valid hex")?;
+    let blockchain = BlockChain::new_blockchain()?;
+    let confirmed = blockchain
+        .get_transaction(txid.as_slice())?
+        .ok_or_else(|| format!("Transaction {} not found in main chain", txid_hex))?;
+    println!("Block hash: {}", confirmed.get_block_hash());
+    println!("Block height: {}", confirmed.get_height());
+    println!("Confirmations: {}", confirmed.get_confirmations());
+    println!("{}", encode_transaction(confirmed.get_transaction()));
+    Ok(())
+}
+
+//打印指定高度的流通量，默认为当前主链高度
+fn println_supply(height: Option<usize>) -> CmdResult {
+    let height = match height {
+        Some(height) => height,
+        None => BlockChain::new_blockchain()?.get_best_height()?,
+    };
+    println!("Height: {}", height);
+    println!("Block subsidy: {}", GLOBAL_EMISSION.subsidy_at(height));
+    println!("Circulating supply: {}", GLOBAL_EMISSION.supply_at(height));
+    println!("Max supply: {}", GLOBAL_EMISSION.get_max_supply());
+    Ok(())
+}
+
+//创建 m-of-n 多签地址，公钥可以是本地钱包地址或十六进制公钥
+fn multisig_create(required: usize, keys: &[String]) -> CmdResult {
+    let wallets = Wallets::new()?;
+    let mut pub_keys = vec![];
+    for key in keys {
+        let pub_key = match wallets.get_wallet(key) {
+            Some(wallet) => wallet.get_public_key().to_vec(),
+            None => HEXLOWER
+                .decode(key.as_bytes())
+                .map_err(|_| format!("{} is neither a local wallet nor a public key", key))?,
+        };
+        pub_keys.push(pub_key);
+    }
+    if required == 0 || required > pub_keys.len() || pub_keys.len() > MAX_MULTISIG_KEYS {
+        return Err("Invalid multisig parameters".into());
+    }
+    let redeem_script = Script::new_multisig(required, &pub_keys);
+    let address = convert_script_address(redeem_script.hash160().as_slice());
+    println!("Address: {}", address);
+    println!(
+        "Redeem script: {}",
+        HEXLOWER.encode(redeem_script.to_bytes().as_slice())
+    );
+    Ok(())
+}
+
+//创建花费多签地址的未签名交易
+fn multisig_spend(redeem_script_hex: &str, to: &str, amount: Amount, fee: Amount) -> CmdResult {
+    let redeem_script = decode_redeem_script(redeem_script_hex)?;
+    if !validate_address(to) {
+        return Err("Recipient address is not valid".into());
+    }
+    let utxo_set = UTXOSet::new(BlockChain::new_blockchain()?);
+    let tx = Transaction::new_multisig_transaction(&redeem_script, to, amount, fee, &utxo_set)?;
+    println!("{}", encode_transaction(&tx));
+    Ok(())
+}
+
+//使用本地钱包为多签交易添加签名
+fn multisig_sign(tx_hex: &str, address: &str) -> CmdResult {
+    let mut tx = decode_transaction(tx_hex)?;
+    let wallets = Wallets::new()?;
+    let wallet = wallets.get_wallet(address).ok_or("Wallet not found")?;
+    let signed = tx.sign_multisig(wallet)?;
+    println!("Signed {} inputs", signed);
+    println!("{}", encode_transaction(&tx));
+    Ok(())
+}
+
+//广播签名完成的交易，指定矿工地址时在本地挖出区块
+fn broadcast_transaction(tx: Transaction, miner: Option<String>) -> CmdResult {
+    let blockchain = BlockChain::new_blockchain()?;
+    let fee = blockchain.validate_transaction(&tx)?;
+    match miner {
+        Some(address) => {
+            if !validate_address(&address) {
+                return Err("Miner address is not valid".into());
+            }
+            let height = blockchain.get_best_height()? + 1;
+            let coinbase_tx = Transaction::new_coinbase_tx(&address, height, fee, &[])?;
+            blockchain.mine_block(&[coinbase_tx, tx])?;
+        }
+        None => {
+            let addr = submit_tx(&tx)?;
+            info!("交易已提交到节点 {}", addr);
+        }
+    }
+    println!("Success!");
+    Ok(())
+}
+
+// 交易编码为十六进制，便于在多个钱包之间传递
+fn encode_transaction(tx: &Transaction) -> String {
+    HEXLOWER.encode(coder::serialized(tx).as_slice())
+}
+
+fn decode_transaction(tx_hex: &str) -> CmdResult<Transaction> {
+    HEXLOWER
+        .decode(tx_hex.as_bytes())
+        .ok()
+        .and_then(|bytes| coder::deserialized(bytes.as_slice()).ok())
+        .ok_or_else(|| "Transaction is not valid".into())
+}
+
+fn decode_redeem_script(redeem_script_hex: &str) -> CmdResult<Script> {
+    HEXLOWER
+        .decode(redeem_script_hex.as_bytes())
+        .ok()
+        .and_then(|bytes| Script::from_bytes(bytes.as_slice()))
+        .filter(|script| script.get_multisig().is_some())
+        .ok_or_else(|| "Redeem script is not valid".into())
+}
+
+//从地址的未花费输出创建部分签名交易，花费 P2SH 地址时需要提供赎回脚本
+fn psbt_create(
+    from: &str,
+    to: &str,
+    amount: Amount,
+    fee: Amount,
+    lock_time: u32,
+    redeem_script: Option<String>,
+) -> CmdResult {
+    if !validate_address(from) {
+        return Err("Sender address is not valid".into());
+    }
+    if !validate_address(to) {
+        return Err("Recipient address is not valid".into());
+    }
+    let utxo_set = UTXOSet::new(BlockChain::new_blockchain()?);
+    let mut psbt = PartiallySignedTransaction::create(from, to, amount, fee, lock_time, &utxo_set)?;
+    if let Some(redeem_script_hex) = redeem_script {
+        let redeem_script = decode_redeem_script(&redeem_script_hex)?;
+        if psbt.add_redeem_script(&redeem_script) == 0 {
+            return Err("Redeem script does not match the sender address".into());
+        }
+    }
+    println!("{}", psbt.to_hex());
+    Ok(())
+}
+
+//使用本地钱包签名，不访问区块链，可以在离线机器上运行
+fn psbt_sign(psbt_hex: &str, address: &str) -> CmdResult {
+    let mut psbt = decode_psbt(psbt_hex)?;
+    let wallets = Wallets::new()?;
+    let wallet = wallets.get_wallet(address).ok_or("Wallet not found")?;
+    let signed = psbt.sign(wallet)?;
+    println!("Signed {} inputs", signed);
+    println!("{}", psbt.to_hex());
+    Ok(())
+}
+
+//合并多个签名方的部分签名交易
+fn psbt_combine(psbt_hexes: &[String]) -> CmdResult {
+    let mut psbt = decode_psbt(&psbt_hexes[0])?;
+    for psbt_hex in &psbt_hexes[1..] {
+        psbt.combine(&decode_psbt(psbt_hex)?)?;
+    }
+    println!("{}", psbt.to_hex());
+    Ok(())
+}
+
+//生成最终交易
+fn psbt_finalize(psbt_hex: &str) -> CmdResult<Transaction> {
+    Ok(decode_psbt(psbt_hex)?.finalize()?)
+}
+
+fn decode_psbt(psbt_hex: &str) -> CmdResult<PartiallySignedTransaction> {
+    PartiallySignedTransaction::from_hex(psbt_hex)
+        .ok_or_else(|| "Partially signed transaction is not valid".into())
 }
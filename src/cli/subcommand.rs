@@ -1,15 +1,12 @@
 use super::{run_cmd, Config};
 use clap::{ArgEnum, Args, Subcommand};
-use core::GLOBAL_CONFIG;
+use core::{Amount, GLOBAL_CONFIG};
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     #[clap(about = "钱包")]
     Wallet { opt: Option<String> },
 
-    #[clap(arg_required_else_help = true, about = "节点")]
-    Center { opt: Option<String> },
-
     #[clap(arg_required_else_help = true, about = "矿工")]
     Miner { opt: Option<String> },
 
@@ -30,13 +27,116 @@ pub enum Commands {
         #[clap(subcommand)]
         opt: Mode,
     },
+
+    #[clap(arg_required_else_help = true, about = "标记区块无效")]
+    Invalidate { hash: String },
+
+    #[clap(
+        arg_required_else_help = true,
+        about = "查看地址的交易历史和未花费输出"
+    )]
+    History { address: String },
+
+    #[clap(arg_required_else_help = true, about = "查看主链上的交易")]
+    Tx { txid: String },
+
+    #[clap(
+        arg_required_else_help = true,
+        about = "开启或关闭交易索引，开启时扫描主链重建索引"
+    )]
+    Txindex {
+        #[clap(arg_enum)]
+        opt: Switch,
+    },
+
+    #[clap(about = "查看地址簿中已知的节点")]
+    Peers,
+
+    #[clap(about = "查看流通量")]
+    Supply { height: Option<usize> },
+
+    #[clap(arg_required_else_help = true, about = "多重签名")]
+    Multisig {
+        #[clap(subcommand)]
+        opt: MultisigMode,
+    },
+
+    #[clap(
+        arg_required_else_help = true,
+        about = "广播签名完成的交易，指定矿工地址时在本地挖出区块"
+    )]
+    Broadcast {
+        tx: String,
+        #[clap(long)]
+        miner: Option<String>,
+    },
+
+    #[clap(
+        arg_required_else_help = true,
+        about = "部分签名交易，支持离线和多方签名"
+    )]
+    Psbt {
+        #[clap(subcommand)]
+        opt: PsbtMode,
+    },
 }
 
 #[derive(Clone, Subcommand, Debug)]
 pub enum Mode {
     Wallet { params: Option<String> },
     Miner { params: Option<String> },
-    Center { params: Option<String> },
+    Chain { params: Option<String> },
+}
+
+#[derive(Clone, Subcommand, Debug)]
+pub enum MultisigMode {
+    #[clap(about = "创建 m-of-n 多签地址，公钥可以是本地钱包地址或十六进制公钥")]
+    Create { required: usize, keys: Vec<String> },
+    #[clap(about = "创建花费多签地址的未签名交易")]
+    Spend {
+        redeem_script: String,
+        to: String,
+        amount: Amount,
+        #[clap(long, help = "手续费，以币为单位", default_value = "0")]
+        fee: Amount,
+    },
+    #[clap(about = "使用本地钱包为交易添加签名")]
+    Sign { tx: String, address: String },
+}
+
+#[derive(Clone, Subcommand, Debug)]
+pub enum PsbtMode {
+    #[clap(about = "从地址的未花费输出创建部分签名交易，不需要钱包")]
+    Create {
+        from: String,
+        to: String,
+        amount: Amount,
+        #[clap(long, help = "手续费，以币为单位", default_value = "0")]
+        fee: Amount,
+        #[clap(
+            long,
+            help = "锁定时间，小于 500000000 时为区块高度，否则为 Unix 时间戳",
+            default_value = "0"
+        )]
+        lock_time: u32,
+        #[clap(long, help = "花费 P2SH 地址时的赎回脚本")]
+        redeem_script: Option<String>,
+    },
+    #[clap(about = "使用本地钱包签名，不需要区块链")]
+    Sign { psbt: String, address: String },
+    #[clap(about = "合并多个签名方的部分签名交易")]
+    Combine {
+        #[clap(required = true, min_values = 2)]
+        psbts: Vec<String>,
+    },
+    #[clap(about = "生成最终交易")]
+    Finalize { psbt: String },
+    #[clap(about = "生成最终交易并广播，指定矿工地址时在本地挖出区块")]
+    Broadcast {
+        psbt: String,
+        #[clap(long)]
+        miner: Option<String>,
+    },
 }
 
 #[derive(Clone, ArgEnum, Debug)]
@@ -46,15 +146,39 @@ pub enum CheckList {
     Utxo,
 }
 
+#[derive(Clone, ArgEnum, Debug)]
+pub enum Switch {
+    On,
+    Off,
+}
+
 #[derive(Args, Debug)]
 pub struct Transform {
     pub from: String,
     pub to: String,
-    pub amount: i32,
+    #[clap(help = "金额，以币为单位，最多 8 位小数")]
+    pub amount: Amount,
     pub mine: i32,
+    #[clap(long, help = "手续费，以币为单位", default_value = "0")]
+    pub fee: Amount,
+    #[clap(
+        long,
+        help = "锁定时间，小于 500000000 时为区块高度，否则为 Unix 时间戳",
+        default_value = "0"
+    )]
+    pub lock_time: u32,
 }
 
 pub fn process(command: Commands, cfg: Config) {
     GLOBAL_CONFIG.set_node_addr(cfg.port);
+    if let Some(dir) = cfg.data_dir {
+        GLOBAL_CONFIG.set_data_dir(dir);
+    }
+    if let Some(addr) = cfg.external_addr {
+        GLOBAL_CONFIG.set_external_addr(addr);
+    }
+    if let Some(seeds) = cfg.seeds {
+        GLOBAL_CONFIG.set_seed_nodes(seeds);
+    }
     run_cmd(command)
 }
@@ -8,14 +8,13 @@ use log::info;
 ///配置运行端口 cargo run -- -p port
 
 ///新建钱包 cargo run -- new wallet
-///新建区块链 cargo run -- new center address
+///新建区块链 cargo run -- new chain address
 ///运行新节点 cargo run -- new miner
 
 ///向钱包地址发送币 cargo run -- send  from to amount mine
 
 ///新建矿工节点cargo run --  miner address
 ///检查地址余额 cargo run -- wallet address
-///创建新区块链 cargo run -- center address
 
 ///查看钱包地址 cargo run -- check wallet-list
 ///查看区块链 cargo run -- check chain
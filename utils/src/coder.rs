@@ -6,9 +6,37 @@ use ring::digest::{Context, SHA256};
 use ring::rand::SystemRandom;
 use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED, ECDSA_P256_SHA256_FIXED_SIGNING};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::iter::repeat;
 
+/// 编解码和密钥操作失败的原因
+#[derive(Debug, Clone, PartialEq)]
+pub enum CoderError {
+    /// 字节数组不是合法的序列化数据
+    Deserialize(String),
+    /// 字符串不是合法的 base58 编码
+    Base58(String),
+    /// 生成或解析密钥失败
+    Key,
+    /// 签名失败
+    Sign,
+}
+
+impl fmt::Display for CoderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoderError::Deserialize(e) => write!(f, "malformed data: {}", e),
+            CoderError::Base58(e) => write!(f, "invalid base58: {}", e),
+            CoderError::Key => write!(f, "invalid key"),
+            CoderError::Sign => write!(f, "signing failed"),
+        }
+    }
+}
+
+impl std::error::Error for CoderError {}
+
 /// 区块序列化
+/// 只用于序列化内存中的结构体，bincode 对这些类型不会失败
 pub fn serialized<T: ?Sized>(value: &T) -> Vec<u8>
 where
     T: Serialize,
@@ -17,13 +45,12 @@ where
     serialized
 }
 
-/// 从字节数组反序列化
-pub fn deserialized<'a, T>(bytes: &'a [u8]) -> T
+/// 从字节数组反序列化，格式错误时返回 CoderError::Deserialize
+pub fn deserialized<'a, T>(bytes: &'a [u8]) -> Result<T, CoderError>
 where
     T: Deserialize<'a>,
 {
-    let deserialized = bincode::deserialize(bytes).expect("序列化失败");
-    deserialized
+    bincode::deserialize(bytes).map_err(|e| CoderError::Deserialize(e.to_string()))
 }
 
 pub fn get_hash(value: &[u8]) -> String {
@@ -40,6 +67,11 @@ pub fn sha256_digest(data: &[u8]) -> Vec<u8> {
     digest.as_ref().to_vec()
 }
 
+/// 计算双重 sha256 哈希值
+pub fn double_sha256_digest(data: &[u8]) -> Vec<u8> {
+    sha256_digest(sha256_digest(data).as_slice())
+}
+
 // 计算 ripemd160 哈希值
 pub fn ripemd160_digest(data: &[u8]) -> Vec<u8> {
     let mut ripemd160 = Ripemd160::new();
@@ -55,22 +87,27 @@ pub fn base58_encode(data: &[u8]) -> String {
 }
 
 // base58 解码
-pub fn base58_decode(data: &str) -> Vec<u8> {
-    bs58::decode(data).into_vec().unwrap()
+pub fn base58_decode(data: &str) -> Result<Vec<u8>, CoderError> {
+    bs58::decode(data)
+        .into_vec()
+        .map_err(|e| CoderError::Base58(e.to_string()))
 }
 
 // 创建密钥对（椭圆曲线加密）
-pub fn new_key_pair() -> Vec<u8> {
+pub fn new_key_pair() -> Result<Vec<u8>, CoderError> {
     let rng = SystemRandom::new();
-    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
-    pkcs8.as_ref().to_vec()
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+        .map_err(|_| CoderError::Key)?;
+    Ok(pkcs8.as_ref().to_vec())
 }
 
 /// ECDSA P256 SHA256 签名
-pub fn ecdsa_p256_sha256_sign_digest(pkcs8: &[u8], message: &[u8]) -> Vec<u8> {
-    let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8).unwrap();
+pub fn ecdsa_p256_sha256_sign_digest(pkcs8: &[u8], message: &[u8]) -> Result<Vec<u8>, CoderError> {
+    let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8)
+        .map_err(|_| CoderError::Key)?;
     let rng = ring::rand::SystemRandom::new();
-    key_pair.sign(&rng, message).unwrap().as_ref().to_vec()
+    let signature = key_pair.sign(&rng, message).map_err(|_| CoderError::Sign)?;
+    Ok(signature.as_ref().to_vec())
 }
 
 /// ECDSA P256 SHA256 签名验证